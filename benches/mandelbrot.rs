@@ -81,13 +81,15 @@ fn mandelbrot_sequential(size: usize) -> Vec<Option<ImageLine>> {
 }
 
 fn mandelbrot_spar_rust(size: usize, threads: usize) {
-    let lines: Vec<ImageLine> = to_stream!(OUTPUT(ImageLine), {
+    let lines: Vec<ImageLine> = to_stream!(INPUT(size: usize), OUTPUT(ImageLine), {
         for i in 0..size {
             STAGE(
                 INPUT(size: usize, i: usize),
-                OUTPUT(ImageLine),
+                OUTPUT(line: ImageLine),
                 REPLICATE = threads,
-                { render_line(size, i) },
+                {
+                    let line = render_line(*size, i).unwrap();
+                },
             );
         }
     });
@@ -0,0 +1,888 @@
+//! Runtime support for the code generated by `spar_rust::to_stream!`.
+//!
+//! Crates whose streams restore external variables, are `LAZY` or `DETACHED`, or
+//! can be cancelled, and crates that use `spar_rust::spar_pipeline!`, must depend
+//! on this crate.
+
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, LinkedList, VecDeque};
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// An external variable that the last stage of a stream accumulates into.
+///
+/// The last stage starts every item from an empty (`Default`) value. Once the
+/// stream ends, the values of all items are merged into the original variable,
+/// in the order the collector received them, which is the order of the input
+/// when the stream is `ORDERED`.
+///
+/// External variables whose type doesn't implement `Restore` are not restored:
+/// each replica of the last stage works on its own copy of them.
+///
+/// ```
+/// use spar_rust_runtime::Restore;
+///
+/// #[derive(Default)]
+/// struct Counter(u64);
+///
+/// impl Restore for Counter {
+///     fn restore(&mut self, other: Self) {
+///         self.0 += other.0;
+///     }
+/// }
+/// ```
+pub trait Restore: Default {
+    /// Merges the value that the last stage produced for one item into `self`
+    fn restore(&mut self, other: Self);
+}
+
+macro_rules! restore_by_extend {
+    ($([$($param:ident),*] $ty:ty, [$($bound:tt)*];)*) => {
+        $(
+            impl<$($param),*> Restore for $ty where $($bound)* {
+                fn restore(&mut self, other: Self) {
+                    self.extend(other);
+                }
+            }
+        )*
+    };
+}
+
+restore_by_extend! {
+    [T] Vec<T>, [];
+    [T] VecDeque<T>, [];
+    [T] LinkedList<T>, [];
+    [T] BinaryHeap<T>, [T: Ord];
+    [T] BTreeSet<T>, [T: Ord];
+    [K, V] BTreeMap<K, V>, [K: Ord];
+    [T, S] HashSet<T, S>, [T: Eq + Hash, S: BuildHasher + Default];
+    [K, V, S] HashMap<K, V, S>, [K: Eq + Hash, S: BuildHasher + Default];
+}
+
+impl Restore for String {
+    fn restore(&mut self, other: Self) {
+        self.push_str(&other);
+    }
+}
+
+/// The items of a `LAZY` stream, yielded as its last stage produces them, in
+/// order if the stream is `ORDERED`.
+///
+/// The stream runs on its own thread. If it panics, the panic is resumed once
+/// the iterator reaches the end of the items. Dropping the iterator doesn't stop
+/// the stream: it runs to the end, and the items it still produces are discarded.
+pub struct Items<T> {
+    items: Receiver<T>,
+    stream: Option<JoinHandle<()>>,
+}
+
+impl<T> Items<T> {
+    #[doc(hidden)]
+    pub fn new(items: Receiver<T>, stream: JoinHandle<()>) -> Self {
+        Self {
+            items,
+            stream: Some(stream),
+        }
+    }
+}
+
+impl<T> Iterator for Items<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match self.items.recv() {
+            Ok(item) => Some(item),
+            // every sender is gone, so the stream ended
+            Err(_) => {
+                if let Some(stream) = self.stream.take() {
+                    if let Err(panic) = stream.join() {
+                        std::panic::resume_unwind(panic);
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+/// The handle returned by `spar_pipeline!`: a pipeline whose stages and threads
+/// are started once, and process the items posted to it until it is shut down.
+///
+/// Every item posted with [`post`](Self::post) yields exactly one result, in the
+/// order the items were posted if the pipeline is `ORDERED`: `spar_pipeline!`
+/// rejects the stages that would send more or fewer, a SPLIT, a LOOP_BACK or a
+/// WINDOW, and [`flush`](Self::flush) counts on it. Dropping the handle ends
+/// the pipeline without waiting for it: the items it still produces are discarded.
+pub struct Pipeline<I, O> {
+    posts: Sender<I>,
+    items: Receiver<O>,
+    stream: Option<JoinHandle<()>>,
+    pending: usize,
+}
+
+impl<I, O> Pipeline<I, O> {
+    #[doc(hidden)]
+    pub fn new(posts: Sender<I>, items: Receiver<O>, stream: JoinHandle<()>) -> Self {
+        Self {
+            posts,
+            items,
+            stream: Some(stream),
+            pending: 0,
+        }
+    }
+
+    /// Sends an item to the first stage, without waiting for its result
+    pub fn post(&mut self, item: I) {
+        // the pipeline only stops receiving items once it panicked
+        if self.posts.send(item).is_err() {
+            self.resume_panic();
+        }
+        self.pending += 1;
+    }
+
+    /// The number of posted items whose results were not returned yet
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Waits until every posted item went through the pipeline, and returns their results
+    pub fn flush(&mut self) -> Vec<O> {
+        let mut items = Vec::with_capacity(self.pending);
+        while self.pending > 0 {
+            match self.items.recv() {
+                Ok(item) => items.push(item),
+                Err(_) => self.resume_panic(),
+            }
+            self.pending -= 1;
+        }
+        items
+    }
+
+    /// Returns the results that are already available, without waiting for the others
+    pub fn drain(&mut self) -> Vec<O> {
+        let mut items = Vec::new();
+        loop {
+            match self.items.try_recv() {
+                Ok(item) => {
+                    items.push(item);
+                    // a release build keeps counting from zero instead of wrapping
+                    debug_assert!(
+                        self.pending > 0,
+                        "the pipeline returned more results than items were posted to it"
+                    );
+                    self.pending = self.pending.saturating_sub(1);
+                }
+                Err(TryRecvError::Empty) => return items,
+                Err(TryRecvError::Disconnected) => self.resume_panic(),
+            }
+        }
+    }
+
+    /// Ends the pipeline once the posted items went through it, and returns
+    /// their results. The panic of any stage is resumed here
+    pub fn shutdown(self) -> Vec<O> {
+        let Self {
+            posts,
+            items,
+            stream,
+            ..
+        } = self;
+        drop(posts);
+        let items = items.into_iter().collect();
+        if let Some(Err(panic)) = stream.map(JoinHandle::join) {
+            std::panic::resume_unwind(panic);
+        }
+        items
+    }
+
+    /// The pipeline stopped receiving items, or sending their results, which
+    /// only happens once its thread ended because a stage panicked
+    fn resume_panic(&mut self) -> ! {
+        match self.stream.take().map(JoinHandle::join) {
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            _ => panic!("the pipeline ended before returning the results of every item"),
+        }
+    }
+}
+
+/// Cancels the streams it is given to with `CANCEL = token`. Clones of a token
+/// share its state, so any of them cancels the stream.
+///
+/// ```
+/// use spar_rust_runtime::CancelToken;
+///
+/// let token = CancelToken::new();
+/// let clone = token.clone();
+/// clone.cancel();
+/// assert!(token.is_cancelled());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the streams of this token to stop. They notice it before dispatching,
+    /// or processing, their next item
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What a stream with a `CANCEL` or `TIMEOUT` evaluates to: the items it returns,
+/// if any, and whether it went through all of them. The external variables are
+/// restored either way, with the results of the items that went through the stages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[must_use]
+pub enum Outcome<T> {
+    /// Every item went through the stream
+    Completed(T),
+    /// The stream was cancelled, or timed out, before it went through all items
+    Cancelled(T),
+}
+
+impl<T> Outcome<T> {
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, Self::Cancelled(_))
+    }
+
+    /// The results of the stream, complete or not
+    pub fn into_inner(self) -> T {
+        match self {
+            Self::Completed(value) | Self::Cancelled(value) => value,
+        }
+    }
+}
+
+/// The handle of a `DETACHED` stream, which runs on its own thread while the
+/// code after `to_stream!` goes on.
+///
+/// Joining the stream returns what it would have evaluated to, followed by the
+/// external variables its last stage mutates, restored if their type implements
+/// `Restore`: a tuple, if there are several, and `()` if there are none. Dropping
+/// the handle doesn't stop the stream, but its results are lost.
+pub struct Detached<T> {
+    stream: JoinHandle<T>,
+    cancellation: __private::Cancellation,
+}
+
+impl<T> Detached<T> {
+    #[doc(hidden)]
+    pub fn new(stream: JoinHandle<T>, cancellation: __private::Cancellation) -> Self {
+        Self {
+            stream,
+            cancellation,
+        }
+    }
+
+    /// Whether the stream ended, so that `join` returns without waiting
+    pub fn is_finished(&self) -> bool {
+        self.stream.is_finished()
+    }
+
+    /// Stops dispatching items to the stages. The items that were already
+    /// dispatched still go through the pipeline, unless the stream has a `CANCEL`
+    /// or `TIMEOUT`, and `join` returns their results
+    pub fn cancel(&self) {
+        self.cancellation.cancel();
+    }
+
+    /// Waits for the end of the stream, and returns its results. The panic of any
+    /// stage is resumed here
+    pub fn join(self) -> T {
+        match self.stream.join() {
+            Ok(results) => results,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+/// Used by the generated code, to restore the external variables whose type
+/// implements `Restore`, and leave the others alone. Not part of the public API.
+#[doc(hidden)]
+pub mod __private {
+    use super::{CancelToken, Outcome, Restore};
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+    use std::marker::PhantomData;
+    use std::num::NonZeroUsize;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// Whether a stream must stop: it was cancelled, or its deadline passed. The
+    /// dispatcher and the stages check it before every item, and it remembers
+    /// whether any of them stopped, so that the outcome says if the stream ended early
+    #[derive(Clone, Default)]
+    pub struct Cancellation {
+        token: CancelToken,
+        deadline: Option<Instant>,
+        stopped: Arc<AtomicBool>,
+    }
+
+    impl Cancellation {
+        pub fn new(token: Option<CancelToken>, timeout: Option<Duration>) -> Self {
+            Self {
+                token: token.unwrap_or_default(),
+                // a deadline too far away to be represented is never reached
+                deadline: timeout.and_then(|timeout| Instant::now().checked_add(timeout)),
+                stopped: Arc::default(),
+            }
+        }
+
+        pub fn cancel(&self) {
+            self.token.cancel();
+        }
+
+        /// Called before dispatching or processing an item, which is skipped if
+        /// the stream must stop
+        pub fn is_cancelled(&self) -> bool {
+            let cancelled = self.token.is_cancelled()
+                || self
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline);
+            if cancelled {
+                self.stopped.store(true, Ordering::Relaxed);
+            }
+            cancelled
+        }
+
+        pub fn outcome<T>(&self, value: T) -> Outcome<T> {
+            if self.stopped.load(Ordering::Relaxed) {
+                Outcome::Cancelled(value)
+            } else {
+                Outcome::Completed(value)
+            }
+        }
+    }
+
+    thread_local! {
+        /// The threads that the streams nested in the stage running on this thread
+        /// may use, while it processes an item
+        static BUDGET: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    /// The threads a stream may use for the replicas of each of its stages: the
+    /// share of the stage it is nested in, if any, or `SPAR_NUM_WORKERS`, or the
+    /// available parallelism
+    pub fn budget(num_workers: Option<u32>) -> usize {
+        BUDGET.with(Cell::get).unwrap_or_else(|| match num_workers {
+            Some(workers) => workers as usize,
+            None => std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        })
+    }
+
+    /// The replicas of a stage of a nested stream, which cannot exceed its budget
+    pub fn nested_replicas(replicas: i32, budget: usize) -> i32 {
+        replicas.min(budget.try_into().unwrap_or(i32::MAX)).max(1)
+    }
+
+    /// The share of the budget of a stream that each of the `replicas` of one of
+    /// its stages gives to the streams nested in it
+    pub fn share(budget: usize, replicas: i32) -> usize {
+        (budget / replicas.max(1) as usize).max(1)
+    }
+
+    /// Sets the budget of the streams nested in a stage while it processes an item,
+    /// and restores the previous one once dropped
+    pub struct Budget(Option<usize>);
+
+    impl Budget {
+        pub fn enter(share: usize) -> Self {
+            Self(BUDGET.with(|budget| budget.replace(Some(share))))
+        }
+    }
+
+    impl Drop for Budget {
+        fn drop(&mut self) {
+            BUDGET.with(|budget| budget.set(self.0));
+        }
+    }
+
+    /// The items that a stage sends back to an earlier stage, which the dispatcher
+    /// posts to the pipeline again. It counts the items between the dispatcher and
+    /// the stage that loops back, so that it knows when none can loop back anymore
+    pub struct Feedback<T> {
+        sender: Sender<T>,
+        receiver: Receiver<T>,
+        pending: Arc<AtomicUsize>,
+    }
+
+    impl<T> Feedback<T> {
+        pub fn new() -> Self {
+            let (sender, receiver) = mpsc::channel();
+            Self {
+                sender,
+                receiver,
+                pending: Arc::default(),
+            }
+        }
+
+        /// What the stage that loops back holds
+        pub fn looper(&self) -> Looper<T> {
+            Looper {
+                sender: self.sender.clone(),
+                pending: self.pending.clone(),
+            }
+        }
+
+        /// Called before the dispatcher posts an item
+        pub fn dispatched(&self) {
+            self.pending.fetch_add(1, Ordering::SeqCst);
+        }
+
+        /// The items sent back so far, which the dispatcher posts between its own
+        pub fn looped(&self) -> mpsc::TryIter<'_, T> {
+            self.receiver.try_iter()
+        }
+
+        /// The items sent back once the dispatcher is done, until every item left
+        /// the loop, or `stop` tells that the stream stopped early
+        pub fn until_done<'a>(
+            &'a self,
+            stop: impl Fn() -> bool + 'a,
+        ) -> impl Iterator<Item = T> + 'a {
+            std::iter::from_fn(move || loop {
+                // an item that loops back is pending until it leaves the loop
+                if self.pending.load(Ordering::SeqCst) == 0 || stop() {
+                    return None;
+                }
+                match self.receiver.recv_timeout(Duration::from_millis(1)) {
+                    Ok(item) => return Some(item),
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return None,
+                }
+            })
+        }
+    }
+
+    impl<T> Default for Feedback<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    /// Sends the items of a stage back to an earlier stage, or lets them leave the loop
+    pub struct Looper<T> {
+        sender: Sender<T>,
+        pending: Arc<AtomicUsize>,
+    }
+
+    impl<T> Looper<T> {
+        pub fn loop_back(&self, item: T) {
+            // the items are discarded once the stream stopped early
+            let _ = self.sender.send(item);
+        }
+
+        pub fn leave(&self) {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    impl<T> Clone for Looper<T> {
+        fn clone(&self) -> Self {
+            Self {
+                sender: self.sender.clone(),
+                pending: self.pending.clone(),
+            }
+        }
+    }
+
+    /// The items of a stage with a WINDOW, until they make a window. The stream tells
+    /// it how many items it sent once it ends, and the items received since the last
+    /// window then make a last, partial one
+    pub struct Window<T> {
+        items: VecDeque<T>,
+        size: usize,
+        slide: usize,
+        /// The items to drop before the next window, when windows start more items
+        /// apart than they have
+        skip: usize,
+        /// The items received since the last window
+        fresh: usize,
+        received: u64,
+        expected: Option<u64>,
+        sent: u64,
+    }
+
+    impl<T: Clone> Window<T> {
+        pub fn new(size: usize, slide: usize) -> Self {
+            assert!(
+                size > 0 && slide > 0,
+                "a WINDOW must have a size and a slide of at least 1"
+            );
+            Self {
+                items: VecDeque::with_capacity(size),
+                size,
+                slide,
+                skip: 0,
+                fresh: 0,
+                received: 0,
+                expected: None,
+                sent: 0,
+            }
+        }
+
+        /// Adds an item, and returns the window it completes, if any
+        pub fn push(&mut self, item: T) -> Option<Vec<T>> {
+            self.received += 1;
+            if self.skip > 0 {
+                self.skip -= 1;
+                return None;
+            }
+            self.items.push_back(item);
+            self.fresh += 1;
+            if self.items.len() < self.size {
+                return None;
+            }
+
+            let window = self.items.iter().cloned().collect();
+            let slid = self.slide.min(self.items.len());
+            self.items.drain(..slid);
+            self.skip = self.slide - slid;
+            self.fresh = 0;
+            self.sent += 1;
+            Some(window)
+        }
+
+        /// Called when the end of the stream reaches the stage, which receives
+        /// `expected` items in all
+        pub fn end(&mut self, expected: u64) {
+            self.expected = Some(expected);
+        }
+
+        /// Once the stage received every item of the stream: the partial window of
+        /// the items received since the last window, if any, and how many windows
+        /// the stage sent in all. Returns it only once
+        pub fn finish(&mut self) -> Option<(Option<Vec<T>>, u64)> {
+            if self.expected != Some(self.received) {
+                return None;
+            }
+            self.expected = None;
+            let partial = (self.fresh > 0).then(|| {
+                self.fresh = 0;
+                self.sent += 1;
+                self.items.drain(..).collect()
+            });
+            Some((partial, self.sent))
+        }
+    }
+
+    /// Picks what to do with an external variable of type `T`, according to
+    /// whether `T` implements `Restore`. Methods are called as `(&Probe::new()).method()`:
+    /// the methods of `Probe<T>` take precedence, but only exist when `T: Restore`
+    pub struct Probe<T>(PhantomData<T>);
+
+    impl<T> Probe<T> {
+        pub fn new() -> Self {
+            Self(PhantomData)
+        }
+    }
+
+    impl<T> Default for Probe<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    pub trait Restored<T> {
+        /// The copy of the variable that the pipeline owns. A restored variable
+        /// starts empty, since only the changes of each item are sent back
+        fn spar_copy(&self, value: &T) -> T;
+        /// The value the last stage starts each item from, if `T` is restored
+        fn spar_start(&self) -> Option<T>;
+        fn spar_restore(&self, target: &mut T, value: Option<T>);
+        /// Called where the last stage mutates an external variable
+        fn spar_mutated(&self) {}
+    }
+
+    impl<T: Restore> Restored<T> for Probe<T> {
+        fn spar_copy(&self, _value: &T) -> T {
+            T::default()
+        }
+
+        fn spar_start(&self) -> Option<T> {
+            Some(T::default())
+        }
+
+        fn spar_restore(&self, target: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                target.restore(value);
+            }
+        }
+    }
+
+    pub trait NotRestored<T> {
+        fn spar_copy(&self, value: &T) -> T
+        where
+            T: Clone;
+        fn spar_start(&self) -> Option<T>;
+        fn spar_restore(&self, target: &mut T, value: Option<T>);
+        /// Macros cannot emit warnings, so this is how the generated code warns
+        /// about changes that are lost
+        #[deprecated(
+            note = "the stage mutates an external variable whose type does not implement `spar_rust_runtime::Restore`, so the changes are lost once the stream ends"
+        )]
+        fn spar_mutated(&self) {}
+    }
+
+    impl<T> NotRestored<T> for &Probe<T> {
+        fn spar_copy(&self, value: &T) -> T
+        where
+            T: Clone,
+        {
+            value.clone()
+        }
+
+        fn spar_start(&self) -> Option<T> {
+            None
+        }
+
+        fn spar_restore(&self, _target: &mut T, _value: Option<T>) {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::__private::{
+        budget, nested_replicas, share, Budget, Cancellation, Feedback, NotRestored, Probe,
+        Restored, Window,
+    };
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn restore_collections() {
+        let mut vec = vec![1, 2];
+        vec.restore(vec![3]);
+        assert_eq!(vec, [1, 2, 3]);
+
+        let mut map = HashMap::from([(1, 'a')]);
+        map.restore(HashMap::from([(2, 'b')]));
+        assert_eq!(map, HashMap::from([(1, 'a'), (2, 'b')]));
+
+        let mut string = String::from("ab");
+        string.restore(String::from("c"));
+        assert_eq!(string, "abc");
+    }
+
+    #[test]
+    fn items() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let stream = std::thread::spawn(move || {
+            for i in 0..3 {
+                sender.send(i).unwrap();
+            }
+        });
+        let items: Vec<u32> = Items::new(receiver, stream).collect();
+        assert_eq!(items, [0, 1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "stage failed")]
+    fn items_resume_panics() {
+        let (sender, receiver) = std::sync::mpsc::channel::<u32>();
+        let stream = std::thread::spawn(move || {
+            drop(sender);
+            panic!("stage failed");
+        });
+        Items::new(receiver, stream).for_each(drop);
+    }
+
+    fn doubling_pipeline() -> Pipeline<u32, u32> {
+        let (posts, received) = std::sync::mpsc::channel::<u32>();
+        let (sender, items) = std::sync::mpsc::channel();
+        let stream = std::thread::spawn(move || {
+            for item in received {
+                if item == 0 {
+                    panic!("stage failed");
+                }
+                sender.send(item * 2).unwrap();
+            }
+        });
+        Pipeline::new(posts, items, stream)
+    }
+
+    #[test]
+    fn pipeline() {
+        let mut pipeline = doubling_pipeline();
+        pipeline.post(1);
+        pipeline.post(2);
+        assert_eq!(pipeline.pending(), 2);
+        assert_eq!(pipeline.flush(), [2, 4]);
+        assert_eq!(pipeline.pending(), 0);
+
+        pipeline.post(3);
+        while pipeline.pending() > 0 {
+            assert!(pipeline.drain().iter().all(|&item| item == 6));
+        }
+        pipeline.post(4);
+        assert_eq!(pipeline.shutdown(), [8]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "more results than items were posted")]
+    fn pipeline_extra_results() {
+        let (posts, received) = std::sync::mpsc::channel::<u32>();
+        let (sender, items) = std::sync::mpsc::channel();
+        let stream = std::thread::spawn(move || {
+            for item in received {
+                sender.send(item).unwrap();
+                sender.send(item).unwrap();
+            }
+        });
+        let mut pipeline = Pipeline::new(posts, items, stream);
+        pipeline.post(1);
+        assert_eq!(pipeline.flush(), [1]);
+        while pipeline.drain().is_empty() {}
+    }
+
+    #[test]
+    #[should_panic(expected = "stage failed")]
+    fn pipeline_resume_panics() {
+        let mut pipeline = doubling_pipeline();
+        pipeline.post(0);
+        pipeline.flush();
+    }
+
+    #[test]
+    fn detached() {
+        let cancellation = Cancellation::new(None, None);
+        let stream = {
+            let cancellation = cancellation.clone();
+            std::thread::spawn(move || {
+                let mut dispatched = 0;
+                loop {
+                    dispatched += 1;
+                    if cancellation.is_cancelled() {
+                        return cancellation.outcome(dispatched);
+                    }
+                    std::thread::yield_now();
+                }
+            })
+        };
+        let detached = Detached::new(stream, cancellation);
+        detached.cancel();
+        while !detached.is_finished() {
+            std::thread::yield_now();
+        }
+        assert!(detached.join().is_cancelled());
+    }
+
+    #[test]
+    fn cancellation() {
+        let token = CancelToken::new();
+        let cancellation = Cancellation::new(Some(token.clone()), None);
+        assert_eq!(cancellation.outcome(1), Outcome::Completed(1));
+        token.cancel();
+        // only a check that stops the stream makes it cancelled
+        assert_eq!(cancellation.outcome(2), Outcome::Completed(2));
+        assert!(cancellation.is_cancelled());
+        assert_eq!(cancellation.outcome(3), Outcome::Cancelled(3));
+
+        let timeout = Cancellation::new(None, Some(Duration::ZERO));
+        assert!(timeout.is_cancelled());
+        assert!(!Cancellation::new(None, Some(Duration::MAX)).is_cancelled());
+        assert_eq!(timeout.outcome(4).into_inner(), 4);
+    }
+
+    #[test]
+    fn budget_shares() {
+        assert_eq!(budget(Some(8)), 8);
+        assert_eq!(share(8, 3), 2);
+        assert_eq!(share(2, 4), 1);
+        {
+            let _outer = Budget::enter(share(budget(Some(8)), 2));
+            assert_eq!(budget(Some(8)), 4);
+            assert_eq!(nested_replicas(6, budget(None)), 4);
+            {
+                let _inner = Budget::enter(share(budget(None), 4));
+                assert_eq!(budget(None), 1);
+            }
+            assert_eq!(budget(None), 4);
+        }
+        assert_eq!(budget(Some(8)), 8);
+        assert_eq!(nested_replicas(0, 4), 1);
+    }
+
+    #[test]
+    fn feedback() {
+        let feedback = Feedback::new();
+        let looper = feedback.looper();
+        feedback.dispatched();
+        feedback.dispatched();
+        looper.loop_back(1);
+        assert_eq!(feedback.looped().collect::<Vec<_>>(), [1]);
+        looper.leave();
+
+        // the last pending item loops back once more before it leaves
+        let stage = std::thread::spawn(move || {
+            looper.loop_back(2);
+            std::thread::sleep(Duration::from_millis(20));
+            looper.leave();
+        });
+        assert_eq!(feedback.until_done(|| false).collect::<Vec<_>>(), [2]);
+        stage.join().unwrap();
+
+        feedback.dispatched();
+        assert_eq!(feedback.until_done(|| true).count(), 0);
+    }
+
+    #[test]
+    fn windows() {
+        // sliding windows, and the last items after the last full one
+        let mut window = Window::new(3, 1);
+        let windows: Vec<Vec<u32>> = (0..5).filter_map(|i| window.push(i)).collect();
+        assert_eq!(windows, [[0, 1, 2], [1, 2, 3], [2, 3, 4]]);
+        window.end(5);
+        assert_eq!(window.finish(), Some((None, 3)));
+        assert_eq!(window.finish(), None);
+
+        // windows that don't overlap, and a partial one at the end
+        let mut window = Window::new(2, 2);
+        let windows: Vec<Vec<u32>> = (0..5).filter_map(|i| window.push(i)).collect();
+        assert_eq!(windows, [[0, 1], [2, 3]]);
+        window.end(5);
+        assert_eq!(window.finish(), Some((Some(vec![4]), 3)));
+
+        // windows that start further apart than they have items, and the end of
+        // the stream that arrives before its last items
+        let mut window = Window::new(2, 3);
+        window.end(7);
+        let windows: Vec<Vec<u32>> = (0..6).filter_map(|i| window.push(i)).collect();
+        assert_eq!(windows, [[0, 1], [3, 4]]);
+        assert_eq!(window.finish(), None);
+        assert_eq!(window.push(6), None);
+        assert_eq!(window.finish(), Some((Some(vec![6]), 3)));
+    }
+
+    // the borrows are what the generated code does, so that `NotRestored` is found
+    // by autoref when `Restored` does not apply
+    #[test]
+    #[allow(clippy::needless_borrow)]
+    fn probe() {
+        let mut vec = vec![1];
+        let start = (&Probe::<Vec<u32>>::new()).spar_start();
+        assert_eq!(start, Some(Vec::new()));
+        (&Probe::<Vec<u32>>::new()).spar_restore(&mut vec, Some(vec![2]));
+        assert_eq!(vec, [1, 2]);
+        assert!((&Probe::<Vec<u32>>::new()).spar_copy(&vec).is_empty());
+
+        let mut size = 10usize;
+        assert_eq!((&Probe::<usize>::new()).spar_copy(&size), 10);
+        let start = (&Probe::<usize>::new()).spar_start();
+        assert_eq!(start, None);
+        (&Probe::<usize>::new()).spar_restore(&mut size, None);
+        assert_eq!(size, 10);
+    }
+}
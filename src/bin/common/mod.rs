@@ -0,0 +1,152 @@
+//! Code shared by the command line tools, to find the `to_stream!` and
+//! `spar_pipeline!` invocations
+//! in a source file and rewrite them in place.
+//!
+//! The tools only rewrite the text of the invocations themselves, so that the
+//! rest of the file, including its comments, is left untouched.
+
+use proc_macro2::{LineColumn, TokenStream};
+use quote::ToTokens;
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+use syn::{Expr, ExprMacro, Item, ItemMacro, Macro, Stmt};
+
+/// A `to_stream!` or `spar_pipeline!` invocation found in a source file
+pub struct Invocation {
+    /// Byte offset of the first character of the invocation
+    pub start: usize,
+    /// Byte offset right after the invocation, including its ';' when it is a statement
+    pub end: usize,
+    /// The line where the invocation starts, counting from 1
+    pub line: usize,
+    /// The column where the line of the invocation starts, for indentation
+    pub indent: usize,
+    /// The path of the macro, as in `to_stream` or `spar_rust::to_stream`
+    pub path: String,
+    /// The arguments given to the macro
+    pub tokens: TokenStream,
+    /// Whether the invocation is a statement, or an expression
+    pub is_statement: bool,
+}
+
+fn is_to_stream(mac: &Macro) -> bool {
+    mac.path
+        .segments
+        .last()
+        .map(|segment| segment.ident == "to_stream" || segment.ident == "spar_pipeline")
+        .unwrap_or(false)
+}
+
+/// Maps the line and column of spans to byte offsets in the source
+pub struct SourceMap<'a> {
+    source: &'a str,
+    line_offsets: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(source: &'a str) -> Self {
+        let mut line_offsets = vec![0];
+        line_offsets.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+        Self {
+            source,
+            line_offsets,
+        }
+    }
+
+    /// LineColumn counts lines from 1 and columns in chars from 0
+    pub fn offset(&self, position: LineColumn) -> usize {
+        let line_start = self.line_offsets[position.line - 1];
+        self.source[line_start..]
+            .char_indices()
+            .nth(position.column)
+            .map(|(i, _)| line_start + i)
+            .unwrap_or(self.source.len())
+    }
+
+    /// The number of whitespace characters at the start of the line
+    pub fn indent(&self, line: usize) -> usize {
+        let line = &self.source[self.line_offsets[line - 1]..];
+        line.len() - line.trim_start().len()
+    }
+}
+
+struct Finder<'a> {
+    map: SourceMap<'a>,
+    invocations: Vec<Invocation>,
+}
+
+impl<'a> Finder<'a> {
+    fn push(&mut self, mac: &Macro, start: LineColumn, end: LineColumn, is_statement: bool) {
+        self.invocations.push(Invocation {
+            start: self.map.offset(start),
+            end: self.map.offset(end),
+            line: start.line,
+            indent: self.map.indent(start.line),
+            path: mac.path.to_token_stream().to_string().replace(' ', ""),
+            tokens: mac.tokens.clone(),
+            is_statement,
+        });
+    }
+}
+
+impl<'ast, 'a> Visit<'ast> for Finder<'a> {
+    fn visit_stmt(&mut self, stmt: &'ast Stmt) {
+        match stmt {
+            Stmt::Item(Item::Macro(ItemMacro { mac, .. })) if is_to_stream(mac) => {
+                self.push(mac, stmt.span().start(), stmt.span().end(), true)
+            }
+            Stmt::Semi(Expr::Macro(ExprMacro { mac, .. }), semi) if is_to_stream(mac) => {
+                self.push(mac, mac.span().start(), semi.span.end(), true)
+            }
+            _ => visit::visit_stmt(self, stmt),
+        }
+    }
+
+    fn visit_expr_macro(&mut self, expr: &'ast ExprMacro) {
+        if is_to_stream(&expr.mac) {
+            self.push(&expr.mac, expr.span().start(), expr.span().end(), false)
+        }
+    }
+}
+
+/// Returns every `to_stream!` and `spar_pipeline!` invocation in the file, in the order they appear
+pub fn find_invocations(source: &str) -> syn::Result<Vec<Invocation>> {
+    let file = syn::parse_file(source)?;
+    let mut finder = Finder {
+        map: SourceMap::new(source),
+        invocations: Vec::new(),
+    };
+    finder.visit_file(&file);
+    finder
+        .invocations
+        .sort_by_key(|invocation| invocation.start);
+    Ok(finder.invocations)
+}
+
+/// Replaces each invocation by the text returned by `replace`
+pub fn rewrite<F>(source: &str, invocations: &[Invocation], mut replace: F) -> String
+where
+    F: FnMut(&Invocation) -> String,
+{
+    let mut rewritten = String::with_capacity(source.len());
+    let mut last = 0;
+    for invocation in invocations {
+        rewritten.push_str(&source[last..invocation.start]);
+        rewritten.push_str(&replace(invocation));
+        last = invocation.end;
+    }
+    rewritten.push_str(&source[last..]);
+    rewritten
+}
+
+/// Prints an error found in `path`, with the line and column where it happened.
+/// Errors that don't point anywhere in particular point to the invocation itself
+pub fn report(path: &str, invocation: Option<&Invocation>, error: &syn::Error) {
+    let start = error.span().start();
+    match invocation {
+        Some(invocation) if start.line == 0 => {
+            eprintln!("{path}:{}: error: {error}", invocation.line)
+        }
+        _ => eprintln!("{path}:{}:{}: error: {error}", start.line, start.column + 1),
+    }
+}
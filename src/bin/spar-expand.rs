@@ -0,0 +1,193 @@
+//! Replaces every `to_stream!` and `spar_pipeline!` invocation in a Rust source
+//! file by the code it expands to, formatted as regular Rust code.
+//!
+//! This is the source-to-source transformation that the macro does, made
+//! visible: the output can be inspected, or kept as hand-maintained rust-spp
+//! code. Comments inside the invocations are lost in the process.
+//!
+//! usage: spar-expand [--in-place] <rust source file>
+
+extern crate proc_macro;
+
+#[allow(dead_code)]
+#[path = "../codegen.rs"]
+mod codegen;
+mod common;
+#[allow(dead_code)]
+#[path = "../instrumentation.rs"]
+mod instrumentation;
+#[allow(dead_code)]
+#[path = "../lints.rs"]
+mod lints;
+#[allow(dead_code)]
+#[path = "../spar_stream.rs"]
+mod spar_stream;
+
+use std::process::ExitCode;
+
+use codegen::codegen;
+use common::{find_invocations, report, rewrite, Invocation};
+use proc_macro2::{LineColumn, TokenStream};
+use quote::quote;
+use spar_stream::SparStream;
+use syn::spanned::Spanned;
+use syn::{Item, UseTree};
+
+/// Returns the lines of `use spar_rust::to_stream;` (or `spar_pipeline`) imports, which are unused once
+/// the invocations are expanded
+fn find_imports(source: &str) -> syn::Result<Vec<LineColumn>> {
+    let file = syn::parse_file(source)?;
+    Ok(file
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Use(item_use) => match &item_use.tree {
+                UseTree::Path(path) if path.ident == "spar_rust" => match &*path.tree {
+                    UseTree::Name(name)
+                        if name.ident == "to_stream" || name.ident == "spar_pipeline" =>
+                    {
+                        Some(item.span().start())
+                    }
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect())
+}
+
+fn remove_lines(source: &str, lines: &[LineColumn]) -> String {
+    source
+        .split_inclusive('\n')
+        .enumerate()
+        .filter(|(i, _)| !lines.iter().any(|line| line.line == i + 1))
+        .map(|(_, line)| line)
+        .collect()
+}
+
+/// Formats a sequence of statements with prettyplease, indented by `indent` spaces.
+/// The first line is not indented, since it replaces text that already is.
+pub fn format_statements(code: TokenStream, indent: usize) -> syn::Result<String> {
+    let file: syn::File = syn::parse2(quote! {
+        fn spar_format() {
+            #code
+        }
+    })?;
+    let formatted = prettyplease::unparse(&file);
+
+    // remove the function that wraps the statements, and the indentation inside it
+    let lines: Vec<&str> = formatted.lines().collect();
+    let body = &lines[1..lines.len() - 1];
+    let padding = " ".repeat(indent);
+    Ok(body
+        .iter()
+        .map(|line| line.strip_prefix("    ").unwrap_or(line))
+        .enumerate()
+        .map(|(i, line)| {
+            if i == 0 || line.is_empty() {
+                line.to_owned()
+            } else {
+                format!("{padding}{line}")
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n"))
+}
+
+/// Lints are reported like the compiler would, but the expanded code does not
+/// keep the calls that emit them
+fn expand(path: &str, invocation: &Invocation) -> syn::Result<String> {
+    let mut spar_stream = if invocation.path.ends_with("spar_pipeline") {
+        SparStream::pipeline(invocation.tokens.clone())?
+    } else {
+        SparStream::try_from(invocation.tokens.clone())?
+    };
+    lints::prune_restored(&mut spar_stream);
+    for lint in lints::check(&spar_stream)? {
+        let start = lint.span.start();
+        eprintln!(
+            "{path}:{}:{}: warning: {}",
+            start.line,
+            start.column + 1,
+            lint.message
+        );
+    }
+
+    // a stream that returns its items, or a handle, is an expression, even as a statement
+    let is_statement = invocation.is_statement && !spar_stream.is_expression();
+    let code = codegen(spar_stream);
+    if is_statement {
+        format_statements(code, invocation.indent)
+    } else {
+        let padding = " ".repeat(invocation.indent);
+        let statements = format_statements(code, invocation.indent + 4)?;
+        Ok(format!("{{\n{padding}    {statements}\n{padding}}}"))
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (in_place, path) = match args.as_slice() {
+        [path] => (false, path),
+        [flag, path] if flag == "--in-place" => (true, path),
+        _ => {
+            eprintln!("usage: spar-expand [--in-place] <rust source file>");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("failed to read {path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // the streams nested in the stages of a stream are expanded once it is
+    let mut expanded = source;
+    loop {
+        let invocations = match find_invocations(&expanded) {
+            Ok(invocations) => invocations,
+            Err(e) => {
+                report(path, None, &e);
+                return ExitCode::FAILURE;
+            }
+        };
+        if invocations.is_empty() {
+            break;
+        }
+
+        let mut failed = false;
+        expanded = rewrite(&expanded, &invocations, |invocation| {
+            match expand(path, invocation) {
+                Ok(code) => code,
+                Err(e) => {
+                    report(path, Some(invocation), &e);
+                    failed = true;
+                    expanded[invocation.start..invocation.end].to_owned()
+                }
+            }
+        });
+        if failed {
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let expanded = match find_imports(&expanded) {
+        Ok(imports) => remove_lines(&expanded, &imports),
+        Err(_) => expanded,
+    };
+
+    if in_place {
+        if let Err(e) = std::fs::write(path, expanded) {
+            eprintln!("failed to write {path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    } else {
+        print!("{expanded}");
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,431 @@
+//! Formats the SPar annotations inside `to_stream!` and `spar_pipeline!`
+//! invocations, which rustfmt leaves alone.
+//!
+//! Attributes are always written in the same order: INPUT, SHARED, OUTPUT,
+//! REPLICATE or ORDERED, NAME, LOOP_BACK, WINDOW, LAZY, DETACHED, NESTED, CANCEL
+//! and TIMEOUT. They are on a single line when they fit, or one per line
+//! otherwise. The code of the stream and of every stage is formatted by rustfmt,
+//! so comments are kept.
+//!
+//! usage: spar-fmt [--check] <rust source files>...
+
+extern crate proc_macro;
+
+mod common;
+#[allow(dead_code)]
+#[path = "../spar_stream.rs"]
+mod spar_stream;
+
+use std::io::Write;
+use std::process::{Command, ExitCode, Stdio};
+
+use common::{find_invocations, report, rewrite, Invocation, SourceMap};
+use proc_macro2::{Delimiter, Group, TokenStream, TokenTree};
+use quote::{quote, ToTokens};
+use spar_stream::{parse_spar_args, Replicate, SparAttrs, SparExpr, SparVar, VarType};
+use syn::buffer::TokenBuffer;
+
+const MAX_WIDTH: usize = 100;
+const INDENT: &str = "    ";
+
+fn error(message: impl std::fmt::Display) -> syn::Error {
+    syn::Error::new(proc_macro2::Span::call_site(), message)
+}
+
+/// Parses the arguments of a `to_stream!` or of a `STAGE`, and returns them with
+/// their code block
+fn parse_args(args: TokenStream) -> syn::Result<(SparAttrs, Group)> {
+    let buffer = TokenBuffer::new2(
+        TokenTree::Group(Group::new(Delimiter::Parenthesis, args.clone())).into_token_stream(),
+    );
+    let (attrs, _, _) = parse_spar_args(buffer.begin())?;
+    let block = args
+        .into_iter()
+        .find_map(|token| match token {
+            TokenTree::Group(group) if group.delimiter() == Delimiter::Brace => Some(group),
+            _ => None,
+        })
+        .ok_or_else(|| error("expected a '{...}' code block"))?;
+    Ok((attrs, block))
+}
+
+fn format_type(var_type: &VarType) -> syn::Result<String> {
+    let file: syn::File = syn::parse2(quote! { type T = #var_type; })?;
+    let formatted = prettyplease::unparse(&file);
+    Ok(formatted
+        .trim()
+        .trim_start_matches("type T = ")
+        .trim_end_matches(';')
+        .to_owned())
+}
+
+fn format_expr(expr: &SparExpr) -> syn::Result<String> {
+    let file: syn::File = syn::parse2(quote! { const T: _ = #expr; })?;
+    let formatted = prettyplease::unparse(&file);
+    Ok(formatted
+        .trim()
+        .trim_start_matches("const T: _ = ")
+        .trim_end_matches(';')
+        .to_owned())
+}
+
+fn format_vars(keyword: &str, vars: &[SparVar]) -> syn::Result<String> {
+    let vars = vars
+        .iter()
+        .map(|var| {
+            Ok(format!(
+                "{}: {}",
+                var.identifier,
+                format_type(&var.var_type)?
+            ))
+        })
+        .collect::<syn::Result<Vec<String>>>()?;
+    Ok(format!("{keyword}({})", vars.join(", ")))
+}
+
+/// The attributes, in the order in which they are always written
+fn format_attrs(attrs: &SparAttrs) -> syn::Result<Vec<String>> {
+    let mut formatted = Vec::new();
+    if !attrs.input.is_empty() {
+        formatted.push(format_vars("INPUT", &attrs.input)?);
+    }
+    if !attrs.shared.is_empty() {
+        formatted.push(format_vars("SHARED", &attrs.shared)?);
+    }
+    if !attrs.output.is_empty() {
+        formatted.push(format_vars("OUTPUT", &attrs.output)?);
+    }
+    if let Some(output_type) = &attrs.output_type {
+        formatted.push(format!("OUTPUT({})", format_type(output_type)?));
+    }
+    match &attrs.replicate {
+        Replicate::Lit(n) => formatted.push(format!("REPLICATE = {n}")),
+        Replicate::Var(v) => formatted.push(format!("REPLICATE = {v}")),
+        Replicate::SeqOrdered => formatted.push("ORDERED".to_owned()),
+        Replicate::SeqUnordered => (),
+    }
+    if let Some(name) = &attrs.name {
+        formatted.push(format!("NAME = {name}"));
+    }
+    if let Some(looped) = &attrs.loop_back {
+        formatted.push(format!(
+            "LOOP_BACK(to = {}, while = {})",
+            looped.to,
+            format_expr(&looped.condition)?
+        ));
+    }
+    if let Some(window) = &attrs.window {
+        let mut formatted_window = format!("WINDOW(size = {}", format_expr(&window.size)?);
+        if let Some(slide) = &window.slide {
+            formatted_window.push_str(&format!(", slide = {}", format_expr(slide)?));
+        }
+        formatted_window.push(')');
+        formatted.push(formatted_window);
+    }
+    if attrs.lazy {
+        formatted.push("LAZY".to_owned());
+    }
+    if attrs.detached {
+        formatted.push("DETACHED".to_owned());
+    }
+    if attrs.nested {
+        formatted.push("NESTED".to_owned());
+    }
+    if let Some(cancel) = &attrs.cancel {
+        formatted.push(format!("CANCEL = {}", format_expr(cancel)?));
+    }
+    if let Some(timeout) = &attrs.timeout {
+        formatted.push(format!("TIMEOUT = {}", format_expr(timeout)?));
+    }
+    Ok(formatted)
+}
+
+/// Formats the statements in `code` with rustfmt, indented by `indent` levels
+fn rustfmt(code: &str, indent: usize) -> syn::Result<String> {
+    let mut child = Command::new("rustfmt")
+        .args(["--edition", "2021", "--emit", "stdout", "--quiet"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| error(format!("failed to run rustfmt: {e}")))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(format!("fn spar_fmt() {{\n{code}\n}}\n").as_bytes())
+        .map_err(|e| error(format!("failed to run rustfmt: {e}")))?;
+    let output = child
+        .wait_with_output()
+        .map_err(|e| error(format!("failed to run rustfmt: {e}")))?;
+    if !output.status.success() {
+        return Err(error(format!(
+            "rustfmt failed:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+
+    // remove the function that wraps the statements, and re-indent them
+    let formatted = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = formatted.lines().collect();
+    // without statements, the function is written on a single line
+    if lines.len() < 2 {
+        return Ok(String::new());
+    }
+    let padding = INDENT.repeat(indent);
+    Ok(lines[1..lines.len() - 1]
+        .iter()
+        .map(|line| match line.strip_prefix(INDENT) {
+            Some(line) if !line.is_empty() => format!("{padding}{line}\n"),
+            _ => "\n".to_owned(),
+        })
+        .collect())
+}
+
+/// Formats `name(attrs, { code })`, with `code` already formatted and indented
+/// `indent + 1` levels. `name` starts at the current position, which is at
+/// `indent` levels
+fn format_call(name: &str, attrs: &[String], code: &str, indent: usize) -> String {
+    let padding = INDENT.repeat(indent);
+    let one_line = if attrs.is_empty() {
+        format!("{name}({{")
+    } else {
+        format!("{name}({}, {{", attrs.join(", "))
+    };
+
+    if padding.len() + one_line.len() <= MAX_WIDTH {
+        if code.is_empty() {
+            return format!("{one_line}}})");
+        }
+        return format!("{one_line}\n{code}{padding}}})");
+    }
+
+    let inner = INDENT.repeat(indent + 1);
+    let code: String = code
+        .lines()
+        .map(|line| {
+            if line.is_empty() {
+                "\n".to_owned()
+            } else {
+                format!("{INDENT}{line}\n")
+            }
+        })
+        .collect();
+    let mut call = format!("{name}(\n");
+    for attr in attrs {
+        call.push_str(&format!("{inner}{attr},\n"));
+    }
+    if code.is_empty() {
+        call.push_str(&format!("{inner}{{}},\n{padding})"));
+    } else {
+        call.push_str(&format!("{inner}{{\n{code}{inner}}},\n{padding})"));
+    }
+    call
+}
+
+/// A STAGE or a SPLIT found in the code of a stream
+struct Stage {
+    start: usize,
+    end: usize,
+    args: TokenStream,
+    is_split: bool,
+}
+
+/// Finds the STAGEs and SPLITs in `tokens`, which may be nested inside blocks. The
+/// stages in the code of a SPLIT or of a STAGE are formatted with it
+fn find_stages(tokens: TokenStream, map: &SourceMap, stages: &mut Vec<Stage>) {
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+    let mut i = 0;
+    while i < tokens.len() {
+        match (&tokens[i], tokens.get(i + 1)) {
+            (TokenTree::Ident(ident), Some(TokenTree::Group(args)))
+                if (ident == "STAGE" || ident == "SPLIT")
+                    && args.delimiter() == Delimiter::Parenthesis =>
+            {
+                stages.push(Stage {
+                    start: map.offset(ident.span().start()),
+                    end: map.offset(args.span_close().end()),
+                    args: args.stream(),
+                    is_split: ident == "SPLIT",
+                });
+                i += 1;
+            }
+            (TokenTree::Group(group), _) => find_stages(group.stream(), map, stages),
+            _ => (),
+        }
+        i += 1;
+    }
+}
+
+/// The text inside a group's delimiters
+fn inner_text<'a>(source: &'a str, map: &SourceMap, group: &Group) -> &'a str {
+    &source[map.offset(group.span_open().end())..map.offset(group.span_close().start())]
+}
+
+fn format_stage(
+    source: &str,
+    map: &SourceMap,
+    stage: &Stage,
+    indent: usize,
+) -> syn::Result<String> {
+    let (attrs, block) = parse_args(stage.args.clone())?;
+    // the code of a SPLIT, or of a stage with nested stages, has stages in it
+    let code = format_block(source, map, &block, indent + 1)?;
+    let name = if stage.is_split { "SPLIT" } else { "STAGE" };
+    Ok(format_call(name, &format_attrs(&attrs)?, &code, indent))
+}
+
+/// Splits `line` around its first stage placeholder, and returns the text before
+/// it, the index of its stage and the text after it
+fn find_placeholder(line: &str) -> Option<(&str, usize, &str)> {
+    let start = line.find("__spar_stage_")?;
+    let rest = &line[start + "__spar_stage_".len()..];
+    let (i, rest) = rest.split_once("!()")?;
+    Some((&line[..start], i.parse().ok()?, rest))
+}
+
+/// Formats the code of `block` and the stages in it, indented by `indent` levels
+fn format_block(
+    source: &str,
+    map: &SourceMap,
+    block: &Group,
+    indent: usize,
+) -> syn::Result<String> {
+    let mut stages = Vec::new();
+    find_stages(block.stream(), map, &mut stages);
+
+    // every STAGE is replaced by a placeholder that rustfmt accepts, and that is
+    // replaced by the formatted STAGE afterwards. The ';' after a STAGE is kept,
+    // but a STAGE in a match arm has none
+    let code = inner_text(source, map, block);
+    let offset = map.offset(block.span_open().end());
+    let mut placeholders = String::new();
+    let mut last = 0;
+    for (i, stage) in stages.iter().enumerate() {
+        placeholders.push_str(&code[last..stage.start - offset]);
+        placeholders.push_str(&format!("__spar_stage_{i}!()"));
+        last = stage.end - offset;
+    }
+    placeholders.push_str(&code[last..]);
+
+    // a line may have several placeholders, such as the arms of a one-line match
+    let mut code = String::new();
+    let mut placed = vec![false; stages.len()];
+    for line in rustfmt(&placeholders, indent)?.lines() {
+        let stage_indent = (line.len() - line.trim_start().len()) / INDENT.len();
+        let mut rest = line;
+        while let Some((before, i, after)) = find_placeholder(rest) {
+            code.push_str(before);
+            code.push_str(&format_stage(source, map, &stages[i], stage_indent)?);
+            placed[i] = true;
+            rest = after;
+        }
+        code.push_str(rest);
+        code.push('\n');
+    }
+    if let Some(i) = placed.iter().position(|placed| !placed) {
+        return Err(error(format!(
+            "rustfmt split the placeholder of stage {i}, which could not be formatted"
+        )));
+    }
+    Ok(code)
+}
+
+fn format_invocation(
+    source: &str,
+    map: &SourceMap,
+    invocation: &Invocation,
+) -> syn::Result<String> {
+    let (attrs, block) = parse_args(invocation.tokens.clone())?;
+    let indent = invocation.indent / INDENT.len();
+    let code = format_block(source, map, &block, indent + 1)?;
+
+    let mut formatted = format_call(
+        &format!("{}!", invocation.path),
+        &format_attrs(&attrs)?,
+        &code,
+        indent,
+    );
+    if invocation.is_statement {
+        formatted.push(';');
+    }
+    Ok(formatted)
+}
+
+/// Returns the formatted file, or None if it failed
+fn format_file(path: &str, source: &str) -> Option<String> {
+    let invocations = match find_invocations(source) {
+        Ok(invocations) => invocations,
+        Err(e) => {
+            report(path, None, &e);
+            return None;
+        }
+    };
+
+    let map = SourceMap::new(source);
+    let mut failed = false;
+    let formatted = rewrite(source, &invocations, |invocation| {
+        match format_invocation(source, &map, invocation) {
+            Ok(code) => code,
+            Err(e) => {
+                report(path, Some(invocation), &e);
+                failed = true;
+                source[invocation.start..invocation.end].to_owned()
+            }
+        }
+    });
+
+    if failed {
+        None
+    } else {
+        Some(formatted)
+    }
+}
+
+fn main() -> ExitCode {
+    let mut check = false;
+    let mut paths = Vec::new();
+    for arg in std::env::args().skip(1) {
+        if arg == "--check" {
+            check = true;
+        } else {
+            paths.push(arg);
+        }
+    }
+
+    if paths.is_empty() {
+        eprintln!("usage: spar-fmt [--check] <rust source files>...");
+        return ExitCode::FAILURE;
+    }
+
+    let mut status = ExitCode::SUCCESS;
+    for path in &paths {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("failed to read {path}: {e}");
+                status = ExitCode::FAILURE;
+                continue;
+            }
+        };
+
+        let Some(formatted) = format_file(path, &source) else {
+            status = ExitCode::FAILURE;
+            continue;
+        };
+
+        if formatted == source {
+            continue;
+        }
+
+        if check {
+            println!("{path}: to_stream! invocations are not formatted");
+            status = ExitCode::FAILURE;
+        } else if let Err(e) = std::fs::write(path, formatted) {
+            eprintln!("failed to write {path}: {e}");
+            status = ExitCode::FAILURE;
+        }
+    }
+
+    status
+}
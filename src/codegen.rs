@@ -1,67 +1,182 @@
-use crate::spar_stream::{Replicate, SparStage, SparStream, SparVar, VarType};
-use proc_macro2::{Group, Ident, Span, TokenStream, TokenTree};
-use quote::{quote, ToTokens};
+use crate::instrumentation::{self, item_envelope, item_envelope_type, StageInstrumentation};
+use crate::spar_stream::{
+    dispatch_marker, next_stage, Replicate, SparAttrs, SparStage, SparStream, SparVar, VarType,
+};
+use proc_macro2::{Delimiter, Group, Ident, Span, TokenStream, TokenTree};
+use quote::{format_ident, quote, quote_spanned, ToTokens};
+
+/// A span that resolves names at the call site, like `Span::call_site()`, but
+/// that points to `span`. Generated code that wraps the user's tokens is given
+/// such spans, so that errors in it underline the user's code instead of the
+/// whole macro invocation
+fn located_at(span: Span) -> Span {
+    Span::call_site().located_at(span)
+}
+
+fn stage_struct_ident(stage: &SparStage) -> Ident {
+    Ident::new(&format!("SparStage{}", stage.id), located_at(stage.span))
+}
 
 struct Dispatcher {
     code: TokenStream,
 }
 
+/// How the dispatcher posts its items to the pipeline
+#[derive(Clone, Copy)]
+struct Posting {
+    /// It stops once the stream is cancelled
+    cancellable: bool,
+    /// Every item is a batch of `SparItem`s
+    branched: bool,
+    /// The items that a stage sends back are posted between its own
+    looped: bool,
+    /// It counts its items, which the stages with a WINDOW receive in all
+    counted: bool,
+}
+
 impl Dispatcher {
-    fn copy_code(tokens: TokenTree, found: &mut bool, replacement: &TokenStream) -> TokenStream {
+    fn copy_code(
+        tokens: TokenTree,
+        found: &mut bool,
+        posts: &[(Ident, TokenStream)],
+    ) -> TokenStream {
         match tokens {
-            TokenTree::Group(group) => Group::new(
-                group.delimiter(),
-                group
-                    .stream()
-                    .into_iter()
-                    .map(|token| Self::copy_code(token, found, replacement))
-                    .collect(),
-            )
-            .into_token_stream(),
-            TokenTree::Ident(ident) => {
-                if ident == "__SPAR_MARKER__" {
+            TokenTree::Group(group) => {
+                let mut copy = Group::new(
+                    group.delimiter(),
+                    group
+                        .stream()
+                        .into_iter()
+                        .map(|token| Self::copy_code(token, found, posts))
+                        .collect(),
+                );
+                copy.set_span(group.span());
+                copy.into_token_stream()
+            }
+            TokenTree::Ident(ident) => match posts.iter().find(|(marker, _)| *marker == ident) {
+                Some((_, post)) => {
                     *found = true;
-                    replacement.clone()
-                } else {
-                    ident.into_token_stream()
+                    post.clone()
                 }
-            }
+                None => ident.into_token_stream(),
+            },
             TokenTree::Punct(punct) => punct.into_token_stream(),
             TokenTree::Literal(literal) => literal.into_token_stream(),
         }
     }
 
-    pub fn new(stage: &SparStage, next_stage: Option<&SparStage>) -> (Self, bool) {
-        let mut idents = Vec::new();
-        if let Some(next_stage) = next_stage {
-            for input in &next_stage.attrs.input {
-                idents.push(input.identifier.clone())
+    /// Posts what `receiver` receives to the pipeline, as a batch with its variant of
+    /// `SparItem` if the stream is branched. A cancellable dispatcher stops instead,
+    /// once the stream is cancelled. If a stage loops back, the items it sent back
+    /// so far are posted after it
+    fn post(receiver: &SparStage, posting: Posting) -> TokenStream {
+        let idents: Vec<&Ident> = receiver
+            .received()
+            .into_iter()
+            .map(|input| &input.identifier)
+            .collect();
+        let mut inputs = item_envelope(make_tuple(&idents));
+        if posting.branched {
+            let variant = stage_struct_ident(receiver);
+            inputs = quote! { vec![SparItem::#variant(#inputs)] };
+        }
+
+        let span = located_at(receiver.span);
+        let mut post = if posting.looped {
+            quote_spanned! {span=>
+                spar_feedback.dispatched();
+                spar_pipeline.post(#inputs).unwrap();
+                for spar_items in spar_feedback.looped() {
+                    spar_pipeline.post(spar_items).unwrap();
+                }
             }
         } else {
-            for input in &stage.attrs.input {
-                idents.push(input.identifier.clone())
-            }
+            quote_spanned! {span=> spar_pipeline.post(#inputs).unwrap(); }
+        };
+        if posting.counted {
+            post.extend(quote! {
+                spar_posted.set(spar_posted.get() + 1);
+            });
         }
-        let inputs = make_tuple(&idents);
+        let item_numbered = instrumentation::item_numbered();
+        let item_dispatched = instrumentation::item_dispatched();
+        // a block, since a match arm or a closure can dispatch items too. A closure
+        // cannot break out of the dispatcher, so it only stops posting its items
+        match (posting.cancellable, receiver.in_closure) {
+            (true, true) => quote_spanned! {span=>
+                {
+                    if !spar_cancel.is_cancelled() {
+                        #item_numbered
+                        #post
+                        #item_dispatched
+                    }
+                }
+            },
+            (true, false) => quote_spanned! {span=>
+                {
+                    if spar_cancel.is_cancelled() {
+                        break 'spar_dispatch;
+                    }
+                    #item_numbered
+                    #post
+                    #item_dispatched
+                }
+            },
+            (false, _) => quote_spanned! {span=>
+                {
+                    #item_numbered
+                    #post
+                    #item_dispatched
+                }
+            },
+        }
+    }
 
-        let pipeline_post = quote! { spar_pipeline.post(#inputs).unwrap(); };
+    /// Replaces the marker of every branch in the code of `stage` with the code that
+    /// posts its items to the first stage of the branch
+    pub fn new(stage: &SparStage, receivers: &[&SparStage], posting: Posting) -> (Self, bool) {
+        let posts: Vec<(Ident, TokenStream)> = receivers
+            .iter()
+            .map(|receiver| (dispatch_marker(receiver.id), Self::post(receiver, posting)))
+            .collect();
         let mut gen = TokenStream::new();
         let mut found = false;
         for token in stage.code.clone().into_iter() {
-            gen.extend(Self::copy_code(token, &mut found, &pipeline_post));
+            gen.extend(Self::copy_code(token, &mut found, &posts));
         }
 
         if found {
             (Self { code: gen }, true)
         } else {
+            let receiver = receivers.first().copied().unwrap_or(stage);
             (
                 Self {
-                    code: pipeline_post,
+                    code: Self::post(receiver, posting),
                 },
                 false,
             )
         }
     }
+
+    /// The code of a source stage evaluates to an iterator over its outputs. It runs
+    /// on the calling thread, and every item it yields is posted to `next_stage`
+    pub fn source(stage: &SparStage, next_stage: &SparStage, posting: Posting) -> Self {
+        let (out_idents, out_types) = get_idents_and_types_from_spar_vars(&stage.attrs.output);
+        let output_tuple = make_tuple(&out_idents);
+        let out_types = make_tuple(&out_types);
+        let mut items = Group::new(Delimiter::Brace, stage.code.clone());
+        items.set_span(located_at(stage.span));
+        let pipeline_post = Self::post(next_stage, posting);
+
+        Self {
+            code: quote_spanned! {located_at(stage.span)=>
+                for spar_source_item in #items {
+                    let #output_tuple: #out_types = spar_source_item;
+                    #pipeline_post
+                }
+            },
+        }
+    }
 }
 
 impl ToTokens for Dispatcher {
@@ -72,12 +187,22 @@ impl ToTokens for Dispatcher {
 
 ///Note: replicate defaults to 1 when it is not given.
 ///If REPLICATE argument exists, then it defaults to what was written in the code
-///if SPAR_NUM_WORKERS is set, all REPLICATES are set to that value
-fn gen_replicate(replicate: &Replicate) -> TokenStream {
+///if SPAR_NUM_WORKERS is set, all REPLICATES are set to that value.
+///The stages of a nested stream cannot have more replicas than its budget
+fn gen_replicate(replicate: &Replicate, nested: bool) -> TokenStream {
+    let replicas = gen_replicas(replicate);
+    if nested && replicate.is_replicate() {
+        quote! { spar_rust_runtime::__private::nested_replicas(#replicas, spar_budget) }
+    } else {
+        replicas
+    }
+}
+
+fn gen_replicas(replicate: &Replicate) -> TokenStream {
     //NOTE: this needs to be i32 in rust_spp
     match replicate {
         Replicate::Var(v) => {
-            quote!(#v as i32)
+            quote_spanned!(located_at(v.span())=> #v as i32)
         }
 
         Replicate::Lit(n) => {
@@ -116,12 +241,53 @@ fn gen_spar_num_workers() -> TokenStream {
     }
 }
 
-fn make_tuple<T: ToTokens>(tokens: &[T]) -> TokenStream {
-    quote! { ( #(#tokens),* ) }
+/// Marks every `to_stream!` in `code` as NESTED, so that it shares the threads of
+/// the stage that runs it. Returns whether there was any. The streams started by
+/// the functions that the stage calls aren't in `code`, so they aren't marked
+fn nest_streams(code: TokenStream, nested: &mut TokenStream) -> bool {
+    let tokens: Vec<TokenTree> = code.into_iter().collect();
+    let mut found = false;
+    let mut i = 0;
+    while i < tokens.len() {
+        match (&tokens[i], tokens.get(i + 1), tokens.get(i + 2)) {
+            (
+                TokenTree::Ident(ident),
+                Some(TokenTree::Punct(bang)),
+                Some(TokenTree::Group(args)),
+            ) if ident == "to_stream" && bang.as_char() == '!' => {
+                let args_stream = args.stream();
+                let mut args_copy = Group::new(args.delimiter(), quote! { NESTED, #args_stream });
+                args_copy.set_span(args.span());
+                nested.extend([tokens[i].clone(), tokens[i + 1].clone(), args_copy.into()]);
+                found = true;
+                i += 3;
+                continue;
+            }
+            (TokenTree::Group(group), _, _) => {
+                let mut inner = TokenStream::new();
+                found |= nest_streams(group.stream(), &mut inner);
+                let mut copy = Group::new(group.delimiter(), inner);
+                copy.set_span(group.span());
+                nested.extend([TokenTree::from(copy)]);
+            }
+            (token, _, _) => nested.extend([token.clone()]),
+        }
+        i += 1;
+    }
+    found
+}
+
+/// Whether the code of the stage runs any stream, which is nested in it
+fn has_nested_streams(stage: &SparStage) -> bool {
+    nest_streams(stage.code.clone(), &mut TokenStream::new())
 }
 
-fn make_mut_tuple<T: ToTokens>(tokens: &[T]) -> TokenStream {
-    quote! { ( #(mut #tokens),* ) }
+/// A single element is not wrapped in parenthesis, since `(a)` is just `a`
+fn make_tuple<T: ToTokens>(tokens: &[T]) -> TokenStream {
+    match tokens {
+        [token] => token.to_token_stream(),
+        _ => quote! { ( #(#tokens),* ) },
+    }
 }
 
 fn get_idents_and_types_from_spar_vars(vars: &[SparVar]) -> (Vec<Ident>, Vec<VarType>) {
@@ -136,13 +302,195 @@ fn get_idents_and_types_from_spar_vars(vars: &[SparVar]) -> (Vec<Ident>, Vec<Var
     (idents, types)
 }
 
-fn rust_spp_stage_struct_gen(stage: &SparStage) -> TokenStream {
-    let (in_idents, in_types) = get_idents_and_types_from_spar_vars(&stage.attrs.input);
-    let (out_idents, out_types) = get_idents_and_types_from_spar_vars(&stage.attrs.output);
+/// Forwarded variables are bound to their own identifiers, so that the code of the
+/// stage cannot shadow them
+fn forward_ident(var: &SparVar) -> Ident {
+    Ident::new(
+        &format!("spar_forward_{}", var.identifier),
+        Span::call_site(),
+    )
+}
+
+/// The value of an external variable that the last stage produced for an item,
+/// which is restored once the stream ends
+fn restore_ident(var: &SparVar) -> Ident {
+    Ident::new(
+        &format!("spar_restore_{}", var.identifier),
+        Span::call_site(),
+    )
+}
+
+/// Methods called on the probe are those of `spar_rust_runtime::__private::Restored`
+/// if the type implements `Restore`, and those of `NotRestored` otherwise
+pub fn restore_probe(var_type: &VarType) -> TokenStream {
+    quote! { (&spar_rust_runtime::__private::Probe::<#var_type>::new()) }
+}
+
+/// The pattern that binds what a stage receives
+fn input_pattern(stage: &SparStage) -> TokenStream {
+    let mut patterns: Vec<TokenStream> = stage
+        .attrs
+        .input
+        .iter()
+        .map(|var| {
+            let ident = &var.identifier;
+            quote! { mut #ident }
+        })
+        .collect();
+    patterns.extend(
+        stage
+            .forwarded
+            .iter()
+            .map(|var| forward_ident(var).into_token_stream()),
+    );
+    make_tuple(&patterns)
+}
+
+/// The types and the tuple that a stage sends. Stages send whatever the next
+/// stage receives, in the same order, and the last stage sends its outputs to the collector
+fn output_tuple(stage: &SparStage, next: Option<&SparStage>) -> (TokenStream, TokenStream) {
+    match next {
+        Some(next) => {
+            let received = next.received();
+            let types: Vec<&VarType> = received.iter().map(|var| &var.var_type).collect();
+            let values: Vec<TokenStream> = received
+                .iter()
+                .map(|var| {
+                    if stage
+                        .forwarded
+                        .iter()
+                        .any(|forwarded| forwarded.identifier == var.identifier)
+                    {
+                        forward_ident(var).into_token_stream()
+                    } else {
+                        var.identifier.to_token_stream()
+                    }
+                })
+                .collect();
+            (
+                item_envelope_type(make_tuple(&types)),
+                item_envelope(make_tuple(&values)),
+            )
+        }
+        // the collector doesn't track items. External variables are sent as the value
+        // to restore, if any
+        None => {
+            let mut types = Vec::new();
+            let mut values = Vec::new();
+            for var in &stage.attrs.output {
+                let var_type = &var.var_type;
+                if stage.state.contains(var) {
+                    let restore = restore_ident(var);
+                    types.push(quote! { Option<#var_type> });
+                    values.push(restore.into_token_stream());
+                } else {
+                    types.push(var_type.to_token_stream());
+                    values.push(var.identifier.to_token_stream());
+                }
+            }
+            (make_tuple(&types), make_tuple(&values))
+        }
+    }
+}
+
+/// The local that holds the cancellation of a stage, until it is moved into the pipeline
+fn cancel_ident(stage: &SparStage) -> Ident {
+    format_ident!("spar_cancel_{}", stage.id)
+}
+
+/// The local that holds what a stage that loops back sends its items back with
+fn looper_ident(stage: &SparStage) -> Ident {
+    format_ident!("spar_loop_{}", stage.id)
+}
+
+/// The local that holds the size and the slide of the windows of a stage
+fn window_ident(stage: &SparStage) -> Ident {
+    format_ident!("spar_window_{}", stage.id)
+}
+
+/// The cancellation that the dispatcher and the stages check before every item
+fn gen_cancellation(attrs: &SparAttrs) -> TokenStream {
+    let token = match &attrs.cancel {
+        Some(token) => {
+            let span = token
+                .0
+                .clone()
+                .into_iter()
+                .next()
+                .map_or(Span::call_site(), |token| token.span());
+            quote_spanned! {located_at(span)=> Some(spar_rust_runtime::CancelToken::clone(&#token)) }
+        }
+        None => quote! { None },
+    };
+    let timeout = match &attrs.timeout {
+        Some(timeout) => {
+            let span = timeout
+                .0
+                .clone()
+                .into_iter()
+                .next()
+                .map_or(Span::call_site(), |token| token.span());
+            quote_spanned! {located_at(span)=> Some(#timeout) }
+        }
+        None => quote! { None },
+    };
+    quote! {
+        let spar_cancel = spar_rust_runtime::__private::Cancellation::new(#token, #timeout);
+    }
+}
+
+/// Where a stage of a branched stream is in the pipeline
+#[derive(Clone, Copy)]
+enum Branch {
+    Inner,
+    Last,
+}
+
+/// The code of a SPLIT, which pushes the items it dispatches to `spar_items`, each
+/// as the variant of `SparItem` for the first stage of its branch
+fn split_code(stage: &SparStage, receivers: &[&SparStage]) -> TokenStream {
+    let posts: Vec<(Ident, TokenStream)> = receivers
+        .iter()
+        .map(|receiver| {
+            let (_, item) = output_tuple(stage, Some(receiver));
+            let variant = stage_struct_ident(receiver);
+            (
+                dispatch_marker(receiver.id),
+                quote_spanned! {located_at(receiver.span)=>
+                    {
+                        spar_items.push(SparItem::#variant(#item));
+                    }
+                },
+            )
+        })
+        .collect();
+    let mut found = false;
+    stage
+        .code
+        .clone()
+        .into_iter()
+        .map(|token| Dispatcher::copy_code(token, &mut found, &posts))
+        .collect()
+}
+
+fn rust_spp_stage_struct_gen(
+    stage: &SparStage,
+    next: Option<&SparStage>,
+    receivers: &[&SparStage],
+    target: Option<&SparStage>,
+    cancellable: bool,
+    branch: Option<Branch>,
+) -> TokenStream {
+    let in_types: Vec<&VarType> = stage.received().iter().map(|var| &var.var_type).collect();
 
-    let struct_name = format!("SparStage{}", stage.id);
-    let struct_ident = Ident::new(&struct_name, Span::call_site());
-    let stage_code = &stage.code;
+    let struct_ident = stage_struct_ident(stage);
+    let code = if stage.is_split {
+        split_code(stage, receivers)
+    } else {
+        stage.code.clone()
+    };
+    let mut stage_code = TokenStream::new();
+    let nests = nest_streams(code, &mut stage_code);
     let state = &stage.state;
     let state_idents: TokenStream = state
         .iter()
@@ -152,138 +500,639 @@ fn rust_spp_stage_struct_gen(stage: &SparStage) -> TokenStream {
         })
         .collect();
 
+    let StageInstrumentation {
+        fields,
+        field_idents,
+        begin,
+        end,
+        items,
+        ..
+    } = StageInstrumentation::new(stage, &struct_ident);
+
+    // the stages of a cancellable stream skip the items they receive once it is cancelled
+    let (cancel_field, cancel_ident) = if cancellable {
+        (
+            quote! { spar_cancel: spar_rust_runtime::__private::Cancellation, },
+            quote! { spar_cancel, },
+        )
+    } else {
+        (TokenStream::new(), TokenStream::new())
+    };
+    let skip = |skipped: TokenStream| {
+        if cancellable {
+            quote! {
+                if self.spar_cancel.is_cancelled() {
+                    return #skipped;
+                }
+            }
+        } else {
+            TokenStream::new()
+        }
+    };
+    // the streams nested in a stage share the threads of its replica
+    let (budget_field, budget_ident, enter_budget) = if nests {
+        (
+            quote! { spar_budget: usize, },
+            quote! { spar_budget, },
+            quote! {
+                let _spar_budget = spar_rust_runtime::__private::Budget::enter(self.spar_budget);
+            },
+        )
+    } else {
+        (TokenStream::new(), TokenStream::new(), TokenStream::new())
+    };
+    // a stage that loops back sends each item back to its target while the
+    // condition holds, and to the next stage once it doesn't
+    let (loop_field, loop_ident) = if target.is_some() {
+        (
+            quote! { spar_loop: spar_rust_runtime::__private::Looper<Vec<SparItem>>, },
+            quote! { spar_loop, },
+        )
+    } else {
+        (TokenStream::new(), TokenStream::new())
+    };
+    // a stage with a WINDOW keeps the items it receives until they make a window
+    let (window_field, window_ident) = if stage.attrs.window.is_some() {
+        let item_type = item_envelope_type(make_tuple(&in_types));
+        (
+            quote! { spar_window: spar_rust_runtime::__private::Window<#item_type>, },
+            quote! { spar_window, },
+        )
+    } else {
+        (TokenStream::new(), TokenStream::new())
+    };
+    let send = |sent: TokenStream, left: TokenStream| match (&stage.attrs.loop_back, target) {
+        (Some(looped), Some(target)) => {
+            let (_, item) = output_tuple(stage, Some(target));
+            let variant = stage_struct_ident(target);
+            let condition = &looped.condition;
+            quote! {
+                if #condition {
+                    self.spar_loop.loop_back(vec![SparItem::#variant(#item)]);
+                    #left
+                } else {
+                    self.spar_loop.leave();
+                    #sent
+                }
+            }
+        }
+        _ => sent,
+    };
+
     let mut code = quote! {
         struct #struct_ident {
-            #(#state),*
+            #(#state,)*
+            #cancel_field
+            #budget_field
+            #loop_field
+            #window_field
+            #fields
         }
 
         impl #struct_ident {
-            fn new(#(#state),*) -> Self {
-                Self { #state_idents }
+            fn new(#(#state,)* #cancel_field #budget_field #loop_field #window_field #fields) -> Self {
+                Self { #state_idents #cancel_ident #budget_ident #loop_ident #window_ident #field_idents }
             }
         }
+
+        #items
     };
 
     let state_deconstruct: TokenStream = state
         .iter()
         .flat_map(|var| {
             let ident = &var.identifier;
+            let span = located_at(ident.span());
             if stage.attrs.output.contains(var) {
-                quote! {
-                    let mut #ident = self.#ident.clone();
+                let restore = restore_ident(var);
+                let var_type = &var.var_type;
+                let probe = restore_probe(var_type);
+                quote_spanned! {span=>
+                    let mut #restore: Option<#var_type> = #probe.spar_start();
+                    let #ident: &mut #var_type = match &mut #restore {
+                        Some(#ident) => #ident,
+                        None => &mut self.#ident,
+                    };
                 }
             } else {
-                quote! {
+                quote_spanned! {span=>
                     let #ident = &mut self.#ident;
                 }
             }
         })
         .collect();
 
-    if !in_types.is_empty() && !out_types.is_empty() {
-        let in_types = make_tuple(&in_types);
-        let out_types = make_tuple(&out_types);
+    let state_deconstruct = if state.iter().any(|var| stage.attrs.output.contains(var)) {
+        quote! {
+            #[allow(unused_imports)]
+            use spar_rust_runtime::__private::{NotRestored as _, Restored as _};
+            #state_deconstruct
+        }
+    } else {
+        state_deconstruct
+    };
 
-        let input_tuple = make_mut_tuple(&in_idents);
-        let output_tuple = make_tuple(&out_idents);
+    if in_types.is_empty() {
+        // the parser only accepts stages without INPUT as source stages, which
+        // become the dispatcher instead of a stage of the pipeline
+        code.extend(
+            syn::Error::new(
+                stage.span,
+                "a stage without INPUT can only be a source stage",
+            )
+            .into_compile_error(),
+        );
+        return code;
+    }
 
-        code.extend(quote! {
-            impl rust_spp::blocks::inout_block::InOut<#in_types, #out_types> for #struct_ident {
-                fn process(&mut self, input: #in_types) -> Option<#out_types> {
-                    let #input_tuple = input;
+    let in_types = item_envelope_type(make_tuple(&in_types));
+    let input_tuple = item_envelope(input_pattern(stage));
+    // a stage with a WINDOW binds each of its inputs to a `Vec` of the values in the
+    // window, oldest first. A window is tracked as its last item
+    let (process_types, bind_input) = if stage.attrs.window.is_some() {
+        let (idents, types) = get_idents_and_types_from_spar_vars(&stage.attrs.input);
+        let values: Vec<Ident> = idents
+            .iter()
+            .map(|ident| format_ident!("spar_{}", ident))
+            .collect();
+        let mut pattern = make_tuple(&values);
+        let mut item = TokenStream::new();
+        if instrumentation::track_items() {
+            pattern = quote! { (_, #pattern) };
+            item = quote! {
+                let spar_item = input.last().map_or(0, |spar_entry| spar_entry.0);
+            };
+        }
+        (
+            quote! { Vec<#in_types> },
+            quote! {
+                #item
+                #(
+                    #[allow(unused_mut)]
+                    let mut #idents: Vec<#types> = Vec::with_capacity(input.len());
+                )*
+                for #pattern in input {
+                    #(#idents.push(#values);)*
+                }
+            },
+        )
+    } else {
+        (in_types.clone(), quote! { let #input_tuple = input; })
+    };
+    // the types it sends, if it sends anything. A SPLIT pushes what it sends instead
+    let sends = next.is_some() || !stage.attrs.output.is_empty();
+    let (out_types, process) = if sends && !stage.is_split {
+        let (out_types, output_tuple) = output_tuple(stage, next);
+        let sent = send(quote! { Some(#output_tuple) }, quote! { None });
+        let skip_item = skip(quote! { None });
+        let process = if end.is_empty() {
+            quote! {
+                #begin
+                #state_deconstruct
+                #stage_code
+                #sent
+            }
+        } else {
+            quote! {
+                #begin
+                let spar_output = {
                     #state_deconstruct
                     #stage_code
-                    Some(#output_tuple)
-                }
+                    #sent
+                };
+                #end
+                spar_output
             }
-        });
-    } else if !in_types.is_empty() {
-        let in_types = make_tuple(&in_types);
-        let input_tuple = make_mut_tuple(&in_idents);
-        code.extend(quote! {
-            impl rust_spp::blocks::in_block::In<#in_types> for #struct_ident {
-                fn process(&mut self, input: #in_types, order: u64) {
-                    let #input_tuple = input;
+        };
+        (
+            Some(out_types),
+            quote! {
+                #skip_item
+                #enter_budget
+                #bind_input
+                #process
+            },
+        )
+    } else {
+        let sent = send(TokenStream::new(), TokenStream::new());
+        let skip_item = skip(TokenStream::new());
+        let process = if end.is_empty() {
+            quote! {
+                #begin
+                #state_deconstruct
+                #stage_code
+                #sent
+            }
+        } else {
+            quote! {
+                #begin
+                {
                     #state_deconstruct
                     #stage_code
+                    #sent
                 }
+                #end
             }
-        });
-    } else {
-        //ERROR
-        panic!("ERROR: Stage without input is invalid!");
+        };
+        (
+            None,
+            quote! {
+                #skip_item
+                #enter_budget
+                #bind_input
+                #process
+            },
+        )
+    };
+
+    let branch = match branch {
+        Some(branch) => branch,
+        None => {
+            code.extend(match out_types {
+                Some(out_types) => quote! {
+                    impl rust_spp::blocks::inout_block::InOut<#in_types, #out_types> for #struct_ident {
+                        fn process(&mut self, input: #in_types) -> Option<#out_types> {
+                            #process
+                        }
+                    }
+                },
+                None => quote! {
+                    impl rust_spp::blocks::in_block::In<#in_types> for #struct_ident {
+                        fn process(&mut self, input: #in_types, order: u64) {
+                            #process
+                        }
+                    }
+                },
+            });
+            return code;
+        }
+    };
+
+    // in a branched stream, every item is a batch of `SparItem`s, since a SPLIT can
+    // dispatch several items for each one it receives. A stage processes the items
+    // of its variant, and passes the items for the stages after it through
+    let process = match (&out_types, stage.is_split) {
+        (Some(out_types), _) => quote! {
+            fn spar_process(&mut self, input: #process_types) -> Option<#out_types> {
+                #process
+            }
+        },
+        (None, true) => quote! {
+            fn spar_process(&mut self, input: #process_types, spar_items: &mut Vec<SparItem>) {
+                #process
+            }
+        },
+        (None, false) => quote! {
+            fn spar_process(&mut self, input: #process_types) {
+                #process
+            }
+        },
+    };
+    code.extend(quote! {
+        impl #struct_ident {
+            #process
+        }
+    });
+    if stage.attrs.window.is_some() {
+        code.extend(window_impls(stage, next, &in_types, out_types, branch));
+        return code;
     }
+    code.extend(match (branch, out_types) {
+        (Branch::Last, Some(out_types)) => quote! {
+            impl rust_spp::blocks::inout_block::InOut<Vec<SparItem>, Vec<#out_types>> for #struct_ident {
+                fn process(&mut self, input: Vec<SparItem>) -> Option<Vec<#out_types>> {
+                    let spar_outputs: Vec<#out_types> = input
+                        .into_iter()
+                        .filter_map(|item| match item {
+                            SparItem::#struct_ident(input) => self.spar_process(input),
+                            _ => None,
+                        })
+                        .collect();
+                    (!spar_outputs.is_empty()).then_some(spar_outputs)
+                }
+            }
+        },
+        (Branch::Last, None) => quote! {
+            impl rust_spp::blocks::in_block::In<Vec<SparItem>> for #struct_ident {
+                fn process(&mut self, input: Vec<SparItem>, _order: u64) {
+                    for item in input {
+                        if let SparItem::#struct_ident(input) = item {
+                            self.spar_process(input);
+                        }
+                    }
+                }
+            }
+        },
+        (Branch::Inner, _) => {
+            let process = match next {
+                _ if stage.is_split => quote! { self.spar_process(input, &mut spar_items) },
+                Some(next) => {
+                    let next_ident = stage_struct_ident(next);
+                    quote! { spar_items.extend(self.spar_process(input).map(SparItem::#next_ident)) }
+                }
+                // the last stage of a branch that no stage merges consumes its items
+                None => quote! { self.spar_process(input) },
+            };
+            quote! {
+                impl rust_spp::blocks::inout_block::InOut<Vec<SparItem>, Vec<SparItem>> for #struct_ident {
+                    fn process(&mut self, input: Vec<SparItem>) -> Option<Vec<SparItem>> {
+                        let mut spar_items = Vec::with_capacity(input.len());
+                        for item in input {
+                            match item {
+                                SparItem::#struct_ident(input) => #process,
+                                item => spar_items.push(item),
+                            }
+                        }
+                        (!spar_items.is_empty()).then_some(spar_items)
+                    }
+                }
+            }
+        }
+    });
 
     code
 }
 
+/// The blocks of a stage with a WINDOW, which gathers the items it receives into
+/// windows, and runs once for every window. Once every item of the stream reached
+/// it, it runs on the last, partial window, if any, and tells the next stage how
+/// many items it sent in all
+fn window_impls(
+    stage: &SparStage,
+    next: Option<&SparStage>,
+    item_type: &TokenStream,
+    out_types: Option<TokenStream>,
+    branch: Branch,
+) -> TokenStream {
+    let struct_ident = stage_struct_ident(stage);
+    let mut code = quote! {
+        impl #struct_ident {
+            fn spar_windows(&mut self, input: Vec<SparItem>) -> (Vec<Vec<#item_type>>, Vec<SparItem>, Option<u64>) {
+                let mut spar_windows = Vec::new();
+                let mut spar_items = Vec::new();
+                let mut spar_end = None;
+                for item in input {
+                    match item {
+                        SparItem::#struct_ident(input) => spar_windows.extend(self.spar_window.push(input)),
+                        SparItem::SparEnd(spar_expected) => self.spar_window.end(spar_expected),
+                        item => spar_items.push(item),
+                    }
+                    if let Some((spar_partial, spar_sent)) = self.spar_window.finish() {
+                        spar_windows.extend(spar_partial);
+                        spar_end = Some(spar_sent);
+                    }
+                }
+                (spar_windows, spar_items, spar_end)
+            }
+        }
+    };
+    code.extend(match (branch, out_types) {
+        (Branch::Last, Some(out_types)) => quote! {
+            impl rust_spp::blocks::inout_block::InOut<Vec<SparItem>, Vec<#out_types>> for #struct_ident {
+                fn process(&mut self, input: Vec<SparItem>) -> Option<Vec<#out_types>> {
+                    let (spar_windows, _, _) = self.spar_windows(input);
+                    let spar_outputs: Vec<#out_types> = spar_windows
+                        .into_iter()
+                        .filter_map(|input| self.spar_process(input))
+                        .collect();
+                    (!spar_outputs.is_empty()).then_some(spar_outputs)
+                }
+            }
+        },
+        (Branch::Last, None) => quote! {
+            impl rust_spp::blocks::in_block::In<Vec<SparItem>> for #struct_ident {
+                fn process(&mut self, input: Vec<SparItem>, _order: u64) {
+                    let (spar_windows, _, _) = self.spar_windows(input);
+                    for input in spar_windows {
+                        self.spar_process(input);
+                    }
+                }
+            }
+        },
+        (Branch::Inner, _) => {
+            let process = match next {
+                Some(next) => {
+                    let next_ident = stage_struct_ident(next);
+                    quote! { spar_items.extend(self.spar_process(input).map(SparItem::#next_ident)) }
+                }
+                None => quote! { self.spar_process(input) },
+            };
+            quote! {
+                impl rust_spp::blocks::inout_block::InOut<Vec<SparItem>, Vec<SparItem>> for #struct_ident {
+                    fn process(&mut self, input: Vec<SparItem>) -> Option<Vec<SparItem>> {
+                        let (spar_windows, mut spar_items, spar_end) = self.spar_windows(input);
+                        for input in spar_windows {
+                            #process;
+                        }
+                        spar_items.extend(spar_end.map(SparItem::SparEnd));
+                        (!spar_items.is_empty()).then_some(spar_items)
+                    }
+                }
+            }
+        }
+    });
+
+    code
+}
+
+/// The `SparItem` enum of a branched stream, with a variant for the items of every
+/// stage, named after it. A stream with WINDOWs also sends the number of items its
+/// dispatcher posted, once it is done
+fn gen_item_enum(stages: &[SparStage], ends: bool) -> TokenStream {
+    let variants = stages.iter().map(|stage| {
+        let ident = stage_struct_ident(stage);
+        let types: Vec<&VarType> = stage.received().iter().map(|var| &var.var_type).collect();
+        let types = item_envelope_type(make_tuple(&types));
+        quote! { #ident(#types) }
+    });
+    let end = if ends {
+        quote! { SparEnd(u64), }
+    } else {
+        TokenStream::new()
+    };
+    quote! {
+        enum SparItem {
+            #(#variants,)*
+            #end
+        }
+    }
+}
+
 fn rust_spp_gen_top_level_code(spar_stream: &mut SparStream) -> (Vec<TokenStream>, Dispatcher) {
+    let posting = Posting {
+        cancellable: spar_stream.attrs.detached || spar_stream.is_cancellable(),
+        branched: spar_stream.is_branched(),
+        looped: spar_stream.loops(),
+        counted: spar_stream.has_windows(),
+    };
+    let skips = spar_stream.is_cancellable();
     let SparStream { ref mut stages, .. } = spar_stream;
     let mut structs = Vec::new();
 
-    let (dispatcher, found) = Dispatcher::new(&stages[0], stages.get(1));
+    let (dispatcher, found) = if stages[0].is_source {
+        (Dispatcher::source(&stages[0], &stages[1], posting), true)
+    } else {
+        let receivers: Vec<&SparStage> = stages
+            .iter()
+            .filter(|receiver| receiver.prev.contains(&stages[0].id))
+            .collect();
+        Dispatcher::new(&stages[0], &receivers, posting)
+    };
     if found {
         stages.remove(0);
     }
 
-    for stage in stages {
-        structs.push(rust_spp_stage_struct_gen(stage));
+    for (i, stage) in stages.iter().enumerate() {
+        let branch = match (posting.branched, i + 1 == stages.len()) {
+            (false, _) => None,
+            (true, false) => Some(Branch::Inner),
+            (true, true) => Some(Branch::Last),
+        };
+        let receivers: Vec<&SparStage> = stages
+            .iter()
+            .filter(|receiver| stage.is_split && receiver.prev.contains(&stage.id))
+            .collect();
+        let target = stage
+            .attrs
+            .loop_back
+            .as_ref()
+            .and_then(|looped| stages.iter().find(|target| target.id == looped.target));
+        structs.push(rust_spp_stage_struct_gen(
+            stage,
+            next_stage(stages, stage),
+            &receivers,
+            target,
+            skips,
+            branch,
+        ));
     }
 
     (structs, dispatcher)
 }
 
-fn rust_spp_pipeline_arg(stage: &SparStage) -> TokenStream {
-    let SparStage {
-        attrs, id, state, ..
-    } = stage;
-    let struct_name = format!("SparStage{id}");
-    let struct_ident = Ident::new(&struct_name, Span::call_site());
+fn rust_spp_pipeline_arg(stage: &SparStage, cancellable: bool, nested: bool) -> TokenStream {
+    let SparStage { attrs, state, .. } = stage;
+    let struct_ident = stage_struct_ident(stage);
+    let span = located_at(stage.span);
 
-    let struct_new_args: Vec<TokenStream> = state
+    let mut struct_new_args: Vec<TokenStream> = state
         .iter()
         .map(|var| {
             let ident = &var.identifier;
-            quote! { #ident }
+            if attrs.output.contains(var) {
+                let probe = restore_probe(&var.var_type);
+                quote_spanned! {located_at(ident.span())=> #probe.spar_copy(&#ident) }
+            } else {
+                quote_spanned! {located_at(ident.span())=> #ident.clone() }
+            }
         })
         .collect();
 
+    if cancellable {
+        let cancel = cancel_ident(stage);
+        struct_new_args.push(quote! { #cancel.clone() });
+    }
+    if has_nested_streams(stage) {
+        let replicas = gen_replicate(&attrs.replicate, nested);
+        struct_new_args
+            .push(quote! { spar_rust_runtime::__private::share(spar_budget, #replicas) });
+    }
+    if attrs.loop_back.is_some() {
+        let looper = looper_ident(stage);
+        struct_new_args.push(quote! { #looper.clone() });
+    }
+    if attrs.window.is_some() {
+        let window = window_ident(stage);
+        struct_new_args
+            .push(quote! { spar_rust_runtime::__private::Window::new(#window.0, #window.1) });
+    }
+    struct_new_args.extend(StageInstrumentation::new(stage, &struct_ident).new_args);
+
+    let new = quote_spanned! {span=> #struct_ident::new( #(#struct_new_args),* ) };
     match attrs.replicate {
         Replicate::Lit(_) | Replicate::Var(_) => {
-            let replicate = gen_replicate(&attrs.replicate);
-            quote! { rust_spp::parallel!(#struct_ident::new( #(#struct_new_args.clone()),* ), #replicate) }
+            let replicate = gen_replicate(&attrs.replicate, nested);
+            quote! { rust_spp::parallel!(#new, #replicate) }
         }
         Replicate::SeqOrdered => {
-            quote! { rust_spp::sequential_ordered!(#struct_ident::new( #(#struct_new_args.clone()),* )) }
+            quote! { rust_spp::sequential_ordered!(#new) }
         }
         Replicate::SeqUnordered => {
-            quote! { rust_spp::sequential!(#struct_ident::new( #(#struct_new_args.clone()),* )) }
+            quote! { rust_spp::sequential!(#new) }
         }
     }
 }
 
 fn rust_spp_gen_pipeline(spar_stream: &SparStream, gen: TokenStream) -> TokenStream {
-    let collector = if matches!(spar_stream.attrs.replicate, Replicate::SeqOrdered) {
-        quote! { collect_ordered!() }
-    } else {
-        quote! { collect!() }
+    let ordered = matches!(spar_stream.attrs.replicate, Replicate::SeqOrdered);
+    let collector = match (spar_stream.in_background(), ordered) {
+        (true, true) => quote! { rust_spp::sequential_ordered!(SparSender(spar_sender.clone())) },
+        (true, false) => quote! { rust_spp::sequential!(SparSender(spar_sender.clone())) },
+        (false, true) => quote! { collect_ordered!() },
+        (false, false) => quote! { collect!() },
     };
+    let mut stage_locals = instrumentation::stage_locals(&spar_stream.stages);
+    if spar_stream.is_cancellable() {
+        for stage in &spar_stream.stages {
+            let cancel = cancel_ident(stage);
+            stage_locals.extend(quote! {
+                let #cancel = spar_cancel.clone();
+            });
+        }
+    }
+    for stage in &spar_stream.stages {
+        if stage.attrs.loop_back.is_some() {
+            let looper = looper_ident(stage);
+            stage_locals.extend(quote! {
+                let #looper = spar_feedback.looper();
+            });
+        }
+        if let Some(window) = &stage.attrs.window {
+            let local = window_ident(stage);
+            let size = &window.size;
+            let slide = match &window.slide {
+                Some(slide) => slide.to_token_stream(),
+                None => quote! { spar_size },
+            };
+            stage_locals.extend(quote_spanned! {located_at(stage.span)=>
+                let #local: (usize, usize) = {
+                    let spar_size: usize = #size;
+                    (spar_size, #slide)
+                };
+            });
+        }
+    }
     if let Some(stage) = spar_stream.stages.last() {
         if stage.attrs.replicate.is_sequential() && stage.attrs.output.is_empty() {
-            return quote! { let mut spar_pipeline = rust_spp::pipeline![#gen]; };
+            return quote! {
+                let mut spar_pipeline = {
+                    #stage_locals
+                    rust_spp::pipeline![#gen]
+                };
+            };
         }
     }
 
+    // the pipeline works on its own copies of the variables to restore, which are
+    // empty when only the changes of each item are sent back
     let mut external_vars = TokenStream::new();
     let (ident, vtype) = get_idents_and_types_from_spar_vars(&spar_stream.attrs.output);
     for (ident, vtype) in ident.iter().zip(vtype) {
-        external_vars.extend(quote! {
-            let #ident: #vtype = #ident.clone();
-
+        let probe = restore_probe(&vtype);
+        external_vars.extend(quote_spanned! {located_at(ident.span())=>
+            let #ident: #vtype = #probe.spar_copy(&#ident);
         });
     }
+    if !external_vars.is_empty() {
+        external_vars = quote! {
+            #[allow(unused_imports)]
+            use spar_rust_runtime::__private::{NotRestored as _, Restored as _};
+            #external_vars
+        };
+    }
     quote! {
         let mut spar_pipeline = {
+            #stage_locals
             #external_vars
             rust_spp::pipeline![
                 #gen,
@@ -300,7 +1149,51 @@ fn rust_spp_gen(spar_stream: &mut SparStream) -> TokenStream {
     let mut code = quote! {
         use rust_spp::*;
     };
+    if let (true, Some(output_type)) = (spar_stream.in_background(), &spar_stream.attrs.output_type)
+    {
+        code.extend(quote! {
+            struct SparSender(std::sync::mpsc::Sender<#output_type>);
+        });
+        // the last stage of a branched stream sends a batch of items at a time
+        code.extend(if spar_stream.is_branched() {
+            quote! {
+                impl rust_spp::blocks::in_block::In<Vec<#output_type>> for SparSender {
+                    fn process(&mut self, items: Vec<#output_type>, _order: u64) {
+                        for item in items {
+                            // the items are discarded once the iterator or the handle is dropped
+                            let _ = self.0.send(item);
+                        }
+                    }
+                }
+            }
+        } else {
+            quote! {
+                impl rust_spp::blocks::in_block::In<#output_type> for SparSender {
+                    fn process(&mut self, item: #output_type, _order: u64) {
+                        // the items are discarded once the iterator or the handle is dropped
+                        let _ = self.0.send(item);
+                    }
+                }
+            }
+        });
+    }
 
+    if spar_stream.is_branched() {
+        code.extend(gen_item_enum(
+            &spar_stream.stages,
+            spar_stream.has_windows(),
+        ));
+    }
+    if spar_stream.loops() {
+        code.extend(quote! {
+            let spar_feedback = spar_rust_runtime::__private::Feedback::<Vec<SparItem>>::new();
+        });
+    }
+    if spar_stream.has_windows() {
+        code.extend(quote! {
+            let spar_posted = std::cell::Cell::new(0u64);
+        });
+    }
     for (stage, spar_struct) in spar_stream.stages.iter().zip(spar_structs) {
         code.extend(spar_struct);
 
@@ -308,43 +1201,247 @@ fn rust_spp_gen(spar_stream: &mut SparStream) -> TokenStream {
             gen.extend(quote!(,));
         }
 
-        gen.extend(rust_spp_pipeline_arg(stage));
+        gen.extend(rust_spp_pipeline_arg(
+            stage,
+            spar_stream.is_cancellable(),
+            spar_stream.attrs.nested,
+        ));
     }
 
     code.extend(rust_spp_gen_pipeline(spar_stream, gen));
-    code.extend(quote! {#dispatcher});
-    if !spar_stream.attrs.output.is_empty() {
+    // the stages with a WINDOW are told how many items the dispatcher posted, once
+    // it is done, so that they run on their last, partial window
+    let end = if spar_stream.has_windows() {
+        quote! {
+            spar_pipeline.post(vec![SparItem::SparEnd(spar_posted.get())]).unwrap();
+        }
+    } else {
+        TokenStream::new()
+    };
+    // the code before the stages may leave early, with `return` or `?`. The pipeline
+    // is then ended before leaving, once the items it received went through it
+    code.extend(quote! {
+        struct SparEndOnDrop<P, F: FnOnce(P)>(Option<(P, F)>);
+
+        impl<P, F: FnOnce(P)> SparEndOnDrop<P, F> {
+            fn new(pipeline: P, end: F) -> Self {
+                Self(Some((pipeline, end)))
+            }
+
+            fn into_inner(mut self) -> P {
+                self.0.take().unwrap().0
+            }
+        }
+
+        impl<P, F: FnOnce(P)> std::ops::Deref for SparEndOnDrop<P, F> {
+            type Target = P;
+
+            fn deref(&self) -> &P {
+                &self.0.as_ref().unwrap().0
+            }
+        }
+
+        impl<P, F: FnOnce(P)> std::ops::DerefMut for SparEndOnDrop<P, F> {
+            fn deref_mut(&mut self) -> &mut P {
+                &mut self.0.as_mut().unwrap().0
+            }
+        }
+
+        impl<P, F: FnOnce(P)> Drop for SparEndOnDrop<P, F> {
+            fn drop(&mut self) {
+                if let Some((pipeline, end)) = self.0.take() {
+                    end(pipeline);
+                }
+            }
+        }
+
+        #[allow(unused_mut)]
+        let mut spar_pipeline = SparEndOnDrop::new(spar_pipeline, |spar_pipeline| {
+            #end
+            spar_pipeline.end_and_wait();
+        });
+    });
+    // the dispatcher posts through references, which closures in the code before
+    // the stages capture, even if they are `move` closures. A detached or
+    // cancellable stream stops dispatching items once it is cancelled. Once it is
+    // done, it posts the items that loop back until none is left in the loop
+    let stops = spar_stream.attrs.detached || spar_stream.is_cancellable();
+    let mut borrows = TokenStream::new();
+    if spar_stream.loops() {
+        borrows.extend(quote! { let spar_feedback = &spar_feedback; });
+    }
+    if spar_stream.has_windows() {
+        borrows.extend(quote! { let spar_posted = &spar_posted; });
+    }
+    borrows.extend(instrumentation::dispatcher_borrows());
+    let feedback = match (spar_stream.loops(), stops) {
+        (false, _) => TokenStream::new(),
+        (true, true) => quote! {
+            for spar_items in spar_feedback.until_done(|| spar_cancel.is_cancelled()) {
+                spar_pipeline.post(spar_items).unwrap();
+            }
+        },
+        (true, false) => quote! {
+            for spar_items in spar_feedback.until_done(|| false) {
+                spar_pipeline.post(spar_items).unwrap();
+            }
+        },
+    };
+    if stops {
         code.extend(quote! {
-            let collection = spar_pipeline.collect();
-        })
+            'spar_dispatch: {
+                let spar_pipeline = &*spar_pipeline;
+                let spar_cancel = &spar_cancel;
+                #borrows
+                #dispatcher
+                #feedback
+                #end
+            }
+        });
+    } else {
+        code.extend(quote! {
+            {
+                let spar_pipeline = &*spar_pipeline;
+                #borrows
+                #dispatcher
+                #feedback
+                #end
+            }
+        });
+    }
+    code.extend(instrumentation::dispatcher_finished());
+    code.extend(quote! {
+        let spar_pipeline = spar_pipeline.into_inner();
+    });
+    if spar_stream.collects() && !spar_stream.in_background() {
+        code.extend(if spar_stream.is_branched() {
+            quote! {
+                let collection: Vec<_> = spar_pipeline.collect().into_iter().flatten().collect();
+            }
+        } else {
+            quote! {
+                let collection = spar_pipeline.collect();
+            }
+        });
+        let collection = Ident::new("collection", Span::call_site());
+        code.extend(instrumentation::stream_end(Some(&collection)));
     } else {
         code.extend(quote! {
             spar_pipeline.end_and_wait();
-        })
+        });
+        code.extend(instrumentation::stream_end(None));
     }
 
     code
 }
 
-fn restore_external_vars(spar_stream: &SparStream) -> TokenStream {
-    let (ident, _) = get_idents_and_types_from_spar_vars(&spar_stream.attrs.output);
-    let mut code = TokenStream::new();
+/// Every item of the collection holds the outputs of the last stage: the item that
+/// the stream returns, if any, then the values of the variables to restore
+fn collect_outputs(spar_stream: &SparStream) -> TokenStream {
+    let outputs = match spar_stream.stages.last() {
+        Some(stage) if spar_stream.collects() => &stage.attrs.output,
+        _ => return TokenStream::new(),
+    };
+    let field = |index: usize, span: Span| {
+        if outputs.len() == 1 {
+            quote_spanned! {span=> spar_item }
+        } else {
+            let index = syn::Index {
+                index: index as u32,
+                span,
+            };
+            quote_spanned! {span=> spar_item.#index }
+        }
+    };
 
-    if ident.len() > 1 {
-        for (i, ident) in ident.iter().enumerate() {
-            code.extend(quote! {
-                for tuple in collection {
-                    #ident.extend(tuple.#i);
-                }
+    let mut restores = TokenStream::new();
+    let mut item = Vec::new();
+    let mut item_span = Span::call_site();
+    for (index, var) in outputs.iter().enumerate() {
+        let span = located_at(var.identifier.span());
+        let value = field(index, span);
+        if spar_stream.attrs.output.contains(var) {
+            let ident = &var.identifier;
+            let probe = restore_probe(&var.var_type);
+            restores.extend(quote! {
+                #probe.spar_restore(&mut #ident, #value);
             });
+        } else {
+            if item.is_empty() {
+                item_span = span;
+            }
+            item.push(value);
         }
-    } else if ident.len() == 1 {
-        let ident = &ident[0];
+    }
+
+    let mut code = TokenStream::new();
+    if !restores.is_empty() {
         code.extend(quote! {
-            for elem in collection {
-                #ident.extend(elem);
+            #[allow(unused_imports)]
+            use spar_rust_runtime::__private::{NotRestored as _, Restored as _};
+        });
+    }
+    match &spar_stream.attrs.output_type {
+        Some(output_type) => {
+            let item = make_tuple(&item);
+            // a mismatched item is reported at the OUTPUT of the last stage
+            let push = quote_spanned! {item_span=> spar_output.push(#item); };
+            quote! {
+                let spar_output: Vec<#output_type> = {
+                    #code
+                    let mut spar_output: Vec<#output_type> = Vec::with_capacity(collection.len());
+                    for spar_item in collection {
+                        #restores
+                        #push
+                    }
+                    spar_output
+                };
+                spar_output
             }
-        })
+        }
+        None => quote! {
+            {
+                #code
+                for spar_item in collection {
+                    #restores
+                }
+            }
+        },
+    }
+}
+
+/// Variables handed from one stage to the next may be declared with types that
+/// are written differently, but they must be the same type. Each pair of
+/// declarations is checked twice, so that both of them are underlined when they
+/// differ
+fn gen_handoff_checks(spar_stream: &SparStream) -> TokenStream {
+    let mut code = TokenStream::new();
+    for handoff in &spar_stream.handoffs {
+        let message = format!(
+            "stage {} declares OUTPUT({}) but stage {} expects INPUT({})",
+            handoff.from, handoff.output, handoff.to, handoff.input
+        );
+        let output = &handoff.output.var_type;
+        let input = &handoff.input.var_type;
+        let output_check = quote_spanned! {located_at(handoff.output.identifier.span())=>
+            spar_same_type::<#output, #input>();
+        };
+        let input_check = quote_spanned! {located_at(handoff.input.identifier.span())=>
+            spar_same_type::<#input, #output>();
+        };
+        code.extend(quote! {
+            {
+                #[diagnostic::on_unimplemented(
+                    message = #message,
+                    label = "`{Self}` is not the same type as `{T}`"
+                )]
+                trait SparSameType<T> {}
+                impl<T> SparSameType<T> for T {}
+                fn spar_same_type<A: SparSameType<B>, B>() {}
+                #output_check
+                #input_check
+            }
+        });
     }
 
     code
@@ -352,8 +1449,123 @@ fn restore_external_vars(spar_stream: &SparStream) -> TokenStream {
 
 pub fn codegen(mut spar_stream: SparStream) -> TokenStream {
     let mut code = gen_spar_num_workers();
+    // the threads that the replicas of its stages, and the streams nested in them, may use
+    let nests = spar_stream
+        .stages
+        .iter()
+        .any(|stage| stage.id != 0 && !stage.is_source && has_nested_streams(stage));
+    if spar_stream.attrs.nested || nests {
+        code.extend(quote! {
+            #[allow(unused_variables)]
+            let spar_budget = spar_rust_runtime::__private::budget(spar_num_workers);
+        });
+    }
+    code.extend(gen_handoff_checks(&spar_stream));
+
+    // a lazy stream runs on its own thread, which sends the items to the iterator.
+    // A pipeline also receives the items posted to its handle
+    if let (true, Some(output_type)) = (spar_stream.in_background(), &spar_stream.attrs.output_type)
+    {
+        let output_type = output_type.clone();
+        let (posts, handle) = match spar_stream.stages.first() {
+            Some(source) if spar_stream.is_pipeline => {
+                let (_, item_types) = get_idents_and_types_from_spar_vars(&source.attrs.output);
+                let item_type = make_tuple(&item_types);
+                (
+                    quote! {
+                        let (spar_posts_sender, spar_posts) = std::sync::mpsc::channel::<#item_type>();
+                    },
+                    quote! {
+                        spar_rust_runtime::Pipeline::<#item_type, #output_type>::new(spar_posts_sender, spar_receiver, spar_stream)
+                    },
+                )
+            }
+            _ => (
+                TokenStream::new(),
+                quote! { spar_rust_runtime::Items::new(spar_receiver, spar_stream) },
+            ),
+        };
+        let stream_begin = instrumentation::stream_begin();
+        let stream = rust_spp_gen(&mut spar_stream);
+        code.extend(quote! {
+            #posts
+            let (spar_sender, spar_receiver) = std::sync::mpsc::channel::<#output_type>();
+            let spar_stream = std::thread::spawn(move || {
+                #stream_begin
+                #stream
+            });
+            #handle
+        });
+        return code;
+    }
+
+    // a detached stream runs on its own thread, which returns the items and the
+    // restored variables to the handle once the stream ends
+    let cancellation = gen_cancellation(&spar_stream.attrs);
+    if spar_stream.attrs.detached {
+        let restored: Vec<Ident> = spar_stream
+            .attrs
+            .output
+            .iter()
+            .map(|var| var.identifier.clone())
+            .collect();
+        let stream_begin = instrumentation::stream_begin();
+        let stream = rust_spp_gen(&mut spar_stream);
+        let outputs = collect_outputs(&spar_stream);
+        let mut returns = restored.clone();
+        let outputs = if spar_stream.attrs.output_type.is_some() {
+            returns.insert(0, Ident::new("spar_output", Span::call_site()));
+            quote! { let spar_output = { #outputs }; }
+        } else {
+            outputs
+        };
+        let mut returns = make_tuple(&returns);
+        if spar_stream.is_cancellable() {
+            returns = quote! { spar_cancel.outcome(#returns) };
+        }
+        code.extend(quote! {
+            #cancellation
+            let spar_stream = {
+                let spar_cancel = spar_cancel.clone();
+                std::thread::spawn(move || {
+                    // the stream owns the variables it restores
+                    #(
+                        #[allow(unused_mut)]
+                        let mut #restored = #restored;
+                    )*
+                    #stream_begin
+                    #stream
+                    #outputs
+                    #returns
+                })
+            };
+            spar_rust_runtime::Detached::new(spar_stream, spar_cancel)
+        });
+        return code;
+    }
+
+    code.extend(instrumentation::stream_begin());
+    if spar_stream.is_cancellable() {
+        code.extend(cancellation);
+        code.extend(rust_spp_gen(&mut spar_stream));
+        let outputs = collect_outputs(&spar_stream);
+        // the outcome tells whether the stream stopped early
+        code.extend(if spar_stream.attrs.output_type.is_some() {
+            quote! {
+                let spar_output = { #outputs };
+                spar_cancel.outcome(spar_output)
+            }
+        } else {
+            quote! {
+                #outputs
+                spar_cancel.outcome(())
+            }
+        });
+        return code;
+    }
+
     code.extend(rust_spp_gen(&mut spar_stream));
-    code.extend(restore_external_vars(&spar_stream));
+    code.extend(collect_outputs(&spar_stream));
 
     code
 }
@@ -0,0 +1,353 @@
+//! This module generates the optional instrumentation of the pipelines.
+//!
+//! Two cargo features use it:
+//! - `tracing`: stages open a `tracing` span for every item they process, and the
+//!   dispatcher and collector emit events;
+//! - `chrome-trace`: every replica records when it started and finished each item.
+//!   If the `SPAR_TRACE_FILE` environment variable is set, these records are written
+//!   to it, in the Chrome Trace Event format, once the stream ends.
+
+use crate::spar_stream::SparStage;
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+
+fn tracing_enabled() -> bool {
+    cfg!(feature = "tracing")
+}
+
+fn chrome_trace_enabled() -> bool {
+    cfg!(feature = "chrome-trace")
+}
+
+/// Items are tracked when any instrumentation is enabled. In that case, every
+/// tuple sent between stages is prefixed by the sequence number the dispatcher
+/// gave to the item, so that each stage can tell which item it is processing.
+pub fn track_items() -> bool {
+    tracing_enabled() || chrome_trace_enabled()
+}
+
+pub fn item_envelope(tuple: TokenStream) -> TokenStream {
+    if track_items() {
+        quote! { (spar_item, #tuple) }
+    } else {
+        tuple
+    }
+}
+
+pub fn item_envelope_type(types: TokenStream) -> TokenStream {
+    if track_items() {
+        quote! { (u64, #types) }
+    } else {
+        types
+    }
+}
+
+fn replicas_ident(stage: &SparStage) -> Ident {
+    Ident::new(&format!("spar_replicas_{}", stage.id), Span::call_site())
+}
+
+fn span_ident(stage: &SparStage) -> Ident {
+    Ident::new(&format!("spar_span_{}", stage.id), Span::call_site())
+}
+
+fn trace_ident(stage: &SparStage) -> Ident {
+    Ident::new(&format!("spar_trace_{}", stage.id), Span::call_site())
+}
+
+/// Code that runs before anything else in the stream
+pub fn stream_begin() -> TokenStream {
+    let mut code = TokenStream::new();
+    if track_items() {
+        // a cell, so that the `move` closures that dispatch items count them too
+        code.extend(quote! {
+            let spar_items = std::cell::Cell::new(0u64);
+        });
+    }
+
+    if tracing_enabled() {
+        code.extend(quote! {
+            let spar_stream_span = tracing::info_span!("spar_stream");
+            let spar_stream_guard = spar_stream_span.enter();
+            tracing::debug!("spar dispatcher started");
+        });
+    }
+
+    if chrome_trace_enabled() {
+        code.extend(gen_trace_sink());
+    }
+
+    code
+}
+
+/// Borrows the item counter, for the closures in the code before the stages
+pub fn dispatcher_borrows() -> TokenStream {
+    if track_items() {
+        quote! { let spar_items = &spar_items; }
+    } else {
+        TokenStream::new()
+    }
+}
+
+/// Code that runs before the dispatcher posts an item to the pipeline
+pub fn item_numbered() -> TokenStream {
+    if track_items() {
+        quote! { let spar_item = spar_items.get(); }
+    } else {
+        TokenStream::new()
+    }
+}
+
+/// Code that runs after the dispatcher posted an item to the pipeline
+pub fn item_dispatched() -> TokenStream {
+    let mut code = TokenStream::new();
+    if tracing_enabled() {
+        code.extend(quote! {
+            tracing::trace!(item = spar_item, "spar dispatcher posted item");
+        });
+    }
+
+    if track_items() {
+        code.extend(quote! {
+            spar_items.set(spar_item + 1);
+        });
+    }
+
+    code
+}
+
+/// Code that runs once the dispatcher is done
+pub fn dispatcher_finished() -> TokenStream {
+    if tracing_enabled() {
+        quote! {
+            tracing::debug!(items = spar_items.get(), "spar dispatcher finished");
+        }
+    } else {
+        TokenStream::new()
+    }
+}
+
+/// Code that runs once the pipeline ended. `collection` is the result of the
+/// collector, if there is one
+pub fn stream_end(collection: Option<&Ident>) -> TokenStream {
+    let mut code = TokenStream::new();
+    if tracing_enabled() {
+        match collection {
+            Some(collection) => code.extend(quote! {
+                tracing::debug!(items = #collection.len(), "spar collector finished");
+            }),
+            None => code.extend(quote! {
+                tracing::debug!("spar pipeline finished");
+            }),
+        }
+        code.extend(quote! {
+            drop(spar_stream_guard);
+        });
+    }
+
+    if chrome_trace_enabled() {
+        code.extend(quote! {
+            if let Some(spar_trace) = spar_trace {
+                spar_trace.write();
+            }
+        });
+    }
+
+    code
+}
+
+/// Each stage gets its own replica counter, and handles to the stream's span and
+/// trace, so that every item can be attributed to the right replica. These
+/// locals are moved into the pipeline
+pub fn stage_locals(stages: &[SparStage]) -> TokenStream {
+    let mut code = TokenStream::new();
+    for stage in stages {
+        if track_items() {
+            let replicas = replicas_ident(stage);
+            code.extend(quote! {
+                let #replicas = std::sync::atomic::AtomicUsize::new(0);
+            });
+        }
+
+        if tracing_enabled() {
+            let span = span_ident(stage);
+            code.extend(quote! {
+                let #span = spar_stream_span.clone();
+            });
+        }
+
+        if chrome_trace_enabled() {
+            let trace = trace_ident(stage);
+            code.extend(quote! {
+                let #trace = spar_trace.clone();
+            });
+        }
+    }
+
+    code
+}
+
+/// What the instrumentation adds to the struct of a stage
+#[derive(Default)]
+pub struct StageInstrumentation {
+    /// Extra fields of the struct, which are also extra arguments of its constructor
+    pub fields: TokenStream,
+    /// Identifiers of the extra fields
+    pub field_idents: TokenStream,
+    /// Extra arguments given to the constructor when building the pipeline
+    pub new_args: Vec<TokenStream>,
+    /// Code that runs before the stage processes an item
+    pub begin: TokenStream,
+    /// Code that runs after the stage processed an item
+    pub end: TokenStream,
+    /// Extra items, such as trait implementations for the struct
+    pub items: TokenStream,
+}
+
+impl StageInstrumentation {
+    pub fn new(stage: &SparStage, struct_ident: &Ident) -> Self {
+        let mut instrumentation = Self::default();
+        if !track_items() {
+            return instrumentation;
+        }
+
+        let id = stage.id;
+        let replicas = replicas_ident(stage);
+        instrumentation
+            .fields
+            .extend(quote! { spar_replica: usize, });
+        instrumentation
+            .field_idents
+            .extend(quote! { spar_replica, });
+        instrumentation
+            .new_args
+            .push(quote! { #replicas.fetch_add(1, std::sync::atomic::Ordering::Relaxed) });
+
+        if tracing_enabled() {
+            let span = span_ident(stage);
+            let name = struct_ident.to_string();
+            instrumentation
+                .fields
+                .extend(quote! { spar_span: tracing::Span, });
+            instrumentation.field_idents.extend(quote! { spar_span, });
+            instrumentation.new_args.push(quote! { #span.clone() });
+            instrumentation.begin.extend(quote! {
+                let spar_stage_span = tracing::trace_span!(
+                    parent: &self.spar_span,
+                    "spar_stage",
+                    stage = #id,
+                    name = #name,
+                    replica = self.spar_replica,
+                    item = spar_item,
+                );
+                let _spar_stage_guard = spar_stage_span.enter();
+            });
+        }
+
+        if chrome_trace_enabled() {
+            let trace = trace_ident(stage);
+            instrumentation.fields.extend(quote! {
+                spar_trace: Option<std::sync::Arc<SparTraceSink>>,
+                spar_trace_events: Vec<SparTraceEvent>,
+            });
+            instrumentation
+                .field_idents
+                .extend(quote! { spar_trace, spar_trace_events, });
+            instrumentation.new_args.push(quote! { #trace.clone() });
+            instrumentation.new_args.push(quote! { Vec::new() });
+            instrumentation.begin.extend(quote! {
+                let spar_trace_start = std::time::Instant::now();
+            });
+            instrumentation.end.extend(quote! {
+                if let Some(sink) = &self.spar_trace {
+                    self.spar_trace_events.push(SparTraceEvent {
+                        stage: #id,
+                        replica: self.spar_replica,
+                        item: spar_item,
+                        start: spar_trace_start.duration_since(sink.epoch),
+                        end: sink.epoch.elapsed(),
+                    });
+                }
+            });
+            // Events are kept by each replica, and only handed to the sink when the
+            // replica is dropped, so that replicas don't contend on the sink's lock
+            instrumentation.items.extend(quote! {
+                impl Drop for #struct_ident {
+                    fn drop(&mut self) {
+                        if let Some(sink) = &self.spar_trace {
+                            sink.events.lock().unwrap().append(&mut self.spar_trace_events);
+                        }
+                    }
+                }
+            });
+        }
+
+        instrumentation
+    }
+}
+
+fn gen_trace_sink() -> TokenStream {
+    quote! {
+        struct SparTraceEvent {
+            stage: u32,
+            replica: usize,
+            item: u64,
+            start: std::time::Duration,
+            end: std::time::Duration,
+        }
+
+        struct SparTraceSink {
+            path: std::path::PathBuf,
+            epoch: std::time::Instant,
+            events: std::sync::Mutex<Vec<SparTraceEvent>>,
+        }
+
+        impl SparTraceSink {
+            /// Writes the events in the Chrome Trace Event format. Every stage
+            /// becomes a process, and every replica of it a thread
+            fn write(&self) {
+                let events = self.events.lock().unwrap();
+                let mut tracks = std::collections::BTreeSet::new();
+                let mut json = Vec::new();
+                for event in events.iter() {
+                    let ts = event.start.as_nanos() as f64 / 1000.0;
+                    let dur = (event.end - event.start).as_nanos() as f64 / 1000.0;
+                    json.push(format!(
+                        "{{\"name\":\"item {}\",\"cat\":\"spar\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":{},\"tid\":{},\"args\":{{\"item\":{}}}}}",
+                        event.item, ts, dur, event.stage, event.replica, event.item
+                    ));
+                    tracks.insert((event.stage, event.replica));
+                }
+
+                let mut stages = std::collections::BTreeSet::new();
+                for (stage, replica) in tracks {
+                    if stages.insert(stage) {
+                        json.push(format!(
+                            "{{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":{},\"args\":{{\"name\":\"SparStage{}\"}}}}",
+                            stage, stage
+                        ));
+                    }
+                    json.push(format!(
+                        "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":\"SparStage{} replica {}\"}}}}",
+                        stage, replica, stage, replica
+                    ));
+                }
+
+                let json = format!("{{\"traceEvents\":[{}],\"displayTimeUnit\":\"ms\"}}", json.join(","));
+                if let Err(e) = std::fs::write(&self.path, json) {
+                    eprintln!("failed to write SPAR_TRACE_FILE {}: {}", self.path.display(), e);
+                }
+            }
+        }
+
+        // Set spar_trace according to the envvar SPAR_TRACE_FILE
+        // If it doesn't exist, nothing is recorded
+        let spar_trace: Option<std::sync::Arc<SparTraceSink>> =
+            std::env::var_os("SPAR_TRACE_FILE").map(|path| {
+                std::sync::Arc::new(SparTraceSink {
+                    path: path.into(),
+                    epoch: std::time::Instant::now(),
+                    events: std::sync::Mutex::new(Vec::new()),
+                })
+            });
+    }
+}
@@ -1,13 +1,63 @@
 mod codegen;
+mod instrumentation;
+mod lints;
 mod spar_stream;
+mod topology;
 
 use codegen::codegen;
 use spar_stream::SparStream;
 
-#[proc_macro]
-pub fn to_stream(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    match SparStream::try_from(&item) {
-        Ok(spar_stream) => codegen(spar_stream).into(),
+/// If SPAR_TOPOLOGY_DIR is set, the topology of every stream is written to it,
+/// in files named after where the stream is in the source code
+fn export_topology(spar_stream: &SparStream) -> syn::Result<()> {
+    let dir = match std::env::var_os("SPAR_TOPOLOGY_DIR") {
+        Some(dir) => std::path::PathBuf::from(dir),
+        None => return Ok(()),
+    };
+
+    let span = proc_macro::Span::call_site();
+    let name: String = format!("{}_{}", span.file(), span.line())
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    topology::write(spar_stream, &dir, &name).map_err(|e| {
+        syn::Error::new(
+            proc_macro2::Span::call_site(),
+            format!("failed to write the topology to {}: {e}", dir.display()),
+        )
+    })
+}
+
+fn expand(spar_stream: syn::Result<SparStream>) -> proc_macro::TokenStream {
+    match spar_stream.and_then(|mut spar_stream| {
+        lints::prune_restored(&mut spar_stream);
+        export_topology(&spar_stream)?;
+        let lints = lints::check(&spar_stream)?;
+        Ok((spar_stream, lints))
+    }) {
+        Ok((spar_stream, lints)) => {
+            let is_expression = spar_stream.is_expression();
+            let mut code = lints::gen_warnings(&lints);
+            code.extend(codegen(spar_stream));
+            // a stream that returns its items, or a handle, is an expression
+            if is_expression {
+                code = quote::quote! { { #code } };
+            }
+            code.into()
+        }
         Err(e) => e.into_compile_error().into(),
     }
 }
+
+#[proc_macro]
+pub fn to_stream(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    expand(SparStream::try_from(&item))
+}
+
+/// Like `to_stream!`, but only with stages: returns a `spar_rust_runtime::Pipeline`
+/// handle, to which the items of the first stage are posted
+#[proc_macro]
+pub fn spar_pipeline(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    expand(SparStream::pipeline(item.into()))
+}
@@ -0,0 +1,934 @@
+//! This module analyses the code of the stages, looking for mistakes in their
+//! annotations.
+//!
+//! Stages are not parsed as Rust code: like the rest of the parser, the analysis
+//! only looks at identifiers and the tokens around them, so it can miss some
+//! uses of a variable, but it never reports a variable that is not mentioned.
+//!
+//! These are errors, which would otherwise be reported inside the generated code:
+//! - a stage that uses a variable of the enclosing code without declaring it as
+//!   an INPUT;
+//! - an OUTPUT that no later stage takes as an INPUT.
+//!
+//! These are warnings:
+//! - an INPUT that the stage never uses;
+//! - a stream input that a stage mutates, but which is not restored once the
+//!   stream ends, so the changes are lost.
+
+use proc_macro2::{Delimiter, Ident, Spacing, Span, TokenStream, TokenTree};
+use quote::quote_spanned;
+use syn::Result;
+
+use crate::codegen::restore_probe;
+use crate::spar_stream::{SparStage, SparStream, SparVar, VarType};
+
+/// Methods of the standard collections that modify them
+const MUTATING_METHODS: &[&str] = &[
+    "append",
+    "clear",
+    "dedup",
+    "drain",
+    "entry",
+    "extend",
+    "extend_from_slice",
+    "get_mut",
+    "insert",
+    "iter_mut",
+    "pop",
+    "pop_back",
+    "pop_front",
+    "push",
+    "push_back",
+    "push_front",
+    "push_str",
+    "remove",
+    "resize",
+    "retain",
+    "reverse",
+    "sort",
+    "sort_by",
+    "sort_by_key",
+    "sort_unstable",
+    "swap",
+    "truncate",
+    "values_mut",
+];
+
+const ASSIGNMENT_OPERATORS: &[&str] = &[
+    "=", "+=", "-=", "*=", "/=", "%=", "^=", "&=", "|=", "<<=", ">>=",
+];
+
+pub struct Lint {
+    pub span: Span,
+    pub message: String,
+    /// The lint only applies if this type doesn't implement `spar_rust_runtime::Restore`,
+    /// which only the compiler can tell
+    pub unless_restored: Option<VarType>,
+}
+
+/// The tokens of some code, with its groups flattened. Each group becomes its
+/// opening delimiter, followed by its tokens, then its closing delimiter
+fn flatten(code: TokenStream) -> Vec<TokenTree> {
+    let mut tokens = Vec::new();
+    for token in code {
+        match token {
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ('(', ')'),
+                    Delimiter::Brace => ('{', '}'),
+                    Delimiter::Bracket => ('[', ']'),
+                    Delimiter::None => {
+                        tokens.extend(flatten(group.stream()));
+                        continue;
+                    }
+                };
+                tokens.push(delimiter(open, group.span_open()));
+                tokens.extend(flatten(group.stream()));
+                tokens.push(delimiter(close, group.span_close()));
+            }
+            token => tokens.push(token),
+        }
+    }
+    tokens
+}
+
+/// Delimiters are represented by identifiers that cannot appear in Rust code,
+/// so that they are never mistaken for punctuation
+fn delimiter(c: char, span: Span) -> TokenTree {
+    let name = match c {
+        '(' => "__spar_open_paren",
+        ')' => "__spar_close_paren",
+        '{' => "__spar_open_brace",
+        '}' => "__spar_close_brace",
+        '[' => "__spar_open_bracket",
+        _ => "__spar_close_bracket",
+    };
+    TokenTree::Ident(Ident::new(name, span))
+}
+
+fn is_punct(token: Option<&TokenTree>, c: char) -> bool {
+    matches!(token, Some(TokenTree::Punct(punct)) if punct.as_char() == c)
+}
+
+fn is_ident(token: Option<&TokenTree>, name: &str) -> bool {
+    matches!(token, Some(TokenTree::Ident(ident)) if ident == name)
+}
+
+/// The operator that starts at `tokens[i]`, and the index after it
+fn operator(tokens: &[TokenTree], mut i: usize) -> (String, usize) {
+    let mut operator = String::new();
+    while let Some(TokenTree::Punct(punct)) = tokens.get(i) {
+        operator.push(punct.as_char());
+        i += 1;
+        if punct.spacing() == Spacing::Alone {
+            break;
+        }
+    }
+    (operator, i)
+}
+
+/// Identifiers that the code binds with `let`, `for`, or as closure parameters
+fn bindings(tokens: &[TokenTree]) -> Vec<Ident> {
+    let mut bindings = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let end = if is_ident(tokens.get(i), "let") {
+            "="
+        } else if is_ident(tokens.get(i), "for") {
+            "in"
+        } else if is_punct(tokens.get(i), '|') {
+            // `||` is either a closure without parameters, or a logical or
+            if is_punct(tokens.get(i + 1), '|') {
+                i += 2;
+                continue;
+            }
+            "|"
+        } else {
+            i += 1;
+            continue;
+        };
+
+        // every identifier in the pattern, up to its end, except for paths, constructors,
+        // field names and types, as in `Point { x: Some(a), .. }` or `(a, b): (u32, u32)`
+        i += 1;
+        let mut depth = 0usize;
+        let mut in_type = false;
+        while let Some(token) = tokens.get(i) {
+            match token {
+                TokenTree::Ident(ident) if ident == end => break,
+                TokenTree::Punct(punct) if punct.as_char().to_string() == end => break,
+                TokenTree::Punct(punct) if punct.as_char() == ';' => break,
+                TokenTree::Punct(punct) if punct.as_char() == ',' && depth == 0 => in_type = false,
+                TokenTree::Punct(punct)
+                    if punct.as_char() == ':'
+                        && punct.spacing() == Spacing::Alone
+                        && !is_punct(tokens.get(i - 1), ':')
+                        && depth == 0 =>
+                {
+                    in_type = true
+                }
+                TokenTree::Punct(punct) if punct.as_char() == '<' && in_type => depth += 1,
+                TokenTree::Punct(punct) if punct.as_char() == '>' && in_type => {
+                    depth = depth.saturating_sub(1)
+                }
+                TokenTree::Ident(ident) if ident.to_string().starts_with("__spar_open") => {
+                    depth += 1
+                }
+                TokenTree::Ident(ident) if ident.to_string().starts_with("__spar_close") => {
+                    depth = depth.saturating_sub(1)
+                }
+                TokenTree::Ident(ident)
+                    if !in_type
+                        && ident != "mut"
+                        && ident != "ref"
+                        && !ident.to_string().starts_with(char::is_uppercase)
+                        && !is_ident(tokens.get(i + 1), "__spar_open_paren")
+                        && !is_ident(tokens.get(i + 1), "__spar_open_brace")
+                        && !(is_punct(tokens.get(i + 1), ':')
+                            && (depth > 0 || is_punct(tokens.get(i + 2), ':')))
+                        && !(i > 1
+                            && is_punct(tokens.get(i - 1), ':')
+                            && is_punct(tokens.get(i - 2), ':')) =>
+                {
+                    bindings.push(ident.clone())
+                }
+                _ => (),
+            }
+            i += 1;
+        }
+        // the '|' that ends the parameters of a closure doesn't start other ones
+        if end == "|" {
+            while i < tokens.len() && !is_punct(tokens.get(i), '|') {
+                i += 1;
+            }
+            i += 1;
+        }
+    }
+    bindings
+}
+
+/// Where the code mentions a variable: an identifier that is not a path segment,
+/// a field, a method or a macro
+fn uses(tokens: &[TokenTree], name: &Ident) -> Vec<usize> {
+    let mut uses = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            TokenTree::Ident(ident) if ident == name => {
+                // `a.name` is a field, but `a..name` is a range
+                let after_path = i > 0
+                    && is_punct(tokens.get(i - 1), '.')
+                    && !(i > 1 && is_punct(tokens.get(i - 2), '.'))
+                    || i > 1
+                        && is_punct(tokens.get(i - 1), ':')
+                        && is_punct(tokens.get(i - 2), ':');
+                let before_path = is_punct(tokens.get(i + 1), '!')
+                    && !is_punct(tokens.get(i + 2), '=')
+                    || is_punct(tokens.get(i + 1), ':');
+                if !after_path && !before_path {
+                    uses.push(i);
+                }
+            }
+            // variables captured by format strings, e.g. println!("{name}")
+            TokenTree::Literal(literal) => {
+                let literal = literal.to_string();
+                if literal.starts_with('"')
+                    && (literal.contains(&format!("{{{name}}}"))
+                        || literal.contains(&format!("{{{name}:")))
+                {
+                    uses.push(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    uses
+}
+
+/// Whether the variable used at `tokens[i]` is modified there: assigned to,
+/// borrowed mutably, or changed by one of the `MUTATING_METHODS`
+fn is_mutation(tokens: &[TokenTree], i: usize) -> bool {
+    if i >= 2 && is_punct(tokens.get(i - 2), '&') && is_ident(tokens.get(i - 1), "mut") {
+        return true;
+    }
+    if is_named_argument(tokens, i) {
+        return false;
+    }
+
+    // skip fields, tuple fields and indices, e.g. `var.field.0[i] = ...`
+    let mut next = i + 1;
+    loop {
+        if is_punct(tokens.get(next), '.') {
+            match tokens.get(next + 1) {
+                Some(TokenTree::Ident(ident)) => {
+                    if is_ident(tokens.get(next + 2), "__spar_open_paren") {
+                        return MUTATING_METHODS.contains(&ident.to_string().as_str());
+                    }
+                    next += 2;
+                    continue;
+                }
+                Some(TokenTree::Literal(_)) => {
+                    next += 2;
+                    continue;
+                }
+                _ => (),
+            }
+        } else if is_ident(tokens.get(next), "__spar_open_bracket") {
+            let mut depth = 0;
+            while let Some(token) = tokens.get(next) {
+                if is_ident(Some(token), "__spar_open_bracket") {
+                    depth += 1;
+                } else if is_ident(Some(token), "__spar_close_bracket") {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                next += 1;
+            }
+            next += 1;
+            continue;
+        }
+        break;
+    }
+
+    let (operator, _) = operator(tokens, next);
+    ASSIGNMENT_OPERATORS.contains(&operator.as_str())
+}
+
+/// The parenthesis that `tokens[i]` is directly in, if it starts an element of it,
+/// as `var` does in `f(a, var)`
+fn enclosing_paren(tokens: &[TokenTree], i: usize) -> Option<usize> {
+    let before = i.checked_sub(1).and_then(|i| tokens.get(i));
+    if !is_punct(before, ',') && !is_ident(before, "__spar_open_paren") {
+        return None;
+    }
+    let mut depth = 0;
+    for open in (0..i).rev() {
+        let token = tokens.get(open);
+        if [
+            "__spar_close_paren",
+            "__spar_close_bracket",
+            "__spar_close_brace",
+        ]
+        .iter()
+        .any(|name| is_ident(token, name))
+        {
+            depth += 1;
+        } else if ["__spar_open_bracket", "__spar_open_brace"]
+            .iter()
+            .any(|name| is_ident(token, name))
+        {
+            if depth == 0 {
+                return None;
+            }
+            depth -= 1;
+        } else if is_ident(token, "__spar_open_paren") {
+            if depth == 0 {
+                return Some(open);
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+/// Whether `tokens[i]` is a whole argument of a function or method call, as in
+/// `add(var, n)`, but not of a macro, as in `println!("{}", var)`
+fn is_argument(tokens: &[TokenTree], i: usize) -> bool {
+    if !is_punct(tokens.get(i + 1), ',') && !is_ident(tokens.get(i + 1), "__spar_close_paren") {
+        return false;
+    }
+    // `f(..)`, `a.f(..)` or `f::<T>(..)`, but not `f!(..)` or a tuple
+    match enclosing_paren(tokens, i).and_then(|open| open.checked_sub(1)) {
+        Some(callee) => match &tokens[callee] {
+            TokenTree::Ident(ident) => {
+                !["if", "in", "match", "return", "while"].contains(&ident.to_string().as_str())
+                    && !ident.to_string().starts_with("__spar")
+            }
+            TokenTree::Punct(punct) => punct.as_char() == '>',
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+/// Whether `tokens[i]` names an argument of a macro, as `width` does in
+/// `format!("{x:width$}", width = 4)`
+fn is_named_argument(tokens: &[TokenTree], i: usize) -> bool {
+    operator(tokens, i + 1).0 == "="
+        && enclosing_paren(tokens, i)
+            .and_then(|open| open.checked_sub(1))
+            .is_some_and(|callee| is_punct(tokens.get(callee), '!'))
+}
+
+fn stage_name(stage: &SparStage) -> String {
+    format!("stage {}", stage.id)
+}
+
+/// A stage can only use the variables it declares as INPUT. Any other variable
+/// of the stream (a stream input, or a variable of the code around the stages)
+/// is an error, since the stage runs in a different thread
+fn check_captures(stage: &SparStage, tokens: &[TokenTree], outer: &[Ident]) -> Result<()> {
+    let local = bindings(tokens);
+    let declared: Vec<&Ident> = stage
+        .attrs
+        .input
+        .iter()
+        .chain(&stage.state)
+        .map(|var| &var.identifier)
+        .collect();
+
+    for name in outer {
+        if declared.contains(&name) || local.contains(name) {
+            continue;
+        }
+        if let Some(&i) = uses(tokens, name).first() {
+            return Err(syn::Error::new(
+                tokens[i].span(),
+                format!(
+                    "{} uses `{name}`, but it is not declared in its INPUT",
+                    stage_name(stage)
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn check_inputs(stage: &SparStage, tokens: &[TokenTree], lints: &mut Vec<Lint>) {
+    for var in stage.attrs.input.iter().chain(&stage.state) {
+        let forwarded = stage
+            .attrs
+            .output
+            .iter()
+            .any(|output| output.identifier == var.identifier);
+        if !forwarded && uses(tokens, &var.identifier).is_empty() {
+            lints.push(Lint {
+                span: var.identifier.span(),
+                message: format!(
+                    "{} declares INPUT({var}), but never uses `{}`",
+                    stage_name(stage),
+                    var.identifier
+                ),
+                unless_restored: None,
+            });
+        }
+    }
+}
+
+/// Every variable a stage sends must be received by the next one, or forwarded by
+/// it to a later stage, or received by the stage it loops back to
+fn check_outputs(stage: &SparStage, receivers: &[&SparStage]) -> Result<()> {
+    for var in &stage.attrs.output {
+        if !receivers
+            .iter()
+            .flat_map(|receiver| receiver.received())
+            .any(|input| input.identifier == var.identifier)
+        {
+            return Err(syn::Error::new(
+                var.identifier.span(),
+                format!(
+                    "{} declares OUTPUT({var}), but no later stage takes `{}` as an INPUT",
+                    stage_name(stage),
+                    var.identifier
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Stages work on their own copy of the stream inputs, so changing them has no
+/// effect outside the stage, unless they are restored
+fn check_mutations(
+    stage: &SparStage,
+    tokens: &[TokenTree],
+    restored: &[SparVar],
+    lints: &mut Vec<Lint>,
+) {
+    let local = bindings(tokens);
+    for var in &stage.state {
+        if local.contains(&var.identifier) {
+            continue;
+        }
+        if let Some(&i) = uses(tokens, &var.identifier)
+            .iter()
+            .find(|&&i| is_mutation(tokens, i))
+        {
+            if restored.contains(var) {
+                lints.push(Lint {
+                    span: tokens[i].span(),
+                    message: format!(
+                        "{} mutates `{}`, but the changes are lost unless its type implements `spar_rust_runtime::Restore`",
+                        stage_name(stage),
+                        var.identifier
+                    ),
+                    unless_restored: Some(var.var_type.clone()),
+                });
+            } else {
+                lints.push(Lint {
+                    span: tokens[i].span(),
+                    message: format!(
+                        "{} mutates `{}`, but the changes are lost: each replica of the stage works on its own copy, which is not restored once the stream ends",
+                        stage_name(stage),
+                        var.identifier
+                    ),
+                    unless_restored: None,
+                });
+            }
+        }
+    }
+}
+
+/// Whether the code of the stage modifies the variable, without binding it first
+fn mutates(stage: &SparStage, var: &SparVar) -> bool {
+    let tokens = flatten(stage.code.clone());
+    !bindings(&tokens).contains(&var.identifier)
+        && uses(&tokens, &var.identifier)
+            .into_iter()
+            .any(|i| is_mutation(&tokens, i))
+}
+
+/// Restored variables start from their default value on every item, so the ones
+/// that the last stage only reads must not be restored: it has to see their values.
+/// Passing a variable to a function counts as changing it, since the function may
+/// take it as `&mut`
+pub fn prune_restored(spar_stream: &mut SparStream) {
+    let stage = match spar_stream.stages.last_mut() {
+        Some(stage) => stage,
+        None => return,
+    };
+    let tokens = flatten(stage.code.clone());
+    let local = bindings(&tokens);
+    let read_only: Vec<SparVar> = spar_stream
+        .attrs
+        .output
+        .iter()
+        .filter(|var| {
+            local.contains(&var.identifier)
+                || !uses(&tokens, &var.identifier)
+                    .into_iter()
+                    .any(|i| is_mutation(&tokens, i) || is_argument(&tokens, i))
+        })
+        .cloned()
+        .collect();
+    stage.attrs.output.retain(|var| !read_only.contains(var));
+    spar_stream
+        .attrs
+        .output
+        .retain(|var| !read_only.contains(var));
+}
+
+/// Returns the warnings about the stream, or the first error
+pub fn check(spar_stream: &SparStream) -> Result<Vec<Lint>> {
+    // a lazy stream or a pipeline returns before it ends, so there is nothing to restore into
+    if spar_stream.in_background() {
+        let mutated = spar_stream.stages.last().and_then(|stage| {
+            stage
+                .state
+                .iter()
+                .find(|var| spar_stream.is_external(var) && mutates(stage, var))
+                .map(|var| (stage, var))
+        });
+        if let Some((stage, var)) = mutated {
+            let span = var.identifier.span();
+            let (stream, returns) = if spar_stream.is_pipeline {
+                ("a `spar_pipeline!`", "`spar_pipeline!`")
+            } else {
+                ("a LAZY stream", "`to_stream!`")
+            };
+            return Err(syn::Error::new(
+                span,
+                format!(
+                    "{} mutates `{}`, which {stream} cannot restore: it runs in the background, after {returns} returns",
+                    stage_name(stage),
+                    var.identifier
+                ),
+            ));
+        }
+    }
+
+    let mut outer: Vec<Ident> = spar_stream
+        .attrs
+        .input
+        .iter()
+        .map(|var| var.identifier.clone())
+        .collect();
+    if let Some(stage) = spar_stream.stages.first().filter(|stage| stage.id == 0) {
+        outer.extend(bindings(&flatten(stage.code.clone())));
+    }
+
+    let mut lints = Vec::new();
+    for stage in &spar_stream.stages {
+        // the dispatcher and source stages run in the enclosing code
+        if stage.id == 0 {
+            continue;
+        }
+        // a SPLIT sends what the stages in its code receive
+        if let (false, Some(next)) = (stage.is_split, spar_stream.next(stage)) {
+            let mut receivers = vec![next];
+            receivers.extend(spar_stream.loop_target(stage));
+            check_outputs(stage, &receivers)?;
+        }
+        if stage.is_source {
+            continue;
+        }
+
+        // the condition of a LOOP_BACK runs after the code of the stage
+        let mut code = stage.code.clone();
+        if let Some(looped) = &stage.attrs.loop_back {
+            code.extend(looped.condition.0.clone());
+        }
+        let tokens = flatten(code);
+        check_captures(stage, &tokens, &outer)?;
+        check_inputs(stage, &tokens, &mut lints);
+        check_mutations(stage, &tokens, &spar_stream.attrs.output, &mut lints);
+    }
+
+    Ok(lints)
+}
+
+/// Rust has no way for a macro to emit a warning, so each lint becomes a call to
+/// a deprecated function, located at the lint's span. Lints that depend on a type
+/// call a method that is only deprecated when the type isn't restored
+pub fn gen_warnings(lints: &[Lint]) -> TokenStream {
+    let mut code = TokenStream::new();
+    for lint in lints {
+        let message = &lint.message;
+        match &lint.unless_restored {
+            Some(var_type) => {
+                let probe = restore_probe(var_type);
+                code.extend(quote_spanned! {lint.span=>
+                    {
+                        #[allow(unused_imports)]
+                        use spar_rust_runtime::__private::{NotRestored as _, Restored as _};
+                        #probe.spar_mutated();
+                    }
+                })
+            }
+            None => code.extend(quote_spanned! {lint.span=>
+                {
+                    #[deprecated(note = #message)]
+                    fn spar_lint() {}
+                    spar_lint();
+                }
+            }),
+        }
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use quote::quote;
+
+    use super::*;
+
+    /// Messages of the lints that don't depend on types
+    fn messages(tokens: TokenStream) -> Vec<String> {
+        let spar_stream = SparStream::try_from(tokens).unwrap();
+        check(&spar_stream)
+            .unwrap()
+            .into_iter()
+            .filter(|lint| lint.unless_restored.is_none())
+            .map(|lint| lint.message)
+            .collect()
+    }
+
+    #[test]
+    fn no_lints() {
+        let lints = messages(quote! {
+            INPUT(size: u32, result: Vec<u32>), {
+                for n in 0..10 {
+                    let item = n;
+                    STAGE(INPUT(item: u32, size: u32), OUTPUT(item: u32), {
+                        item *= (1..size).count() as u32;
+                    });
+                    STAGE(INPUT(item: u32, result: Vec<u32>), {
+                        result.push(item);
+                        println!("{item}");
+                    });
+                }
+            }
+        });
+        assert!(lints.is_empty(), "{lints:?}");
+    }
+
+    #[test]
+    fn loop_back() {
+        // `steps` is only sent back to the first stage, and `limit` is only used by
+        // the condition
+        let lints = messages(quote! {
+            {
+                for n in 0..10u32 {
+                    let (steps, limit) = (0u32, 100u32);
+                    STAGE(INPUT(n: u32, steps: u32), OUTPUT(n: u32, steps: u32), NAME = double, {
+                        n *= 2;
+                        steps += 1;
+                    });
+                    STAGE(INPUT(n: u32, steps: u32, limit: u32), OUTPUT(n: u32, steps: u32), LOOP_BACK(to = double, while = n < limit), {
+                        println!("{steps}");
+                    });
+                    STAGE(INPUT(n: u32), {
+                        println!("{n}");
+                    });
+                }
+            }
+        });
+        assert!(lints.is_empty(), "{lints:?}");
+    }
+
+    #[test]
+    fn unused_input() {
+        let lints = messages(quote! {
+            INPUT(size: u32), {
+                for n in 0..10 {
+                    let item = n;
+                    STAGE(INPUT(item: u32, size: u32), OUTPUT(item: u32), {
+                        item *= 2;
+                    });
+                    STAGE(INPUT(item: u32), {
+                        println!("{}", item);
+                    });
+                }
+            }
+        });
+        assert_eq!(
+            lints,
+            ["stage 1 declares INPUT(size: u32), but never uses `size`"]
+        );
+    }
+
+    #[test]
+    fn mutation_is_lost() {
+        let lints = messages(quote! {
+            INPUT(count: u32, total: Vec<u32>), {
+                for n in 0..10 {
+                    let item = n;
+                    STAGE(INPUT(item: u32, total: Vec<u32>, count: u32), OUTPUT(item: u32), {
+                        total.push(item);
+                        count += 1;
+                    });
+                    STAGE(INPUT(item: u32), {
+                        println!("{}", item);
+                    });
+                }
+            }
+        });
+        assert_eq!(
+            lints,
+            [
+                "stage 1 mutates `total`, but the changes are lost: each replica of the stage works on its own copy, which is not restored once the stream ends",
+                "stage 1 mutates `count`, but the changes are lost: each replica of the stage works on its own copy, which is not restored once the stream ends",
+            ]
+        );
+    }
+
+    #[test]
+    fn output_not_consumed() {
+        let spar_stream = SparStream::try_from(quote! {
+            {
+                for n in 0..10 {
+                    let item = n;
+                    STAGE(INPUT(item: u32), OUTPUT(item: u32, twice: u32), {
+                        let twice = item * 2;
+                    });
+                    STAGE(INPUT(item: u32), {
+                        println!("{}", item);
+                    });
+                }
+            }
+        })
+        .unwrap();
+        let error = check(&spar_stream).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "stage 1 declares OUTPUT(twice: u32), but no later stage takes `twice` as an INPUT"
+        );
+    }
+
+    #[test]
+    fn mutation_unless_restored() {
+        let spar_stream = SparStream::try_from(quote! {
+            INPUT(total: Vec<u32>), {
+                for n in 0..10 {
+                    let item = n;
+                    STAGE(INPUT(item: u32, total: Vec<u32>), {
+                        total.push(item);
+                    });
+                }
+            }
+        })
+        .unwrap();
+        let lints = check(&spar_stream).unwrap();
+        assert_eq!(lints.len(), 1);
+        assert_eq!(
+            lints[0].unless_restored.as_ref().unwrap().to_string(),
+            "Vec<u32>"
+        );
+    }
+
+    #[test]
+    fn read_only_not_restored() {
+        let mut spar_stream = SparStream::try_from(quote! {
+            INPUT(names: Vec<String>, total: Vec<String>, table: HashMap<usize, u64>, counter: Counter, sums: Vec<u64>), {
+                for n in 0..3 {
+                    let item = n;
+                    STAGE(
+                        INPUT(item: usize, names: Vec<String>, total: Vec<String>, table: HashMap<usize, u64>, counter: Counter, sums: Vec<u64>),
+                        {
+                            total.push(names[item].clone());
+                            println!("{}", table[&item]);
+                            counter.0 += table[&item];
+                            add(sums, table[&item]);
+                        }
+                    );
+                }
+            }
+        })
+        .unwrap();
+        prune_restored(&mut spar_stream);
+        let restored: Vec<String> = spar_stream
+            .attrs
+            .output
+            .iter()
+            .map(|var| var.identifier.to_string())
+            .collect();
+        assert_eq!(restored, ["total", "counter", "sums"]);
+        assert_eq!(
+            spar_stream.stages.last().unwrap().attrs.output,
+            spar_stream.attrs.output
+        );
+    }
+
+    #[test]
+    fn background_stream_reads_inputs() {
+        let spar_stream = SparStream::try_from(quote! {
+            INPUT(offset: u32), OUTPUT(u32), LAZY, {
+                for n in 0..10 {
+                    STAGE(INPUT(n: u32, offset: u32), OUTPUT(n: u32), {
+                        n += *offset;
+                    });
+                }
+            }
+        })
+        .unwrap();
+        assert!(spar_stream.attrs.output.is_empty());
+        assert!(check(&spar_stream).unwrap().is_empty());
+    }
+
+    #[test]
+    fn lazy_stream_cannot_restore() {
+        let spar_stream = SparStream::try_from(quote! {
+            INPUT(total: Vec<u32>), OUTPUT(u32), LAZY, {
+                for n in 0..10 {
+                    STAGE(INPUT(n: u32, total: Vec<u32>), OUTPUT(n: u32), {
+                        total.push(n);
+                    });
+                }
+            }
+        })
+        .unwrap();
+        assert_eq!(
+            check(&spar_stream).err().unwrap().to_string(),
+            "stage 1 mutates `total`, which a LAZY stream cannot restore: it runs in the background, after `to_stream!` returns"
+        );
+    }
+
+    #[test]
+    fn pipeline_cannot_restore() {
+        let spar_stream = SparStream::pipeline(quote! {
+            INPUT(total: Vec<u32>), OUTPUT(u32), {
+                STAGE(INPUT(n: u32, total: Vec<u32>), OUTPUT(n: u32), {
+                    total.push(n);
+                });
+            }
+        })
+        .unwrap();
+        assert_eq!(
+            check(&spar_stream).err().unwrap().to_string(),
+            "stage 1 mutates `total`, which a `spar_pipeline!` cannot restore: it runs in the background, after `spar_pipeline!` returns"
+        );
+    }
+
+    #[test]
+    fn undeclared_capture() {
+        let spar_stream = SparStream::try_from(quote! {
+            INPUT(size: u32), {
+                for n in 0..10 {
+                    let item = n;
+                    let offset = 5;
+                    STAGE(INPUT(item: u32), {
+                        println!("{}", item + offset);
+                    });
+                }
+            }
+        })
+        .unwrap();
+        let error = check(&spar_stream).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "stage 1 uses `offset`, but it is not declared in its INPUT"
+        );
+    }
+
+    #[test]
+    fn closure_parameters() {
+        // the parameters of the closure are bound, but nothing after them is
+        let lints = messages(quote! {
+            {
+                (0..10).for_each(|n: u32| {
+                    let item = n;
+                    STAGE(INPUT(item: u32), {
+                        let twice = item * 2;
+                        println!("{twice}");
+                    });
+                });
+            }
+        });
+        assert!(lints.is_empty(), "{lints:?}");
+    }
+
+    #[test]
+    fn pattern_bindings() {
+        let tokens = flatten(quote! {
+            let Some(y) = x else { continue };
+            if let Ok(v) = r {}
+            let Point { x: px, y: ref py, .. } = p;
+            let crate::Pair(a, b): crate::Pair<u32, u32> = pair;
+            let (c, d): (u32, u32) = (1, 2);
+            for Item { id, .. } in items {}
+            let f = |e: HashMap<u32, u32>, g: u32| e;
+        });
+        let names: Vec<String> = bindings(&tokens).iter().map(Ident::to_string).collect();
+        assert_eq!(
+            names,
+            ["y", "v", "px", "py", "a", "b", "c", "d", "id", "f", "e", "g"]
+        );
+    }
+
+    #[test]
+    fn constructors_in_patterns() {
+        // `Some`, `Ok` and `Point` are not variables of the code around the stages
+        let lints = messages(quote! {
+            {
+                for n in 0..10 {
+                    let Some(item) = checked(n) else { continue };
+                    if let Ok(limit) = parse(n) {
+                        println!("{limit}");
+                    }
+                    let Point { x, .. } = origin();
+                    STAGE(INPUT(item: u32, x: u32), {
+                        let total = Some(item + x);
+                        if let Ok(value) = Point::new(item).check() {
+                            println!("{total:?} {value}");
+                        }
+                    });
+                }
+            }
+        });
+        assert!(lints.is_empty(), "{lints:?}");
+    }
+}
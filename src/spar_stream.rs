@@ -2,7 +2,7 @@
 
 use std::num::NonZeroU32;
 
-use proc_macro2::{Delimiter, Group, Span, TokenStream, TokenTree};
+use proc_macro2::{Delimiter, Group, Spacing, Span, TokenStream, TokenTree};
 use quote::{quote, ToTokens};
 use syn::{
     buffer::{Cursor, TokenBuffer},
@@ -26,12 +26,53 @@ impl PartialEq for VarType {
     }
 }
 
+/// Formats a type as it would be written by hand, instead of with a space between each token
+impl std::fmt::Display for VarType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut string = self.0.to_string();
+        for (from, to) in [
+            (" :: ", "::"),
+            (":: ", "::"),
+            (" < ", "<"),
+            (" <", "<"),
+            ("< ", "<"),
+            (" >", ">"),
+            (" ,", ","),
+            ("& ", "&"),
+            ("( ", "("),
+            (" )", ")"),
+            ("[ ", "["),
+            (" ]", "]"),
+            (" ;", ";"),
+        ] {
+            string = string.replace(from, to);
+        }
+        f.write_str(&string)
+    }
+}
+
 impl ToTokens for VarType {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         tokens.extend(self.0.clone())
     }
 }
 
+/// An expression given to an attribute, as in `TIMEOUT = Duration::from_secs(5)`
+#[derive(Debug, Clone)]
+pub struct SparExpr(pub TokenStream);
+
+impl PartialEq for SparExpr {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_string() == other.0.to_string()
+    }
+}
+
+impl ToTokens for SparExpr {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        tokens.extend(self.0.clone())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SparVar {
     pub identifier: Ident,
@@ -45,10 +86,6 @@ impl SparVar {
             var_type,
         }
     }
-
-    pub fn is_vec(&self) -> bool {
-        self.var_type.0.to_string().starts_with("Vec")
-    }
 }
 
 impl PartialEq for SparVar {
@@ -58,6 +95,12 @@ impl PartialEq for SparVar {
     }
 }
 
+impl std::fmt::Display for SparVar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.identifier, self.var_type)
+    }
+}
+
 impl ToTokens for SparVar {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let Self {
@@ -93,6 +136,52 @@ pub struct SparAttrs {
     pub input: Vec<SparVar>,
     pub output: Vec<SparVar>,
     pub replicate: Replicate,
+    /// `SHARED(name: Type)`, only allowed in a stream: inputs that the stages only
+    /// read, which are never restored. The stream adds them to its INPUT
+    pub shared: Vec<SparVar>,
+    /// `OUTPUT(Type)`, only allowed in a stream: the type of the items it returns
+    pub output_type: Option<VarType>,
+    /// `LAZY`, only allowed in a stream: it returns an iterator over its items,
+    /// instead of collecting them
+    pub lazy: bool,
+    /// `DETACHED`, only allowed in a stream: it runs on its own thread, and returns
+    /// a handle to join it
+    pub detached: bool,
+    /// `CANCEL = token`, only allowed in a stream: a `spar_rust_runtime::CancelToken`
+    /// that stops it early
+    pub cancel: Option<SparExpr>,
+    /// `TIMEOUT = duration`, only allowed in a stream: how long it may run before
+    /// it stops early
+    pub timeout: Option<SparExpr>,
+    /// `NESTED`, only allowed in a stream: it runs inside a stage of another stream,
+    /// and its replicas share the threads of that stage. The enclosing stream adds it
+    /// to the streams written in its stages, and the others must declare it
+    pub nested: bool,
+    /// `NAME = name`, only allowed in a stage: how a LOOP_BACK refers to it
+    pub name: Option<Ident>,
+    /// `LOOP_BACK(to = name, while = condition)`, only allowed in a stage: it sends
+    /// its items back to the stage named `name` while `condition` holds
+    pub loop_back: Option<SparLoop>,
+    /// `WINDOW(size = N, slide = M)`, only allowed in a stage: it runs once for
+    /// every window of `N` consecutive items, which starts `M` items after the last one
+    pub window: Option<SparWindow>,
+}
+
+/// How many items the windows of a stage have, and how many items apart they start
+#[derive(Debug, PartialEq, Clone)]
+pub struct SparWindow {
+    pub size: SparExpr,
+    /// The size, if not given: the windows don't overlap
+    pub slide: Option<SparExpr>,
+}
+
+/// Where a stage sends its items back to, and while which condition
+#[derive(Debug, PartialEq, Clone)]
+pub struct SparLoop {
+    pub to: Ident,
+    pub condition: SparExpr,
+    /// The id of the stage named `to`, once the stream is parsed
+    pub target: u32,
 }
 
 impl SparAttrs {
@@ -101,6 +190,16 @@ impl SparAttrs {
             input,
             output,
             replicate,
+            shared: Vec::new(),
+            output_type: None,
+            lazy: false,
+            detached: false,
+            cancel: None,
+            timeout: None,
+            nested: false,
+            name: None,
+            loop_back: None,
+            window: None,
         }
     }
 }
@@ -111,6 +210,27 @@ pub struct SparStage {
     pub state: Vec<SparVar>,
     pub code: TokenStream,
     pub id: u32,
+    /// Span of the 'STAGE' keyword, which errors about the whole stage point to
+    pub span: Span,
+    /// Variables that this stage receives and sends to the next one, without using
+    /// them, because a later stage takes them as input
+    pub forwarded: Vec<SparVar>,
+    /// A source stage has no INPUT: it is the first stage of a stream without code
+    /// before its stages, and its code produces the items of the stream
+    pub is_source: bool,
+    /// The ids of the stages it receives items from: the code before the stages
+    /// (id 0) for the first stage of a branch, or the last stage of every branch
+    /// that it merges
+    pub prev: Vec<u32>,
+    /// The code before the stages dispatches items to this stage from inside a
+    /// closure, which cannot stop the dispatcher with `break`
+    pub in_closure: bool,
+    /// A SPLIT has no code of its own to process items: like the code before the
+    /// stages, its code dispatches each item to the branches of stages in it
+    pub is_split: bool,
+    /// The chain of stages that the code of this stage is made of, which every
+    /// replica runs one after the other. Its code is then the code of the chain
+    pub nested: Vec<SparStage>,
 }
 
 impl SparStage {
@@ -120,8 +240,21 @@ impl SparStage {
             state: Vec::new(),
             code,
             id,
+            span: Span::call_site(),
+            forwarded: Vec::new(),
+            is_source: false,
+            prev: Vec::new(),
+            in_closure: false,
+            is_split: false,
+            nested: Vec::new(),
         }
     }
+
+    /// Everything the stage receives from the previous stage: its inputs, then
+    /// the variables it forwards
+    pub fn received(&self) -> Vec<&SparVar> {
+        self.attrs.input.iter().chain(&self.forwarded).collect()
+    }
 }
 
 impl PartialEq for SparStage {
@@ -130,55 +263,406 @@ impl PartialEq for SparStage {
     }
 }
 
+/// A variable sent from one stage to the next, whose OUTPUT and INPUT
+/// declarations are written with different types. Whether they are the same
+/// type can only be checked by the compiler
+#[derive(Debug)]
+pub struct SparHandoff {
+    pub from: u32,
+    pub to: u32,
+    pub output: SparVar,
+    pub input: SparVar,
+}
+
 pub struct SparStream {
     pub attrs: SparAttrs,
     pub stages: Vec<SparStage>,
     pub external_vars: Vec<SparVar>,
+    pub handoffs: Vec<SparHandoff>,
+    /// Parsed from `spar_pipeline!`: the items are posted to the handle it returns
+    pub is_pipeline: bool,
 }
 
 impl SparStream {
+    /// Parses a `spar_pipeline!`. Its first stage receives the items posted to the
+    /// handle, from a source stage that reads them from the `spar_posts` channel
+    pub fn pipeline(value: TokenStream) -> Result<Self> {
+        Self::parse(value, true)
+    }
+
+    /// Whether the stream runs on its own thread, after the macro returns, and
+    /// hands its items over as it produces them
+    pub fn in_background(&self) -> bool {
+        self.attrs.lazy || self.is_pipeline
+    }
+
+    /// Whether the macro evaluates to a value: the items of the stream, or a
+    /// handle to it
+    pub fn is_expression(&self) -> bool {
+        self.attrs.output_type.is_some() || self.attrs.detached || self.is_cancellable()
+    }
+
+    /// Whether the stream has a `CANCEL` or a `TIMEOUT`, so that it evaluates to
+    /// a `spar_rust_runtime::Outcome`
+    pub fn is_cancellable(&self) -> bool {
+        self.attrs.cancel.is_some() || self.attrs.timeout.is_some()
+    }
+
+    /// Whether the code before the stages, or a SPLIT, dispatches items to several
+    /// branches, or some stage merges branches, loops back or has a WINDOW, instead of
+    /// every stage sending to the next one
+    pub fn is_branched(&self) -> bool {
+        self.loops()
+            || self.has_windows()
+            || self
+                .stages
+                .windows(2)
+                .any(|pair| pair[1].prev != [pair[0].id] || pair[0].is_split)
+    }
+
+    /// Whether a stage sends items back to an earlier stage. The stream is then
+    /// branched, since earlier stages pass through the items for the stages after them
+    pub fn loops(&self) -> bool {
+        self.stages
+            .iter()
+            .any(|stage| stage.attrs.loop_back.is_some())
+    }
+
+    /// Whether a stage runs on windows of items. The stream is then branched, since
+    /// the end of the stream reaches the stages as an item of its own
+    pub fn has_windows(&self) -> bool {
+        self.stages.iter().any(|stage| stage.attrs.window.is_some())
+    }
+
+    /// The stage that `stage` loops back to, if any
+    pub fn loop_target(&self, stage: &SparStage) -> Option<&SparStage> {
+        let looped = stage.attrs.loop_back.as_ref()?;
+        self.stages.iter().find(|target| target.id == looped.target)
+    }
+
+    /// The stage that `stage` sends its items to, if any
+    pub fn next(&self, stage: &SparStage) -> Option<&SparStage> {
+        next_stage(&self.stages, stage)
+    }
+
     pub fn is_external(&self, var: &SparVar) -> bool {
         self.external_vars.contains(var)
     }
+
+    /// Whether the last stage sends anything to the collector: the items that the
+    /// stream returns, or the values of the external variables to restore
+    pub fn collects(&self) -> bool {
+        self.stages
+            .last()
+            .is_some_and(|stage| !stage.attrs.output.is_empty())
+    }
 }
 
 impl TryFrom<&proc_macro::TokenStream> for SparStream {
     type Error = syn::Error;
 
     fn try_from(value: &proc_macro::TokenStream) -> std::result::Result<Self, Self::Error> {
-        let input = TokenBuffer::new(
-            TokenTree::Group(Group::new(Delimiter::Parenthesis, value.clone().into()))
-                .into_token_stream()
-                .into(),
+        Self::try_from(TokenStream::from(value.clone()))
+    }
+}
+
+impl TryFrom<TokenStream> for SparStream {
+    type Error = syn::Error;
+
+    fn try_from(value: TokenStream) -> std::result::Result<Self, Self::Error> {
+        Self::parse(value, false)
+    }
+}
+
+impl SparStream {
+    fn parse(value: TokenStream, is_pipeline: bool) -> Result<Self> {
+        let input = TokenBuffer::new2(
+            TokenTree::Group(Group::new(Delimiter::Parenthesis, value)).into_token_stream(),
         );
         let (mut attrs, _, block) = parse_spar_args(input.begin())?;
+        for var in &attrs.shared {
+            if attrs
+                .input
+                .iter()
+                .any(|input| input.identifier == var.identifier)
+            {
+                return Err(syn::Error::new(
+                    var.identifier.span(),
+                    format!("`{}` is both an INPUT and SHARED", var.identifier),
+                ));
+            }
+        }
+        attrs.input.extend(attrs.shared.iter().cloned());
+        if let Some(name) = attrs
+            .name
+            .as_ref()
+            .or(attrs.loop_back.as_ref().map(|looped| &looped.to))
+        {
+            return Err(syn::Error::new(
+                name.span(),
+                "only a stage can have a NAME or a LOOP_BACK",
+            ));
+        }
+        if attrs.window.is_some() {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "only a stage can have a WINDOW",
+            ));
+        }
+        if let Some(var) = attrs.output.first() {
+            return Err(syn::Error::new(
+                var.identifier.span(),
+                "the OUTPUT of a stream is the type of the items it returns, as in OUTPUT(Type)",
+            ));
+        }
+        if attrs.lazy && attrs.output_type.is_none() {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "a LAZY stream returns an iterator over its items, whose type it must declare with OUTPUT(Type)",
+            ));
+        }
+        if attrs.lazy && attrs.detached {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "a LAZY stream already runs in the background, and cannot be DETACHED",
+            ));
+        }
+        if let (true, Some(expr)) = (attrs.lazy, attrs.cancel.as_ref().or(attrs.timeout.as_ref())) {
+            return Err(syn::Error::new_spanned(
+                expr,
+                "a LAZY stream is stopped by dropping its iterator, and cannot have a CANCEL or TIMEOUT",
+            ));
+        }
+        if is_pipeline {
+            if attrs.output_type.is_none() {
+                return Err(syn::Error::new(
+                    Span::call_site(),
+                    "a `spar_pipeline!` returns the results of the items posted to it, whose type it must declare with OUTPUT(Type)",
+                ));
+            }
+            if attrs.lazy || attrs.detached {
+                return Err(syn::Error::new(
+                    Span::call_site(),
+                    "a `spar_pipeline!` always runs in the background, and cannot be LAZY or DETACHED",
+                ));
+            }
+            if let Some(expr) = attrs.cancel.as_ref().or(attrs.timeout.as_ref()) {
+                return Err(syn::Error::new_spanned(
+                    expr,
+                    "a `spar_pipeline!` runs until it is shut down, and cannot have a CANCEL or TIMEOUT",
+                ));
+            }
+        }
         let (mut stages, code) = parse_spar_stages(block)?;
 
-        // if there is any code before the stages, it becomes the first stage:
+        if is_pipeline {
+            if let Some(token) = code.clone().into_iter().next() {
+                return Err(syn::Error::new(
+                    token.span(),
+                    "a `spar_pipeline!` only has stages: its items are posted to the handle it returns",
+                ));
+            }
+            // the handle of a pipeline waits for one result per item posted to it
+            if let Some(split) = stages.iter().find(|stage| stage.is_split) {
+                return Err(syn::Error::new(
+                    split.span,
+                    "a `spar_pipeline!` returns one result for every item posted to it, and cannot have a SPLIT",
+                ));
+            }
+            if let Some(looped) = stages
+                .iter()
+                .find_map(|stage| stage.attrs.loop_back.as_ref())
+            {
+                return Err(syn::Error::new(
+                    looped.to.span(),
+                    "a `spar_pipeline!` returns one result for every item posted to it, and cannot LOOP_BACK",
+                ));
+            }
+            if let Some(window) = stages.iter().find_map(|stage| stage.attrs.window.as_ref()) {
+                return Err(syn::Error::new_spanned(
+                    &window.size,
+                    "a `spar_pipeline!` returns one result for every item posted to it, and cannot have a WINDOW",
+                ));
+            }
+            let first = match stages.first() {
+                Some(first) => first,
+                None => {
+                    return Err(syn::Error::new(
+                        Span::call_site(),
+                        "a `spar_pipeline!` must have at least one stage",
+                    ))
+                }
+            };
+            // the inputs of the first stage that aren't inputs of the pipeline are the posted item
+            let items: Vec<SparVar> = first
+                .attrs
+                .input
+                .iter()
+                .filter(|var| {
+                    !attrs
+                        .input
+                        .iter()
+                        .any(|input| input.identifier == var.identifier)
+                })
+                .cloned()
+                .collect();
+            if items.is_empty() {
+                return Err(syn::Error::new(
+                    first.span,
+                    "the first stage of a `spar_pipeline!` receives the items posted to it, which it must declare as INPUT",
+                ));
+            }
+            let mut source = SparStage::new(
+                SparAttrs::new(Vec::new(), items, Replicate::SeqUnordered),
+                quote! { spar_posts },
+                0,
+            );
+            source.is_source = true;
+            stages.insert(0, source);
+        }
+
+        // if there is any code before the stages, it becomes the first stage. Its
+        // outputs are the variables it binds that any stage takes as input
         if !code.is_empty() {
             let mut stage = SparStage::new(attrs.clone(), code.clone(), 0);
-            if let Some(s) = stages.get(0) {
-                stage.attrs.output = find_variables_in_code(code, &s.attrs.input)?;
-            }
+            stage.attrs.output = find_variables_in_code(code, &inputs(&stages))?;
             stages.insert(0, stage)
+        } else if !is_pipeline {
+            // without code before the stages, the first stage receives nothing
+            if let Some(first) = stages.first_mut() {
+                if first.is_split {
+                    return Err(syn::Error::new(
+                        first.span,
+                        "a SPLIT dispatches the items it receives, so it cannot be the first stage of a stream without code before it",
+                    ));
+                }
+                first.prev.clear();
+            }
         }
 
-        // any input that was not send by the previous stage becomes 'state'
-        for i in 0..stages.len() - 1 {
-            if let Some(&mut [ref mut prev, ref mut cur]) = stages.get_mut(i..i + 2) {
-                cur.state = cur
-                    .attrs
-                    .input
-                    .iter()
-                    .filter_map(|var| {
-                        if !prev.attrs.output.contains(var) {
-                            Some(var.clone())
-                        } else {
-                            None
+        // every input is sent by the closest earlier stage that outputs it, and
+        // forwarded by the stages in between. Inputs that no stage sends become 'state'.
+        // Variables are matched by identifier only, since the same type can be
+        // written in many ways. When they are written differently, the input
+        // takes the type of the output, and the codegen asserts that both are the same.
+        // A stage that merges branches receives each input from every one of them
+        let mut handoffs = Vec::new();
+        for i in 1..stages.len() {
+            let branches: Vec<Vec<usize>> = stages[i]
+                .prev
+                .iter()
+                .filter_map(|&id| stages.iter().position(|stage| stage.id == id))
+                .map(|j| path(&stages, j))
+                .collect();
+            let mut input = Vec::new();
+            let mut state = Vec::new();
+            for mut var in std::mem::take(&mut stages[i].attrs.input) {
+                let mut producers = Vec::new();
+                for path in &branches {
+                    producers.push(
+                        find_producer(&stages, path, &var)?
+                            .map(|(k, output)| (path, k, output.clone())),
+                    );
+                }
+                if producers.iter().all(Option::is_none) {
+                    state.push(var);
+                    continue;
+                }
+                let merged = producers.len() > 1;
+                for (branch, producer) in stages[i].prev.clone().into_iter().zip(producers) {
+                    let (path, k, output) = match producer {
+                        Some(producer) => producer,
+                        None => {
+                            return Err(syn::Error::new(
+                                var.identifier.span(),
+                                format!(
+                                    "`{}` must be sent to stage {} by every branch it merges, but the branch that ends with stage {branch} doesn't send it",
+                                    var.identifier, stages[i].id
+                                ),
+                            ))
                         }
-                    })
-                    .collect();
-                cur.attrs.input.retain(|var| !cur.state.contains(var));
+                    };
+                    if output.var_type != var.var_type {
+                        handoffs.push(SparHandoff {
+                            from: stages[path[k]].id,
+                            to: stages[i].id,
+                            output: output.clone(),
+                            input: var.clone(),
+                        });
+                    }
+                    if !merged {
+                        var.var_type = output.var_type.clone();
+                    }
+                    for &j in &path[..k] {
+                        stages[j].forwarded.push(var.clone());
+                    }
+                }
+                input.push(var);
+            }
+            stages[i].attrs.input = input;
+            stages[i].state = state;
+
+            // a SPLIT sends its inputs to the stages it dispatches to, and the
+            // variables its code binds that later stages take as input
+            if stages[i].is_split {
+                let mut output = stages[i].attrs.input.clone();
+                for var in
+                    find_variables_in_code(stages[i].code.clone(), &inputs(&stages[i + 1..]))?
+                {
+                    if !output.iter().any(|out| out.identifier == var.identifier) {
+                        output.push(var);
+                    }
+                }
+                stages[i].attrs.output = output;
+            }
+        }
+
+        validate_stages(&mut stages)?;
+        resolve_loops(&mut stages)?;
+        validate_windows(&stages)?;
+
+        // the last stage of a branch that no stage merges consumes its items, and only
+        // the last stage of the stream sends anything to the collector
+        for stage in stages.iter().rev().skip(1) {
+            if stage.id == 0 || next_stage(&stages, stage).is_some() {
+                continue;
+            }
+            if attrs.output_type.is_some() {
+                return Err(syn::Error::new(
+                    stage.span,
+                    format!(
+                        "stage {} ends a branch that no later stage merges, but the stream returns the items of its last stage, which every branch must reach",
+                        stage.id
+                    ),
+                ));
+            }
+            if let Some(var) = stage.attrs.output.first() {
+                return Err(syn::Error::new(
+                    var.identifier.span(),
+                    format!(
+                        "stage {} ends a branch that no later stage merges, so it cannot declare an OUTPUT",
+                        stage.id
+                    ),
+                ));
+            }
+        }
+
+        // the stream returns the items that its last stage outputs
+        if let Some(last) = stages.last().filter(|stage| stage.id != 0) {
+            match (&attrs.output_type, last.attrs.output.is_empty()) {
+                (Some(_), true) => {
+                    return Err(syn::Error::new(
+                        last.span,
+                        "the stream returns the items of its last stage, which must declare them as OUTPUT",
+                    ))
+                }
+                (None, false) => {
+                    return Err(syn::Error::new(
+                        last.span,
+                        "the last stage can only declare an OUTPUT if the stream returns its items, with OUTPUT(Type)",
+                    ))
+                }
+                _ => (),
             }
         }
 
@@ -187,7 +671,7 @@ impl TryFrom<&proc_macro::TokenStream> for SparStream {
         for stage in &stages {
             for input in &stage.state {
                 if !attrs.input.contains(input) {
-                    return Err(syn::Error::new(Span::call_site(), "every stage input must either be sent from the previous stage, or be a stream input"));
+                    return Err(syn::Error::new(input.identifier.span(), "every stage input must either be sent from an earlier stage, or be a stream input"));
                 }
 
                 if !external_vars.contains(input) {
@@ -196,10 +680,13 @@ impl TryFrom<&proc_macro::TokenStream> for SparStream {
             }
         }
 
-        // variables that must be restored become the stream's output
-        if let Some(stage) = stages.last_mut() {
+        // the external variables that the last stage uses become the stream's output,
+        // unless they are SHARED or the stream runs in the background. The ones whose
+        // type implements `spar_rust_runtime::Restore` are restored
+        let restores = !attrs.lazy && !is_pipeline;
+        if let (true, Some(stage)) = (restores, stages.last_mut()) {
             for var in &stage.state {
-                if external_vars.contains(var) && var.is_vec() {
+                if external_vars.contains(var) && !attrs.shared.contains(var) {
                     stage.attrs.output.push(var.clone());
                     attrs.output.push(var.clone());
                 }
@@ -210,61 +697,482 @@ impl TryFrom<&proc_macro::TokenStream> for SparStream {
             attrs,
             stages,
             external_vars,
+            handoffs,
+            is_pipeline,
         })
     }
 }
 
+/// The stages that the items of stage `from` went through, closest first: `from`,
+/// then the stage it received them from, and so on, up to the first stage of the
+/// stream or a stage that merges branches, whose inputs depend on the branch
+fn path(stages: &[SparStage], from: usize) -> Vec<usize> {
+    let mut path = vec![from];
+    while let [prev] = stages[*path.last().unwrap()].prev[..] {
+        match stages.iter().position(|stage| stage.id == prev) {
+            Some(j) => path.push(j),
+            None => break,
+        }
+    }
+    path
+}
+
+/// Returns the position in `path` of the closest stage that outputs `var`, and its
+/// declaration. A stage that receives `var` without sending it consumes it, so
+/// stages after it cannot receive it
+fn find_producer<'a>(
+    stages: &'a [SparStage],
+    path: &[usize],
+    var: &SparVar,
+) -> Result<Option<(usize, &'a SparVar)>> {
+    for (k, stage) in path.iter().map(|&j| &stages[j]).enumerate() {
+        if let Some(output) = stage
+            .attrs
+            .output
+            .iter()
+            .find(|output| output.identifier == var.identifier)
+        {
+            return Ok(Some((k, output)));
+        }
+        // the inputs of the code before the stages are the stream's inputs
+        if stage.id != 0
+            && stage
+                .attrs
+                .input
+                .iter()
+                .any(|input| input.identifier == var.identifier)
+        {
+            return Err(syn::Error::new(
+                var.identifier.span(),
+                format!(
+                    "`{}` cannot be sent to this stage, since stage {} receives it without sending it as OUTPUT",
+                    var.identifier, stage.id
+                ),
+            ));
+        }
+    }
+    Ok(None)
+}
+
+/// The inputs of `stages`, once each
+fn inputs(stages: &[SparStage]) -> Vec<SparVar> {
+    let mut inputs: Vec<SparVar> = Vec::new();
+    for var in stages.iter().flat_map(|stage| &stage.attrs.input) {
+        if !inputs
+            .iter()
+            .any(|found| found.identifier == var.identifier)
+        {
+            inputs.push(var.clone());
+        }
+    }
+    inputs
+}
+
+/// The stage that `stage` sends its items to: the next stage of its branch, or the
+/// stage that merges it
+pub fn next_stage<'a>(stages: &'a [SparStage], stage: &SparStage) -> Option<&'a SparStage> {
+    stages.iter().find(|next| next.prev.contains(&stage.id))
+}
+
+/// Every stage must receive items from the previous one, except for the first
+/// stage of a stream without code before its stages, which can be a source stage
+fn validate_stages(stages: &mut [SparStage]) -> Result<()> {
+    let has_source = match stages.first_mut() {
+        Some(first) if first.id == 1 && first.attrs.input.is_empty() && first.state.is_empty() => {
+            if first.attrs.output.is_empty() {
+                return Err(syn::Error::new(
+                    first.span,
+                    "a source stage, without INPUT, must declare the OUTPUT of the items it produces",
+                ));
+            }
+            if first.attrs.replicate.is_replicate() {
+                return Err(syn::Error::new(
+                    first.span,
+                    "a source stage runs on the calling thread, and cannot be replicated",
+                ));
+            }
+            first.is_source = true;
+            true
+        }
+        _ => false,
+    };
+
+    if has_source && stages.len() == 1 {
+        return Err(syn::Error::new(
+            stages[0].span,
+            "a source stage must be followed by a stage that receives its items",
+        ));
+    }
+
+    for stage in stages.iter().skip(1) {
+        if stage.received().is_empty() {
+            let from = match stage.prev.first() {
+                Some(&prev) if prev != 0 => format!("stage {prev}"),
+                _ => "the code before it".to_owned(),
+            };
+            return Err(syn::Error::new(
+                stage.span,
+                format!(
+                    "stage {} does not receive any variable from {from}. Only the first stage of a stream without code before its stages can have no INPUT",
+                    stage.id
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// A stage that loops back sends its items back to itself or to an earlier stage.
+/// Every item that loops must go through the same stages again, so the stream
+/// cannot have branches, and the stage must have what its target receives
+fn resolve_loops(stages: &mut [SparStage]) -> Result<()> {
+    for (i, stage) in stages.iter().enumerate() {
+        if let Some(name) = &stage.attrs.name {
+            if stages[..i]
+                .iter()
+                .any(|earlier| earlier.attrs.name.as_ref() == Some(name))
+            {
+                return Err(syn::Error::new(
+                    name.span(),
+                    format!("more than one stage is named `{name}`"),
+                ));
+            }
+        }
+    }
+
+    for i in 0..stages.len() {
+        let stage = &stages[i];
+        let looped = match &stage.attrs.loop_back {
+            Some(looped) => looped,
+            None => continue,
+        };
+        if stages
+            .windows(2)
+            .any(|pair| pair[1].prev != [pair[0].id] || pair[0].is_split)
+        {
+            return Err(syn::Error::new(
+                stage.span,
+                format!(
+                    "stage {} loops back, so every item must go through every stage, and the stream cannot have branches or SPLITs",
+                    stage.id
+                ),
+            ));
+        }
+        let target = match stages
+            .iter()
+            .position(|target| target.attrs.name.as_ref() == Some(&looped.to))
+        {
+            Some(j) if j > i => {
+                return Err(syn::Error::new(
+                    looped.to.span(),
+                    format!(
+                        "stage {} can only loop back to itself or to an earlier stage, but `{}` is stage {}",
+                        stage.id, looped.to, stages[j].id
+                    ),
+                ))
+            }
+            Some(j) => &stages[j],
+            None => {
+                return Err(syn::Error::new(
+                    looped.to.span(),
+                    format!("no stage is named `{}`", looped.to),
+                ))
+            }
+        };
+        if target.is_source {
+            return Err(syn::Error::new(
+                looped.to.span(),
+                format!(
+                    "`{}` is a source stage, which produces the items of the stream, so no stage can loop back to it",
+                    looped.to
+                ),
+            ));
+        }
+        let has = |var: &SparVar| {
+            stage
+                .received()
+                .into_iter()
+                .chain(&stage.attrs.output)
+                .any(|has| has.identifier == var.identifier)
+        };
+        if let Some(var) = target.received().into_iter().find(|var| !has(var)) {
+            return Err(syn::Error::new(
+                looped.to.span(),
+                format!(
+                    "stage {} loops back to stage {}, which receives `{}`, but stage {} neither receives nor sends it",
+                    stage.id, target.id, var.identifier, stage.id
+                ),
+            ));
+        }
+        let target = target.id;
+        stages[i].attrs.loop_back.as_mut().unwrap().target = target;
+    }
+
+    Ok(())
+}
+
+/// A stage with a WINDOW runs once for every window of consecutive items, on one
+/// thread. The stream tells it how many items it sent once it ends, so that it can
+/// run on the last, partial window, so every item must reach it
+fn validate_windows(stages: &[SparStage]) -> Result<()> {
+    for stage in stages.iter().filter(|stage| stage.attrs.window.is_some()) {
+        if stage.is_source {
+            return Err(syn::Error::new(
+                stage.span,
+                "a source stage produces the items of the stream, and cannot have a WINDOW",
+            ));
+        }
+        if stage.attrs.replicate.is_replicate() {
+            return Err(syn::Error::new(
+                stage.span,
+                format!(
+                    "stage {} runs on windows of consecutive items, so it cannot be replicated",
+                    stage.id
+                ),
+            ));
+        }
+        if stages
+            .windows(2)
+            .any(|pair| pair[1].prev != [pair[0].id] || pair[0].is_split)
+            || stages.iter().any(|stage| stage.attrs.loop_back.is_some())
+        {
+            return Err(syn::Error::new(
+                stage.span,
+                format!(
+                    "stage {} has a WINDOW, so every item must reach it, and the stream cannot have branches, SPLITs or LOOP_BACKs",
+                    stage.id
+                ),
+            ));
+        }
+        if let Some(var) = stage.forwarded.first() {
+            return Err(syn::Error::new(
+                stage.span,
+                format!(
+                    "stage {} runs once for every window of items, so `{}` cannot go through it to a later stage. A later stage can only receive what it sends as OUTPUT",
+                    stage.id, var.identifier
+                ),
+            ));
+        }
+        // the stream ends the last window once this stage received as many items as
+        // it posted, so none of the stages before it can drop one
+        for earlier in stages
+            .iter()
+            .take_while(|earlier| earlier.id != stage.id)
+            .filter(|earlier| earlier.id != 0 && !earlier.is_source)
+        {
+            if let Some(span) = leaves_early(earlier.code.clone()) {
+                return Err(syn::Error::new(
+                    span,
+                    format!(
+                        "stage {} has a WINDOW, so every item must reach it, and stage {} cannot leave its code early, which would drop its item",
+                        stage.id, earlier.id
+                    ),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Pushes the identifiers of `to_find` that are in `pattern` to `vars`
+fn find_variables_in_pattern(pattern: &[TokenTree], to_find: &[SparVar], vars: &mut Vec<SparVar>) {
+    for token in pattern {
+        match token {
+            TokenTree::Group(group) => {
+                let pattern: Vec<TokenTree> = group.stream().into_iter().collect();
+                find_variables_in_pattern(&pattern, to_find, vars);
+            }
+            TokenTree::Ident(ident) => {
+                if let Some(v) = to_find.iter().find(|var| var.identifier == *ident) {
+                    if !vars.contains(v) {
+                        vars.push(v.clone());
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Whether the '|' at `tokens[i]` starts the parameters of a closure: it starts an
+/// argument or a statement, or it follows `move` or '='. A `||` closure has none
+fn starts_closure(tokens: &[TokenTree], i: usize) -> bool {
+    let is_bar = matches!(
+        &tokens[i],
+        TokenTree::Punct(punct) if punct.as_char() == '|' && punct.spacing() == Spacing::Alone
+    );
+    is_bar && starts_argument(tokens, i)
+}
+
+/// Whether `tokens[i]` starts an argument or a statement, or follows `move` or '='
+fn starts_argument(tokens: &[TokenTree], i: usize) -> bool {
+    match i.checked_sub(1).map(|i| &tokens[i]) {
+        None => true,
+        Some(TokenTree::Ident(ident)) => ident == "move",
+        Some(TokenTree::Punct(punct)) => matches!(punct.as_char(), ',' | '=' | ';'),
+        Some(_) => false,
+    }
+}
+
+/// The span of the first `return` or `?` in `tokens` that leaves the code of a
+/// stage, which then sends nothing for its item. The ones in closures, nested
+/// functions and async blocks leave those instead
+fn leaves_early(tokens: TokenStream) -> Option<Span> {
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+    let is_punct = |i: usize, chars: &[char]| match tokens.get(i) {
+        Some(TokenTree::Punct(punct)) => chars.contains(&punct.as_char()),
+        _ => false,
+    };
+    let is_block = |i: usize| match tokens.get(i) {
+        Some(TokenTree::Group(group)) => group.delimiter() == Delimiter::Brace,
+        _ => false,
+    };
+    let mut i = 0;
+    while i < tokens.len() {
+        match &tokens[i] {
+            TokenTree::Group(group) => {
+                if let Some(span) = leaves_early(group.stream()) {
+                    return Some(span);
+                }
+            }
+            TokenTree::Ident(ident) if ident == "return" => return Some(ident.span()),
+            TokenTree::Punct(punct) if punct.as_char() == '?' => return Some(punct.span()),
+            // skip the parameters and the body of a closure, which ends at the
+            // end of its block, or of the argument or statement
+            TokenTree::Punct(_)
+                if starts_closure(&tokens, i)
+                    || (is_punct(i, &['|'])
+                        && is_punct(i + 1, &['|'])
+                        && starts_argument(&tokens, i)) =>
+            {
+                i += 1;
+                while i < tokens.len() && !is_punct(i, &['|']) {
+                    i += 1;
+                }
+                if is_block(i + 1) {
+                    i += 1;
+                } else {
+                    while i + 1 < tokens.len() && !is_punct(i + 1, &[',', ';']) {
+                        i += 1;
+                    }
+                }
+            }
+            // skip everything up to the body of a nested function or async block
+            TokenTree::Ident(ident) if ident == "fn" || ident == "async" => {
+                while i + 1 < tokens.len() && !is_block(i + 1) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    None
+}
+
+/// The variables of `to_find` that the code binds, in the pattern of a `let` (which
+/// includes `if let` and `while let`), of a `for`, of a match arm, or in the
+/// parameters of a closure
 fn find_variables_in_code(tokens: TokenStream, to_find: &[SparVar]) -> Result<Vec<SparVar>> {
     let mut vars = Vec::new();
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+    // where the pattern of the current match arm starts
+    let mut arm = 0;
 
-    let buf = TokenBuffer::new2(tokens);
-    let mut cursor = buf.begin();
-
-    while let Some((token_tree, mut next)) = cursor.token_tree() {
+    for (i, token_tree) in tokens.iter().enumerate() {
         match token_tree {
             TokenTree::Group(group) => {
-                let inner_vars = find_variables_in_code(group.stream(), to_find)?;
-                vars.extend(inner_vars);
-            }
-            TokenTree::Ident(ident) if ident == "let" => {
-                //TODO: TUPLES!!!
-                while let Some((token, after)) = next.token_tree() {
-                    if let TokenTree::Ident(ident) = token {
-                        if ident == "mut" {
-                            next = after;
-                            continue;
+                vars.extend(
+                    find_variables_in_code(group.stream(), to_find)?
+                        .into_iter()
+                        .filter(|var| !vars.contains(var))
+                        .collect::<Vec<_>>(),
+                );
+                if group.delimiter() == Delimiter::Brace {
+                    arm = i + 1;
+                }
+            }
+            TokenTree::Ident(ident) if ident == "let" || ident == "for" => {
+                let end = tokens[i + 1..]
+                    .iter()
+                    .position(|token| match token {
+                        TokenTree::Ident(ident) => ident == "in",
+                        TokenTree::Punct(punct) => {
+                            matches!(punct.as_char(), '=' | ';')
+                                || (punct.as_char() == ':' && punct.spacing() == Spacing::Alone)
                         }
+                        _ => false,
+                    })
+                    .map_or(tokens.len(), |end| i + 1 + end);
+                find_variables_in_pattern(&tokens[i + 1..end], to_find, &mut vars);
+            }
+            TokenTree::Punct(_) if starts_closure(&tokens, i) => {
+                let end = tokens[i + 1..]
+                    .iter()
+                    .position(
+                        |token| matches!(token, TokenTree::Punct(punct) if punct.as_char() == '|'),
+                    )
+                    .map_or(tokens.len(), |end| i + 1 + end);
+                // a parameter ends at its type, if any
+                let mut pattern = Vec::new();
+                let mut in_type = false;
+                for token in &tokens[i + 1..end] {
+                    match token {
+                        TokenTree::Punct(punct) if punct.as_char() == ':' => in_type = true,
+                        TokenTree::Punct(punct) if punct.as_char() == ',' => in_type = false,
+                        _ if !in_type => pattern.push(token.clone()),
+                        _ => (),
                     }
-                    break;
                 }
-                if let Some((token, after)) = next.token_tree() {
-                    if let TokenTree::Ident(ident) = token {
-                        if let Some(v) = to_find.iter().find(|var| var.identifier == ident) {
-                            if !vars.contains(v) {
-                                vars.push(v.clone());
-                            }
-                        }
+                find_variables_in_pattern(&pattern, to_find, &mut vars);
+            }
+            TokenTree::Punct(punct) if punct.as_char() == ',' || punct.as_char() == ';' => {
+                arm = i + 1;
+            }
+            TokenTree::Punct(punct) if punct.as_char() == '>' && i > 0 => {
+                if let TokenTree::Punct(eq) = &tokens[i - 1] {
+                    if eq.as_char() == '=' && eq.spacing() == Spacing::Joint {
+                        // the pattern ends at its guard, if any
+                        let end = tokens[arm..i - 1]
+                            .iter()
+                            .position(
+                                |token| matches!(token, TokenTree::Ident(ident) if ident == "if"),
+                            )
+                            .map_or(i - 1, |end| arm + end);
+                        find_variables_in_pattern(&tokens[arm.min(end)..end], to_find, &mut vars);
+                        arm = i + 1;
                     }
-                    next = after;
                 }
             }
             _ => (),
         };
-
-        cursor = next;
     }
 
     Ok(vars)
 }
 
+/// The type ends at the first comma outside of its generic arguments, e.g.
+/// `HashMap<u32, String>`. The `>` of an arrow, as in `fn(u32) -> u32`, doesn't
+/// close any of them
 fn get_type(cursor: Cursor) -> Result<(TokenStream, Cursor)> {
     let mut next = cursor;
     let mut code = TokenStream::new();
+    let mut depth = 0usize;
+    let mut after_dash = false;
     while let Some((token_tree, rest)) = next.token_tree() {
         if let TokenTree::Punct(ref punct) = token_tree {
-            if punct.as_char() == ',' {
-                return Ok((code, next));
+            match punct.as_char() {
+                ',' if depth == 0 => return Ok((code, next)),
+                '<' => depth += 1,
+                '>' if !after_dash => depth = depth.saturating_sub(1),
+                _ => (),
             }
+            after_dash = punct.as_char() == '-';
+        } else {
+            after_dash = false;
         }
         code.extend(token_tree.into_token_stream());
         next = rest;
@@ -290,6 +1198,30 @@ fn skip_parenthesis(cursor: Cursor) -> Result<(Cursor, Cursor)> {
     }
 }
 
+/// `OUTPUT(Type)` instead of `OUTPUT(name: Type, ...)`. The colon of a path,
+/// as in `std::string::String`, is joint with the next one
+fn get_output_type(cursor: Cursor) -> Result<Option<(VarType, Cursor)>> {
+    let (args, after) = skip_parenthesis(cursor)?;
+    if let Some((TokenTree::Ident(_), next)) = args.token_tree() {
+        if let Some((TokenTree::Punct(punct), _)) = next.token_tree() {
+            if punct.as_char() == ':' && punct.spacing() == Spacing::Alone {
+                return Ok(None);
+            }
+        }
+    }
+    if args.eof() {
+        return Ok(None);
+    }
+    let (ty, rest) = get_type(args)?;
+    if !rest.eof() {
+        return Err(syn::Error::new(
+            rest.span(),
+            "OUTPUT takes either variables, as in OUTPUT(name: Type), or a single type",
+        ));
+    }
+    Ok(Some((VarType(ty), after)))
+}
+
 fn get_variables(cursor: Cursor) -> Result<(Vec<SparVar>, Cursor)> {
     let (args, after) = skip_parenthesis(cursor)?;
     let mut rest = args;
@@ -353,6 +1285,139 @@ fn parse_replicate(cursor: Cursor) -> Result<(Replicate, Cursor)> {
     ))
 }
 
+/// Parses the `= expression` of an attribute, up to the next ','
+fn parse_expr<'a>(cursor: Cursor<'a>, attr: &str) -> Result<(SparExpr, Cursor<'a>)> {
+    let mut rest = match cursor.token_tree() {
+        Some((TokenTree::Punct(punct), next)) if punct.as_char() == '=' => next,
+        _ => {
+            return Err(syn::Error::new(
+                cursor.span(),
+                format!("expected '=' after {attr}, as in '{attr} = expression'"),
+            ))
+        }
+    };
+
+    let mut expr = TokenStream::new();
+    while let Some((token_tree, next)) = rest.token_tree() {
+        if matches!(&token_tree, TokenTree::Punct(punct) if punct.as_char() == ',') {
+            break;
+        }
+        expr.extend([token_tree]);
+        rest = next;
+    }
+    if expr.is_empty() {
+        return Err(syn::Error::new(
+            rest.span(),
+            format!("expected an expression after '{attr} ='"),
+        ));
+    }
+    Ok((SparExpr(expr), rest))
+}
+
+/// The name given to an attribute, as in `NAME = name`
+fn parse_name<'a>(cursor: Cursor<'a>, attr: &str) -> Result<(Ident, Cursor<'a>)> {
+    if let Some((TokenTree::Punct(punct), next)) = cursor.token_tree() {
+        if let (true, Some((TokenTree::Ident(name), next))) =
+            (punct.as_char() == '=', next.token_tree())
+        {
+            return Ok((name, next));
+        }
+    }
+    Err(syn::Error::new(
+        cursor.span(),
+        format!("expected a name after {attr}, as in '{attr} = name'"),
+    ))
+}
+
+/// `LOOP_BACK(to = name, while = condition)`, in any order
+fn parse_loop_back(cursor: Cursor) -> Result<(SparLoop, Cursor)> {
+    let (args, after) = skip_parenthesis(cursor)?;
+    let mut to = None;
+    let mut condition = None;
+
+    let mut rest = args;
+    while let Some((token_tree, next)) = rest.token_tree() {
+        let next = match &token_tree {
+            TokenTree::Ident(ident) if ident == "to" && to.is_none() => {
+                let (name, next) = parse_name(next, "to")?;
+                to = Some(name);
+                next
+            }
+            TokenTree::Ident(ident) if ident == "while" && condition.is_none() => {
+                let (expr, next) = parse_expr(next, "while")?;
+                condition = Some(expr);
+                next
+            }
+            _ => {
+                return Err(syn::Error::new(
+                    rest.span(),
+                    format!("unexpected token '{token_tree}'. A LOOP_BACK takes 'to = name' and 'while = condition'"),
+                ))
+            }
+        };
+        rest = match next.token_tree() {
+            Some(_) => skip_punct(next, ',')?,
+            None => next,
+        };
+    }
+
+    match (to, condition) {
+        (Some(to), Some(condition)) => Ok((
+            SparLoop {
+                to,
+                condition,
+                target: 0,
+            },
+            after,
+        )),
+        _ => Err(syn::Error::new(
+            cursor.span(),
+            "a LOOP_BACK takes the NAME of the stage it sends its items back to, and while to send them, as in 'LOOP_BACK(to = name, while = condition)'",
+        )),
+    }
+}
+
+/// `WINDOW(size = N, slide = M)`, in any order. The slide is optional
+fn parse_window(cursor: Cursor) -> Result<(SparWindow, Cursor)> {
+    let (args, after) = skip_parenthesis(cursor)?;
+    let mut size = None;
+    let mut slide = None;
+
+    let mut rest = args;
+    while let Some((token_tree, next)) = rest.token_tree() {
+        let next = match &token_tree {
+            TokenTree::Ident(ident) if ident == "size" && size.is_none() => {
+                let (expr, next) = parse_expr(next, "size")?;
+                size = Some(expr);
+                next
+            }
+            TokenTree::Ident(ident) if ident == "slide" && slide.is_none() => {
+                let (expr, next) = parse_expr(next, "slide")?;
+                slide = Some(expr);
+                next
+            }
+            _ => {
+                return Err(syn::Error::new(
+                    rest.span(),
+                    format!("unexpected token '{token_tree}'. A WINDOW takes 'size = N' and, optionally, 'slide = M'"),
+                ))
+            }
+        };
+        rest = match next.token_tree() {
+            Some(_) => skip_punct(next, ',')?,
+            None => next,
+        };
+    }
+
+    match size {
+        Some(size) => Ok((SparWindow { size, slide }, after)),
+        None => Err(syn::Error::new(
+            cursor.span(),
+            "a WINDOW takes how many items each window has, and optionally how many items apart they start, as in 'WINDOW(size = N, slide = M)'",
+        )),
+    }
+}
+
 fn skip_punct(cursor: Cursor, punct: char) -> Result<Cursor> {
     if let Some((token_tree, next)) = cursor.token_tree() {
         if let TokenTree::Punct(ref p) = token_tree {
@@ -365,16 +1430,26 @@ fn skip_punct(cursor: Cursor, punct: char) -> Result<Cursor> {
     }
     Err(syn::Error::new(
         cursor.span(),
-        "expected '{punct}', found EOF",
+        format!("expected '{punct}', found EOF"),
     ))
 }
 
-fn parse_spar_args(cursor: Cursor) -> Result<(SparAttrs, Cursor, Cursor)> {
+pub fn parse_spar_args(cursor: Cursor) -> Result<(SparAttrs, Cursor, Cursor)> {
     let (args, after) = skip_parenthesis(cursor)?;
 
     let mut input: Vec<SparVar> = Vec::new();
     let mut output: Vec<SparVar> = Vec::new();
+    let mut shared: Vec<SparVar> = Vec::new();
     let mut replicate = Replicate::SeqUnordered;
+    let mut output_type = None;
+    let mut lazy = false;
+    let mut detached = false;
+    let mut cancel = None;
+    let mut timeout = None;
+    let mut nested = false;
+    let mut name = None;
+    let mut loop_back = None;
+    let mut window = None;
 
     let mut rest = args;
     while let Some((token_tree, next)) = rest.token_tree() {
@@ -394,13 +1469,32 @@ fn parse_spar_args(cursor: Cursor) -> Result<(SparAttrs, Cursor, Cursor)> {
                     input = i;
                     rest = skip_punct(next, ',')?;
                 }
+                "SHARED" => {
+                    if !shared.is_empty() {
+                        return Err(syn::Error::new(
+                            rest.span(),
+                            "multiple SHAREDs aren't allowed",
+                        ));
+                    }
+                    let (s, next) = get_variables(next)?;
+                    if s.is_empty() {
+                        return Err(syn::Error::new(rest.span(), "SHARED cannot be empty"));
+                    }
+                    shared = s;
+                    rest = skip_punct(next, ',')?;
+                }
                 "OUTPUT" => {
-                    if !output.is_empty() {
+                    if !output.is_empty() || output_type.is_some() {
                         return Err(syn::Error::new(
                             rest.span(),
                             "multiple OUTPUTs aren't allowed",
                         ));
                     }
+                    if let Some((ty, next)) = get_output_type(next)? {
+                        output_type = Some(ty);
+                        rest = skip_punct(next, ',')?;
+                        continue;
+                    }
                     let (o, next) = get_variables(next)?;
                     if o.is_empty() {
                         return Err(syn::Error::new(rest.span(), "INPUT cannot be empty"));
@@ -423,10 +1517,72 @@ fn parse_spar_args(cursor: Cursor) -> Result<(SparAttrs, Cursor, Cursor)> {
                     if !matches!(replicate, Replicate::SeqUnordered) {
                         return Err(syn::Error::new(
                             rest.span(),
-                            "only one of REPLICATE or ORDERED can be specified",
+                            "only one of REPLICATE or ORDERED can be specified",
+                        ));
+                    }
+                    replicate = Replicate::SeqOrdered;
+                    rest = skip_punct(next, ',')?;
+                }
+                "LAZY" => {
+                    lazy = true;
+                    rest = skip_punct(next, ',')?;
+                }
+                "DETACHED" => {
+                    detached = true;
+                    rest = skip_punct(next, ',')?;
+                }
+                "NESTED" => {
+                    nested = true;
+                    rest = skip_punct(next, ',')?;
+                }
+                "NAME" => {
+                    if name.is_some() {
+                        return Err(syn::Error::new(
+                            rest.span(),
+                            "multiple NAMEs aren't allowed",
+                        ));
+                    }
+                    let (n, next) = parse_name(next, "NAME")?;
+                    name = Some(n);
+                    rest = skip_punct(next, ',')?;
+                }
+                "LOOP_BACK" => {
+                    if loop_back.is_some() {
+                        return Err(syn::Error::new(
+                            rest.span(),
+                            "multiple LOOP_BACKs aren't allowed",
+                        ));
+                    }
+                    let (l, next) = parse_loop_back(next)?;
+                    loop_back = Some(l);
+                    rest = skip_punct(next, ',')?;
+                }
+                "WINDOW" => {
+                    if window.is_some() {
+                        return Err(syn::Error::new(
+                            rest.span(),
+                            "multiple WINDOWs aren't allowed",
+                        ));
+                    }
+                    let (w, next) = parse_window(next)?;
+                    window = Some(w);
+                    rest = skip_punct(next, ',')?;
+                }
+                "CANCEL" | "TIMEOUT" => {
+                    let attr = ident.to_string();
+                    let target = if attr == "CANCEL" {
+                        &mut cancel
+                    } else {
+                        &mut timeout
+                    };
+                    if target.is_some() {
+                        return Err(syn::Error::new(
+                            rest.span(),
+                            format!("multiple {attr}s aren't allowed"),
                         ));
                     }
-                    replicate = Replicate::SeqOrdered;
+                    let (expr, next) = parse_expr(next, &attr)?;
+                    *target = Some(expr);
                     rest = skip_punct(next, ',')?;
                 }
 
@@ -444,11 +1600,18 @@ fn parse_spar_args(cursor: Cursor) -> Result<(SparAttrs, Cursor, Cursor)> {
                         "unexpected token after code block",
                     ));
                 }
-                return Ok((
-                    SparAttrs::new(input, output, replicate),
-                    after,
-                    group_cursor,
-                ));
+                let mut attrs = SparAttrs::new(input, output, replicate);
+                attrs.shared = shared;
+                attrs.output_type = output_type;
+                attrs.lazy = lazy;
+                attrs.detached = detached;
+                attrs.cancel = cancel;
+                attrs.timeout = timeout;
+                attrs.nested = nested;
+                attrs.name = name;
+                attrs.loop_back = loop_back;
+                attrs.window = window;
+                return Ok((attrs, after, group_cursor));
             }
 
             _ => {
@@ -464,90 +1627,358 @@ fn parse_spar_args(cursor: Cursor) -> Result<(SparAttrs, Cursor, Cursor)> {
     ))
 }
 
-fn parse_spar_stages(cursor: Cursor) -> Result<(Vec<SparStage>, TokenStream)> {
-    let mut stages = Vec::new();
-    let mut code_stack = vec![TokenStream::new()];
+/// The marker that replaces the dispatch point of the branch that starts with
+/// stage `id`, where the codegen posts its items to the pipeline
+pub fn dispatch_marker(id: u32) -> Ident {
+    Ident::new(&format!("__SPAR_MARKER_{id}__"), Span::call_site())
+}
 
-    let mut groups = Vec::new();
-    let mut rest = cursor;
-    loop {
-        while let Some((token_tree, next)) = rest.token_tree() {
-            match &token_tree {
-                TokenTree::Ident(ident) if ident == "STAGE" => {
-                    groups.clear();
-                    if !code_stack.iter().all(|code| code.is_empty()) {
-                        code_stack
-                            .last_mut()
-                            .unwrap()
-                            .extend(quote! {__SPAR_MARKER__});
-                    }
+/// Parses a STAGE or a SPLIT. Returns it, what follows it, and its code
+fn parse_stage<'a>(
+    ident: &Ident,
+    next: Cursor<'a>,
+    id: u32,
+) -> Result<(SparStage, Cursor<'a>, Cursor<'a>)> {
+    let (attrs, rest, code_cursor) = parse_spar_args(next)?;
+    if attrs.output_type.is_some() {
+        return Err(syn::Error::new(
+            ident.span(),
+            "the OUTPUT of a stage names the variables it sends, as in OUTPUT(name: Type). Only a stream can declare the type of the items it returns, as in OUTPUT(Type)",
+        ));
+    }
+    if attrs.lazy {
+        return Err(syn::Error::new(ident.span(), "only a stream can be LAZY"));
+    }
+    if attrs.detached {
+        return Err(syn::Error::new(
+            ident.span(),
+            "only a stream can be DETACHED",
+        ));
+    }
+    if attrs.cancel.is_some() || attrs.timeout.is_some() {
+        return Err(syn::Error::new(
+            ident.span(),
+            "only a stream can have a CANCEL or TIMEOUT",
+        ));
+    }
+    if attrs.nested {
+        return Err(syn::Error::new(ident.span(), "only a stream can be NESTED"));
+    }
+    if let Some(var) = attrs.shared.first() {
+        return Err(syn::Error::new(
+            var.identifier.span(),
+            "only a stream can have SHARED inputs",
+        ));
+    }
+    if let (true, Some(looped)) = (ident == "SPLIT", &attrs.loop_back) {
+        return Err(syn::Error::new(
+            looped.to.span(),
+            "a SPLIT dispatches its items to the stages in its code, and cannot LOOP_BACK",
+        ));
+    }
+    if let (true, Some(_)) = (ident == "SPLIT", &attrs.window) {
+        return Err(syn::Error::new(
+            ident.span(),
+            "a SPLIT dispatches its items one at a time, and cannot have a WINDOW",
+        ));
+    }
+    if let (true, Some(var)) = (ident == "SPLIT", attrs.output.first()) {
+        return Err(syn::Error::new(
+            var.identifier.span(),
+            "a SPLIT sends its items to the stages in its code, and cannot declare an OUTPUT",
+        ));
+    }
+    let mut stage = SparStage::new(attrs, code_cursor.token_stream(), id);
+    stage.span = ident.span();
+    stage.is_split = ident == "SPLIT";
+    if !stage.is_split {
+        parse_nested_stages(&mut stage, code_cursor)?;
+    }
+    Ok((stage, rest, code_cursor))
+}
 
-                    while code_stack.len() > 1 {
-                        let code = code_stack.pop().unwrap();
-                        code_stack.last_mut().unwrap().extend(
-                            TokenTree::Group(Group::new(Delimiter::Brace, code))
-                                .into_token_stream(),
-                        );
-                    }
-                    break;
-                }
+/// A stage whose code is a chain of STAGEs runs them one after the other, in each
+/// of its replicas. Every stage of the chain takes its INPUT from the INPUT of the
+/// stage, or from the OUTPUT of an earlier stage of the chain, and its code becomes
+/// a block that binds its OUTPUT
+fn parse_nested_stages(stage: &mut SparStage, code: Cursor) -> Result<()> {
+    let has_stages = stage.code.clone().into_iter().any(
+        |token| matches!(&token, TokenTree::Ident(ident) if ident == "STAGE" || ident == "SPLIT"),
+    );
+    if !has_stages {
+        return Ok(());
+    }
 
-                TokenTree::Group(group) if group.delimiter() == Delimiter::Brace => {
-                    let (group_cursor, _, next) = rest.group(group.delimiter()).unwrap();
-                    code_stack.push(TokenStream::new());
-                    rest = group_cursor;
-                    groups.push(next);
-                }
+    let mut nested = Vec::new();
+    let (rest, _) = parse_chain(code, &mut nested, Vec::new(), false, true)?;
+    if nested.is_empty() || rest.token_tree().is_some() {
+        return Err(syn::Error::new(
+            stage.span,
+            format!(
+                "the code of stage {} has stages in it, which every replica runs one after the other, so it can only be a chain of STAGEs",
+                stage.id
+            ),
+        ));
+    }
 
-                _ => {
-                    token_tree.to_tokens(code_stack.last_mut().unwrap());
-                    rest = next;
+    let mut available: Vec<Ident> = stage
+        .attrs
+        .input
+        .iter()
+        .map(|var| var.identifier.clone())
+        .collect();
+    let mut code = TokenStream::new();
+    for sub in &nested {
+        if sub.is_split {
+            return Err(syn::Error::new(
+                sub.span,
+                format!("a SPLIT cannot run inside stage {}", stage.id),
+            ));
+        }
+        if let Some(looped) = &sub.attrs.loop_back {
+            return Err(syn::Error::new(
+                looped.to.span(),
+                format!(
+                    "a stage nested in stage {} runs as part of it, and cannot LOOP_BACK. Stage {} can",
+                    stage.id, stage.id
+                ),
+            ));
+        }
+        if sub.attrs.window.is_some() {
+            return Err(syn::Error::new(
+                sub.span,
+                format!(
+                    "a stage nested in stage {} runs once for every item of it, and cannot have a WINDOW. Stage {} can",
+                    stage.id, stage.id
+                ),
+            ));
+        }
+        if !matches!(sub.attrs.replicate, Replicate::SeqUnordered) {
+            return Err(syn::Error::new(
+                sub.span,
+                format!(
+                    "a stage nested in stage {} runs on the thread of each of its replicas, so it cannot be REPLICATE or ORDERED. A `to_stream!` in its code runs a nested stream",
+                    stage.id
+                ),
+            ));
+        }
+        if let Some(var) = sub
+            .attrs
+            .input
+            .iter()
+            .find(|var| !available.contains(&var.identifier))
+        {
+            return Err(syn::Error::new(
+                var.identifier.span(),
+                format!(
+                    "`{}` must be an INPUT of stage {}, or an OUTPUT of a stage nested in it before this one",
+                    var.identifier, stage.id
+                ),
+            ));
+        }
+
+        let sub_code = &sub.code;
+        let outputs = &sub.attrs.output;
+        code.extend(match outputs.as_slice() {
+            [] => quote! {
+                {
+                    #sub_code
+                }
+            },
+            [var] => {
+                let (ident, var_type) = (&var.identifier, &var.var_type);
+                quote! {
+                    #[allow(unused_mut)]
+                    let mut #ident: #var_type = {
+                        #sub_code
+                        #ident
+                    };
                 }
             }
-        }
-        if let Some(cursor) = groups.pop() {
-            rest = cursor;
-            let code = code_stack.pop().unwrap();
-            code_stack
-                .last_mut()
-                .unwrap()
-                .extend(TokenTree::Group(Group::new(Delimiter::Brace, code)).into_token_stream());
-        } else {
+            _ => {
+                let idents: Vec<&Ident> = outputs.iter().map(|var| &var.identifier).collect();
+                let types: Vec<&VarType> = outputs.iter().map(|var| &var.var_type).collect();
+                quote! {
+                    #[allow(unused_mut)]
+                    let (#(mut #idents),*): (#(#types),*) = {
+                        #sub_code
+                        (#(#idents),*)
+                    };
+                }
+            }
+        });
+        available.extend(outputs.iter().map(|var| var.identifier.clone()));
+    }
+
+    if let Some(var) = stage
+        .attrs
+        .output
+        .iter()
+        .find(|var| !available.contains(&var.identifier))
+    {
+        return Err(syn::Error::new(
+            var.identifier.span(),
+            format!(
+                "stage {} declares OUTPUT({var}), but neither its INPUT nor the stages nested in it send `{}`",
+                stage.id, var.identifier
+            ),
+        ));
+    }
+
+    stage.code = code;
+    stage.nested = nested;
+    Ok(())
+}
+
+/// Parses a chain of stages, the STAGEs (or SPLITs) that follow each other
+/// separated by ';', which starts at `rest`. Each stage receives from the one before
+/// it, and the first one from `prev`. The stage after a SPLIT merges the branches in
+/// it. A chain ends at the code after it, at the end of its block, or at the ','
+/// after it, if it is a match arm. Returns what follows the chain, and the ids of
+/// the stages it ends with
+fn parse_chain<'a>(
+    mut rest: Cursor<'a>,
+    stages: &mut Vec<SparStage>,
+    mut prev: Vec<u32>,
+    is_arm: bool,
+    is_nested: bool,
+) -> Result<(Cursor<'a>, Vec<u32>)> {
+    while let Some((ident, next)) = rest.ident() {
+        if ident != "STAGE" && ident != "SPLIT" {
             break;
         }
+        let (mut stage, after, code) = parse_stage(&ident, next, stages.len() as u32 + 1)?;
+        let id = stage.id;
+        stage.prev = std::mem::replace(&mut prev, vec![id]);
+        stages.push(stage);
+        if ident == "SPLIT" {
+            let (code, branches) = parse_block(code, stages, true, true, false, id)?;
+            if branches.is_empty() {
+                return Err(syn::Error::new(
+                    ident.span(),
+                    "a SPLIT sends its items to the stages in its code, and must have at least one",
+                ));
+            }
+            stages[id as usize - 1].code = code;
+            prev = branches;
+        }
+
+        rest = match after.token_tree() {
+            Some((TokenTree::Punct(punct), next)) if punct.as_char() == ';' => next,
+            Some((TokenTree::Punct(punct), next)) if punct.as_char() == ',' && is_arm => {
+                return Ok((next, prev))
+            }
+            None if is_nested => return Ok((after, prev)),
+            _ => return Err(syn::Error::new(after.span(), "expected ';'")),
+        };
     }
+    Ok((rest, prev))
+}
 
+/// Parses the code of a group and the chains of stages in it. A chain either
+/// starts a branch, which the code dispatches items to at its marker, or merges the
+/// branches that end before it in the same block, such as the arms of a `match`.
+/// The code after a chain runs once the item was dispatched. Returns the code, and
+/// the ids of the last stages of the branches that no stage merges yet
+fn parse_block(
+    cursor: Cursor,
+    stages: &mut Vec<SparStage>,
+    has_code: bool,
+    is_nested: bool,
+    in_closure: bool,
+    dispatcher: u32,
+) -> Result<(TokenStream, Vec<u32>)> {
+    let mut code = TokenStream::new();
+    // the branches that end before, which the next chain merges
+    let mut unmerged = Vec::new();
+    // the branches of the match arms without braces, which no chain merges here
+    let mut arms = Vec::new();
+    // whether the last tokens are '=>', before the code of a match arm
+    let (mut after_eq, mut after_arrow) = (false, false);
+    // whether the last token is '|', before the body of a closure
+    let mut after_bar = false;
+    // a chain at the start of the stream, without code before it to dispatch to it,
+    // cannot have code after it either
+    let mut undispatched = false;
+
+    let mut rest = cursor;
     while let Some((token_tree, next)) = rest.token_tree() {
         match &token_tree {
-            TokenTree::Ident(ident) if *ident == "STAGE" => {
-                let (attrs, semicolon, code_cursor) = parse_spar_args(next)?;
-                stages.push(SparStage::new(
-                    attrs,
-                    code_cursor.token_stream(),
-                    stages.len() as u32 + 1,
-                ));
-
-                match semicolon.token_tree() {
-                    Some((token, next)) => match token {
-                        TokenTree::Punct(punct) if punct.as_char() == ';' => {
-                            rest = next;
-                        }
-                        _ => return Err(syn::Error::new(next.span(), "expected ';'")),
-                    },
-                    None => return Err(syn::Error::new(next.span(), "expected ';'")),
+            TokenTree::Ident(ident) if ident == "STAGE" || ident == "SPLIT" => {
+                let prev = if unmerged.is_empty() || after_arrow || after_bar {
+                    if has_code || !code.is_empty() {
+                        code.extend(dispatch_marker(stages.len() as u32 + 1).into_token_stream());
+                    } else {
+                        undispatched = true;
+                    }
+                    vec![dispatcher]
+                } else {
+                    std::mem::take(&mut unmerged)
+                };
+                let first = stages.len();
+                let (next, ends) = parse_chain(rest, stages, prev, after_arrow, is_nested)?;
+                rest = next;
+                stages[first].in_closure = in_closure || after_bar;
+                if after_arrow {
+                    arms.extend(ends);
+                    code.extend(quote! {,});
+                } else {
+                    unmerged.extend(ends);
                 }
+                (after_eq, after_arrow, after_bar) = (false, false, false);
             }
 
-            _ => {
+            _ if undispatched => {
                 return Err(syn::Error::new(
-                    next.span(),
+                    token_tree.span(),
                     format!("expected 'STAGE' or END OF STREAM, found {token_tree}"),
                 ))
             }
+
+            TokenTree::Group(group) => {
+                let (group_cursor, _, next) = rest.group(group.delimiter()).unwrap();
+                let is_closure = in_closure || (after_bar && group.delimiter() == Delimiter::Brace);
+                let (group_code, branches) = parse_block(
+                    group_cursor,
+                    stages,
+                    has_code || !code.is_empty(),
+                    true,
+                    is_closure,
+                    dispatcher,
+                )?;
+                let mut rebuilt = Group::new(group.delimiter(), group_code);
+                rebuilt.set_span(group.span());
+                rebuilt.to_tokens(&mut code);
+                unmerged.extend(branches);
+                (after_eq, after_arrow, after_bar) = (false, false, false);
+                rest = next;
+            }
+
+            _ => {
+                (after_eq, after_arrow, after_bar) = match &token_tree {
+                    TokenTree::Punct(punct) => (
+                        punct.as_char() == '=' && punct.spacing() == Spacing::Joint,
+                        punct.as_char() == '>' && after_eq,
+                        punct.as_char() == '|',
+                    ),
+                    _ => (false, false, false),
+                };
+                token_tree.to_tokens(&mut code);
+                rest = next;
+            }
         }
     }
 
-    Ok((stages, code_stack.pop().unwrap()))
+    // in the order of the stages, which is the order of the code
+    unmerged.extend(arms);
+    unmerged.sort_unstable();
+    Ok((code, unmerged))
+}
+
+fn parse_spar_stages(cursor: Cursor) -> Result<(Vec<SparStage>, TokenStream)> {
+    let mut stages = Vec::new();
+    let (code, _) = parse_block(cursor, &mut stages, false, false, false, 0)?;
+    Ok((stages, code))
 }
 
 #[cfg(test)]
@@ -563,8 +1994,7 @@ mod tests {
         let cursor = buffer.begin();
 
         let mut groups = vec![cursor];
-        while !groups.is_empty() {
-            let mut rest = groups.pop().unwrap();
+        while let Some(mut rest) = groups.pop() {
             while let Some((token_tree, next)) = rest.token_tree() {
                 match &token_tree {
                     TokenTree::Ident(ident) if *ident == identifier => {
@@ -623,7 +2053,7 @@ mod tests {
             let mut a = 10;
             while true {
                 a += 1;
-                __SPAR_MARKER__
+                __SPAR_MARKER_1__
             }
         };
 
@@ -631,6 +2061,23 @@ mod tests {
         assert_eq!(expected_tokens.to_string(), tokens.to_string());
     }
 
+    #[test]
+    fn generic_types() {
+        let args = quote! {
+            (a: HashMap<u32, Vec<(u8, u8)>>, b: Box<dyn Fn(u32, u32) -> u32>, c: u32)
+        };
+        let (vars, _) = get_variables(TokenBuffer::new2(args).begin()).unwrap();
+        let types: Vec<String> = vars.iter().map(|var| var.var_type.to_string()).collect();
+        assert_eq!(
+            types,
+            [
+                "HashMap<u32, Vec<(u8, u8)>>",
+                "Box<dyn Fn (u32, u32) -> u32>",
+                "u32"
+            ]
+        );
+    }
+
     #[test]
     fn stage_no_attributes() {
         let tokens = quote! {
@@ -859,6 +2306,949 @@ mod tests {
         );
     }
 
+    #[test]
+    fn handoff_matches_identifiers() {
+        let spar_stream = SparStream::try_from(quote! {
+            {
+                for n in 0..10 {
+                    let i = n;
+                    STAGE(INPUT(i: u32), OUTPUT(i: std::primitive::u32), {});
+                    STAGE(INPUT(i: u32), {});
+                }
+            }
+        })
+        .unwrap();
+
+        let stage = &spar_stream.stages[2];
+        assert!(stage.state.is_empty());
+        assert_eq!(stage.attrs.input[0].to_string(), "i: std::primitive::u32");
+
+        let handoff = &spar_stream.handoffs[0];
+        assert_eq!((handoff.from, handoff.to), (1, 2));
+        assert_eq!(handoff.output.to_string(), "i: std::primitive::u32");
+        assert_eq!(handoff.input.to_string(), "i: u32");
+    }
+
+    #[test]
+    fn forwarding() {
+        let spar_stream = SparStream::try_from(quote! {
+            {
+                for n in 0..10 {
+                    let a = n;
+                    STAGE(INPUT(a: u32), OUTPUT(a: u32, b: u32), {});
+                    STAGE(INPUT(a: u32), OUTPUT(a: u32), {});
+                    STAGE(INPUT(a: u32), OUTPUT(a: u32), {});
+                    STAGE(INPUT(b: u32, a: u32), {});
+                }
+            }
+        })
+        .unwrap();
+
+        let forwarded: Vec<Vec<String>> = spar_stream
+            .stages
+            .iter()
+            .map(|stage| stage.forwarded.iter().map(|var| var.to_string()).collect())
+            .collect();
+        assert_eq!(
+            forwarded,
+            [vec![], vec![], vec!["b: u32"], vec!["b: u32"], vec![]]
+        );
+        assert!(spar_stream
+            .stages
+            .iter()
+            .all(|stage| stage.state.is_empty()));
+
+        let error = SparStream::try_from(quote! {
+            {
+                for n in 0..10 {
+                    let a = n;
+                    STAGE(INPUT(a: u32), OUTPUT(b: u32), {});
+                    STAGE(INPUT(b: u32), OUTPUT(c: u32), {});
+                    STAGE(INPUT(c: u32, a: u32), {});
+                }
+            }
+        })
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "`a` cannot be sent to this stage, since stage 1 receives it without sending it as OUTPUT"
+        );
+    }
+
+    #[test]
+    fn branches() {
+        let spar_stream = SparStream::try_from(quote! {
+            {
+                for msg in msgs {
+                    match msg {
+                        Msg::A(a) => STAGE(INPUT(a: u32), OUTPUT(c: u64), {}),
+                        Msg::B(b) => {
+                            let d = 1;
+                            STAGE(INPUT(b: String, d: u8), OUTPUT(c: u64, d: u8), {});
+                            STAGE(INPUT(d: u8), OUTPUT(c: u64), {});
+                        }
+                    }
+                    STAGE(INPUT(c: u64), {});
+                }
+                let e = 2;
+            }
+        })
+        .unwrap();
+        assert!(spar_stream.is_branched());
+
+        let prev: Vec<Vec<u32>> = spar_stream
+            .stages
+            .iter()
+            .map(|stage| stage.prev.clone())
+            .collect();
+        assert_eq!(prev, [vec![], vec![0], vec![0], vec![2], vec![1, 3]]);
+        let outputs: Vec<Vec<String>> = spar_stream
+            .stages
+            .iter()
+            .map(|stage| {
+                stage
+                    .attrs
+                    .output
+                    .iter()
+                    .map(|var| var.to_string())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(outputs[0], ["a: u32", "b: String", "d: u8"]);
+        assert_eq!(spar_stream.stages[3].forwarded, []);
+
+        // every branch has its own marker, and the code after the stages is kept
+        let code = spar_stream.stages[0].code.to_string();
+        assert!(code.contains("Msg :: A (a) => __SPAR_MARKER_1__ ,"));
+        assert!(code.contains("let d = 1 ; __SPAR_MARKER_2__"));
+        assert!(code.ends_with("let e = 2 ;"));
+
+        let linear = SparStream::try_from(quote! {
+            {
+                for n in 0..10 {
+                    if n > 5 {
+                        STAGE(INPUT(n: u32), OUTPUT(n: u32), {});
+                    }
+                    STAGE(INPUT(n: u32), {});
+                }
+            }
+        })
+        .unwrap();
+        assert!(!linear.is_branched());
+
+        let errors = [
+            (
+                quote! {{
+                    for n in 0..10 {
+                        if n > 5 {
+                            STAGE(INPUT(n: u32), OUTPUT(c: u32), {});
+                        } else {
+                            let m = n;
+                            STAGE(INPUT(m: u32), OUTPUT(m: u32), {});
+                        }
+                        STAGE(INPUT(c: u32), {});
+                    }
+                }},
+                "`c` must be sent to stage 3 by every branch it merges, but the branch that ends with stage 2 doesn't send it",
+            ),
+            (
+                quote! {{
+                    for n in 0..10 {
+                        if n > 5 {
+                            STAGE(INPUT(n: u32), OUTPUT(n: u32), {});
+                        } else {
+                            STAGE(INPUT(n: u32), {});
+                        }
+                    }
+                }},
+                "stage 1 ends a branch that no later stage merges, so it cannot declare an OUTPUT",
+            ),
+            (
+                quote! {OUTPUT(u32), {
+                    for n in 0..10 {
+                        if n > 5 {
+                            STAGE(INPUT(n: u32), OUTPUT(n: u32), {});
+                        } else {
+                            STAGE(INPUT(n: u32), OUTPUT(n: u32), {});
+                        }
+                    }
+                }},
+                "stage 1 ends a branch that no later stage merges, but the stream returns the items of its last stage, which every branch must reach",
+            ),
+            (
+                quote! {{
+                    STAGE(OUTPUT(n: u32), { 0..10 });
+                    STAGE(INPUT(n: u32), {});
+                    println!("done");
+                }},
+                "expected 'STAGE' or END OF STREAM, found println",
+            ),
+        ];
+
+        for (tokens, message) in errors {
+            match SparStream::try_from(tokens) {
+                Ok(_) => panic!("expected error: {message}"),
+                Err(e) => assert_eq!(e.to_string(), message),
+            }
+        }
+    }
+
+    #[test]
+    fn closures() {
+        let spar_stream = SparStream::try_from(quote! {
+            {
+                lines.try_for_each(|line| {
+                    let line = line?;
+                    STAGE(INPUT(line: String), OUTPUT(line: String), {});
+                    STAGE(INPUT(line: String), {});
+                    Ok(())
+                })?;
+                (0..10).for_each(move |n| STAGE(INPUT(n: u32), {}));
+            }
+        })
+        .unwrap();
+
+        let in_closure: Vec<bool> = spar_stream
+            .stages
+            .iter()
+            .map(|stage| stage.in_closure)
+            .collect();
+        assert_eq!(in_closure, [false, true, false, true]);
+        let outputs: Vec<String> = spar_stream.stages[0]
+            .attrs
+            .output
+            .iter()
+            .map(|var| var.to_string())
+            .collect();
+        assert_eq!(outputs, ["line: String", "n: u32"]);
+
+        // the code after a chain runs once the item was dispatched
+        let code = spar_stream.stages[0].code.to_string();
+        assert!(code.contains("__SPAR_MARKER_1__ Ok (())"));
+        assert!(code.contains("for_each (move | n | __SPAR_MARKER_3__)"));
+    }
+
+    #[test]
+    fn splits() {
+        let spar_stream = SparStream::try_from(quote! {
+            {
+                for n in 0..10 {
+                    STAGE(INPUT(n: u32), OUTPUT(n: u32), {});
+                    SPLIT(INPUT(n: u32), {
+                        let half = n / 2;
+                        if n % 2 == 0 {
+                            STAGE(INPUT(half: u32), OUTPUT(text: String), {});
+                        } else {
+                            { STAGE(INPUT(n: u32), OUTPUT(n: u32, text: String), {}); }
+                            { STAGE(INPUT(n: u32), OUTPUT(n: u32, text: String), {}); }
+                        }
+                    });
+                    STAGE(INPUT(n: u32, text: String), {});
+                }
+            }
+        })
+        .unwrap();
+        assert!(spar_stream.is_branched());
+
+        let prev: Vec<Vec<u32>> = spar_stream
+            .stages
+            .iter()
+            .map(|stage| stage.prev.clone())
+            .collect();
+        assert_eq!(
+            prev,
+            [
+                vec![],
+                vec![0],
+                vec![1],
+                vec![2],
+                vec![2],
+                vec![2],
+                vec![3, 4, 5]
+            ]
+        );
+        let split = &spar_stream.stages[2];
+        assert!(split.is_split);
+        let outputs: Vec<String> = split
+            .attrs
+            .output
+            .iter()
+            .map(|var| var.to_string())
+            .collect();
+        assert_eq!(outputs, ["n: u32", "half: u32"]);
+        // the branch that doesn't receive `n` forwards it to the merge
+        let forwarded: Vec<String> = spar_stream.stages[3]
+            .forwarded
+            .iter()
+            .map(|var| var.to_string())
+            .collect();
+        assert_eq!(forwarded, ["n: u32"]);
+        let code = split.code.to_string();
+        assert!(code.contains("{ __SPAR_MARKER_4__ } { __SPAR_MARKER_5__ }"));
+
+        let errors = [
+            (
+                quote! {{
+                    for n in 0..10 {
+                        SPLIT(INPUT(n: u32), OUTPUT(n: u32), {
+                            STAGE(INPUT(n: u32), {});
+                        });
+                    }
+                }},
+                "a SPLIT sends its items to the stages in its code, and cannot declare an OUTPUT",
+            ),
+            (
+                quote! {{
+                    for n in 0..10 {
+                        SPLIT(INPUT(n: u32), {});
+                    }
+                }},
+                "a SPLIT sends its items to the stages in its code, and must have at least one",
+            ),
+            (
+                quote! {{
+                    SPLIT(INPUT(n: u32), {
+                        STAGE(INPUT(n: u32), {});
+                    });
+                }},
+                "a SPLIT dispatches the items it receives, so it cannot be the first stage of a stream without code before it",
+            ),
+        ];
+
+        for (tokens, message) in errors {
+            match SparStream::try_from(tokens) {
+                Ok(_) => panic!("expected error: {message}"),
+                Err(e) => assert_eq!(e.to_string(), message),
+            }
+        }
+    }
+
+    #[test]
+    fn nested_stages() {
+        let spar_stream = SparStream::try_from(quote! {
+            {
+                for frame in frames {
+                    STAGE(INPUT(frame: Frame), OUTPUT(frame: Frame, edges: u32), REPLICATE = 4, {
+                        STAGE(INPUT(frame: Frame), OUTPUT(gray: Frame), { let gray = frame.gray(); });
+                        STAGE(INPUT(gray: Frame), OUTPUT(edges: u32), { let edges = gray.edges(); });
+                    });
+                    STAGE(INPUT(frame: Frame, edges: u32), {});
+                }
+            }
+        })
+        .unwrap();
+        let stage = &spar_stream.stages[1];
+        assert_eq!(spar_stream.stages.len(), 3);
+        assert_eq!(stage.nested.len(), 2);
+        // each nested stage becomes a block that binds its OUTPUT
+        let code = stage.code.to_string();
+        assert!(code.contains("let mut gray : Frame = { let gray = frame . gray () ; gray } ;"));
+        assert!(code.contains("let mut edges : u32 = { let edges = gray . edges () ; edges } ;"));
+
+        let errors = [
+            (
+                quote! {{
+                    for n in 0..10 {
+                        STAGE(INPUT(n: u32), OUTPUT(n: u32), {
+                            let m = n;
+                            STAGE(INPUT(m: u32), OUTPUT(n: u32), {});
+                        });
+                        STAGE(INPUT(n: u32), {});
+                    }
+                }},
+                "the code of stage 1 has stages in it, which every replica runs one after the other, so it can only be a chain of STAGEs",
+            ),
+            (
+                quote! {{
+                    for n in 0..10 {
+                        STAGE(INPUT(n: u32), OUTPUT(n: u32), REPLICATE = 2, {
+                            STAGE(INPUT(n: u32), OUTPUT(n: u32), REPLICATE = 2, {});
+                        });
+                        STAGE(INPUT(n: u32), {});
+                    }
+                }},
+                "a stage nested in stage 1 runs on the thread of each of its replicas, so it cannot be REPLICATE or ORDERED. A `to_stream!` in its code runs a nested stream",
+            ),
+            (
+                quote! {{
+                    for n in 0..10 {
+                        STAGE(INPUT(n: u32), OUTPUT(n: u32), {
+                            STAGE(INPUT(m: u32), OUTPUT(n: u32), {});
+                        });
+                        STAGE(INPUT(n: u32), {});
+                    }
+                }},
+                "`m` must be an INPUT of stage 1, or an OUTPUT of a stage nested in it before this one",
+            ),
+            (
+                quote! {{
+                    for n in 0..10 {
+                        STAGE(INPUT(n: u32), OUTPUT(m: u32), {
+                            STAGE(INPUT(n: u32), OUTPUT(k: u32), {});
+                        });
+                        STAGE(INPUT(m: u32), {});
+                    }
+                }},
+                "stage 1 declares OUTPUT(m: u32), but neither its INPUT nor the stages nested in it send `m`",
+            ),
+        ];
+
+        for (tokens, message) in errors {
+            match SparStream::try_from(tokens) {
+                Ok(_) => panic!("expected error: {message}"),
+                Err(e) => assert_eq!(e.to_string(), message),
+            }
+        }
+    }
+
+    #[test]
+    fn loop_back() {
+        let spar_stream = SparStream::try_from(quote! {{
+            for id in 0..10 {
+                let x = id;
+                STAGE(INPUT(x: u32), OUTPUT(x: u32), NAME = grow, { x += 1; });
+                STAGE(INPUT(x: u32), OUTPUT(x: u32), LOOP_BACK(while = x < 50, to = grow), { x *= 2; });
+                STAGE(INPUT(id: u32, x: u32), {});
+            }
+        }})
+        .unwrap();
+        assert!(spar_stream.loops());
+        assert!(spar_stream.is_branched());
+        let looped = spar_stream.stages[2].attrs.loop_back.as_ref().unwrap();
+        assert_eq!(looped.target, 1);
+        assert_eq!(looped.condition.0.to_string(), "x < 50");
+        assert_eq!(
+            spar_stream.loop_target(&spar_stream.stages[2]).unwrap().id,
+            1
+        );
+
+        let errors = [
+            (
+                quote! { NAME = stream, { let a = 1; STAGE(INPUT(a: u32), {}); } },
+                "only a stage can have a NAME or a LOOP_BACK",
+            ),
+            (
+                quote! {{ let a = 1; STAGE(INPUT(a: u32), LOOP_BACK(to = first), {}); }},
+                "a LOOP_BACK takes the NAME of the stage it sends its items back to, and while to send them, as in 'LOOP_BACK(to = name, while = condition)'",
+            ),
+            (
+                quote! {{ let a = 1; STAGE(INPUT(a: u32), LOOP_BACK(to = first, while = true), {}); }},
+                "no stage is named `first`",
+            ),
+            (
+                quote! {{
+                    let a = 1;
+                    STAGE(INPUT(a: u32), OUTPUT(a: u32), NAME = first, {});
+                    STAGE(INPUT(a: u32), NAME = first, {});
+                }},
+                "more than one stage is named `first`",
+            ),
+            (
+                quote! {{
+                    let a = 1;
+                    STAGE(INPUT(a: u32), OUTPUT(a: u32), LOOP_BACK(to = last, while = a > 1), {});
+                    STAGE(INPUT(a: u32), NAME = last, {});
+                }},
+                "stage 1 can only loop back to itself or to an earlier stage, but `last` is stage 2",
+            ),
+            (
+                quote! {{
+                    let a = 1;
+                    let b = 2;
+                    STAGE(INPUT(a: u32, b: u32), OUTPUT(a: u32), NAME = first, {});
+                    STAGE(INPUT(a: u32), LOOP_BACK(to = first, while = a > 1), {});
+                }},
+                "stage 2 loops back to stage 1, which receives `b`, but stage 2 neither receives nor sends it",
+            ),
+            (
+                quote! {{
+                    for n in 0..10 {
+                        if n % 2 == 0 {
+                            STAGE(INPUT(n: u32), OUTPUT(n: u32), NAME = even, LOOP_BACK(to = even, while = n < 5), {});
+                        } else {
+                            STAGE(INPUT(n: u32), OUTPUT(n: u32), {});
+                        }
+                        STAGE(INPUT(n: u32), {});
+                    }
+                }},
+                "stage 1 loops back, so every item must go through every stage, and the stream cannot have branches or SPLITs",
+            ),
+            (
+                quote! {{
+                    STAGE(OUTPUT(a: u32), NAME = source, { 0..10 });
+                    STAGE(INPUT(a: u32), LOOP_BACK(to = source, while = a > 1), {});
+                }},
+                "`source` is a source stage, which produces the items of the stream, so no stage can loop back to it",
+            ),
+            (
+                quote! {{
+                    for n in 0..10 {
+                        STAGE(INPUT(n: u32), OUTPUT(n: u32), NAME = outer, {
+                            STAGE(INPUT(n: u32), OUTPUT(n: u32), LOOP_BACK(to = outer, while = n < 5), {});
+                        });
+                    }
+                }},
+                "a stage nested in stage 1 runs as part of it, and cannot LOOP_BACK. Stage 1 can",
+            ),
+        ];
+
+        for (tokens, message) in errors {
+            match SparStream::try_from(tokens) {
+                Ok(_) => panic!("expected error: {message}"),
+                Err(e) => assert_eq!(e.to_string(), message),
+            }
+        }
+    }
+
+    #[test]
+    fn windows() {
+        let spar_stream = SparStream::try_from(quote! {{
+            for n in 0..10 {
+                STAGE(INPUT(n: u32), OUTPUT(n: u32), REPLICATE = 2, {});
+                STAGE(INPUT(n: u32), OUTPUT(sum: u32), WINDOW(slide = 1, size = 3), {
+                    let sum = n.iter().sum();
+                });
+                STAGE(INPUT(sum: u32), WINDOW(size = 2), {});
+            }
+        }})
+        .unwrap();
+        assert!(spar_stream.has_windows());
+        assert!(spar_stream.is_branched());
+        let window = spar_stream.stages[2].attrs.window.as_ref().unwrap();
+        assert_eq!(window.size.0.to_string(), "3");
+        assert_eq!(window.slide.as_ref().unwrap().0.to_string(), "1");
+        assert!(spar_stream.stages[3]
+            .attrs
+            .window
+            .as_ref()
+            .unwrap()
+            .slide
+            .is_none());
+
+        // closures, nested functions and async blocks leave their own code early
+        SparStream::try_from(quote! {{
+            for n in 0..10 {
+                STAGE(INPUT(n: u32), OUTPUT(n: u32), {
+                    fn halve(n: u32) -> Option<u32> {
+                        if n % 2 == 1 {
+                            return None;
+                        }
+                        Some(n / 2)
+                    }
+                    let halves = (0..n).filter_map(|i| Some(halve(i)? + 1)).count();
+                    let checked = (|| n.checked_sub(1)?.checked_sub(1))();
+                    let later = async move { Some(halve(n)?) };
+                    let n = n + halves as u32;
+                });
+                STAGE(INPUT(n: u32), WINDOW(size = 2), {});
+            }
+        }})
+        .unwrap();
+
+        let errors = [
+            (
+                quote! { WINDOW(size = 2), { let a = 1; STAGE(INPUT(a: u32), {}); } },
+                "only a stage can have a WINDOW",
+            ),
+            (
+                quote! {{ let a = 1; STAGE(INPUT(a: u32), WINDOW(slide = 2), {}); }},
+                "a WINDOW takes how many items each window has, and optionally how many items apart they start, as in 'WINDOW(size = N, slide = M)'",
+            ),
+            (
+                quote! {{ let a = 1; STAGE(INPUT(a: u32), WINDOW(size = 2), REPLICATE = 2, {}); }},
+                "stage 1 runs on windows of consecutive items, so it cannot be replicated",
+            ),
+            (
+                quote! {{
+                    STAGE(OUTPUT(a: u32), WINDOW(size = 2), { 0..10 });
+                    STAGE(INPUT(a: u32), {});
+                }},
+                "a source stage produces the items of the stream, and cannot have a WINDOW",
+            ),
+            (
+                quote! {{
+                    let (a, b) = (1, 2);
+                    STAGE(INPUT(b: u32), OUTPUT(c: u32), WINDOW(size = 2), { let c = b.len() as u32; });
+                    STAGE(INPUT(a: u32, c: u32), {});
+                }},
+                "stage 1 runs once for every window of items, so `a` cannot go through it to a later stage. A later stage can only receive what it sends as OUTPUT",
+            ),
+            (
+                quote! {{
+                    let a = 1;
+                    STAGE(INPUT(a: u32), OUTPUT(a: u32), NAME = first, WINDOW(size = 2), { let a = a[0]; });
+                    STAGE(INPUT(a: u32), OUTPUT(a: u32), LOOP_BACK(to = first, while = a > 1), {});
+                    STAGE(INPUT(a: u32), {});
+                }},
+                "stage 1 has a WINDOW, so every item must reach it, and the stream cannot have branches, SPLITs or LOOP_BACKs",
+            ),
+            (
+                quote! {{
+                    for a in 0..10 {
+                        STAGE(INPUT(a: u32), OUTPUT(b: u32), { let b = (a == 0 || a.checked_sub(1)? > 2) as u32; });
+                        STAGE(INPUT(b: u32), WINDOW(size = 2), {});
+                    }
+                }},
+                "stage 2 has a WINDOW, so every item must reach it, and stage 1 cannot leave its code early, which would drop its item",
+            ),
+            (
+                quote! {{
+                    for a in 0..10 {
+                        STAGE(INPUT(a: u32), OUTPUT(a: u32), REPLICATE = 2, {
+                            if a % 2 == 0 {
+                                return None;
+                            }
+                        });
+                        STAGE(INPUT(a: u32), OUTPUT(a: u32), {});
+                        STAGE(INPUT(a: u32), WINDOW(size = 2), {});
+                    }
+                }},
+                "stage 3 has a WINDOW, so every item must reach it, and stage 1 cannot leave its code early, which would drop its item",
+            ),
+            (
+                quote! {{
+                    let a = 1;
+                    SPLIT(INPUT(a: u32), WINDOW(size = 2), {
+                        STAGE(INPUT(a: u32), {});
+                    });
+                }},
+                "a SPLIT dispatches its items one at a time, and cannot have a WINDOW",
+            ),
+            (
+                quote! {{
+                    for n in 0..10 {
+                        STAGE(INPUT(n: u32), {
+                            STAGE(INPUT(n: u32), WINDOW(size = 2), {});
+                        });
+                    }
+                }},
+                "a stage nested in stage 1 runs once for every item of it, and cannot have a WINDOW. Stage 1 can",
+            ),
+        ];
+
+        for (tokens, message) in errors {
+            match SparStream::try_from(tokens) {
+                Ok(_) => panic!("expected error: {message}"),
+                Err(e) => assert_eq!(e.to_string(), message),
+            }
+        }
+    }
+
+    #[test]
+    fn source_stage() {
+        let spar_stream = SparStream::try_from(quote! {
+            {
+                STAGE(OUTPUT(line: String), {
+                    std::io::stdin().lines().map(|line| line.unwrap())
+                });
+                STAGE(INPUT(line: String), {});
+            }
+        })
+        .unwrap();
+
+        assert_eq!(spar_stream.stages.len(), 2);
+        assert!(spar_stream.stages[0].is_source);
+        assert!(!spar_stream.stages[1].is_source);
+    }
+
+    #[test]
+    fn invalid_source_stages() {
+        let errors = [
+            (
+                quote! {{ STAGE({}); STAGE(INPUT(a: u32), {}); }},
+                "a source stage, without INPUT, must declare the OUTPUT of the items it produces",
+            ),
+            (
+                quote! {{ STAGE(OUTPUT(a: u32), REPLICATE = 2, {}); STAGE(INPUT(a: u32), {}); }},
+                "a source stage runs on the calling thread, and cannot be replicated",
+            ),
+            (
+                quote! {{ STAGE(OUTPUT(a: u32), {}); }},
+                "a source stage must be followed by a stage that receives its items",
+            ),
+        ];
+
+        for (tokens, message) in errors {
+            match SparStream::try_from(tokens) {
+                Ok(_) => panic!("expected error: {message}"),
+                Err(e) => assert_eq!(e.to_string(), message),
+            }
+        }
+    }
+
+    #[test]
+    fn stream_output_type() {
+        let spar_stream = SparStream::try_from(quote! {
+            OUTPUT(std::vec::Vec<u8>), {
+                for i in 0..10 {
+                    STAGE(INPUT(i: u8), OUTPUT(bytes: Vec<u8>), { let bytes = vec![i]; });
+                }
+            }
+        })
+        .unwrap();
+        let output_type = spar_stream.attrs.output_type.as_ref().unwrap();
+        assert_eq!(output_type.to_string(), "std::vec::Vec<u8>");
+        assert!(spar_stream.collects());
+        assert!(spar_stream.attrs.output.is_empty());
+
+        let errors = [
+            (
+                quote! { OUTPUT(a: u32), { let a = 1; STAGE(INPUT(a: u32), {}); } },
+                "the OUTPUT of a stream is the type of the items it returns, as in OUTPUT(Type)",
+            ),
+            (
+                quote! { OUTPUT(u32), { let a = 1; STAGE(INPUT(a: u32), {}); } },
+                "the stream returns the items of its last stage, which must declare them as OUTPUT",
+            ),
+            (
+                quote! {{ let a = 1; STAGE(INPUT(a: u32), OUTPUT(a: u32), {}); }},
+                "the last stage can only declare an OUTPUT if the stream returns its items, with OUTPUT(Type)",
+            ),
+            (
+                quote! {{ let a = 1; STAGE(INPUT(a: u32), OUTPUT(u32), {}); }},
+                "the OUTPUT of a stage names the variables it sends, as in OUTPUT(name: Type). Only a stream can declare the type of the items it returns, as in OUTPUT(Type)",
+            ),
+        ];
+
+        for (tokens, message) in errors {
+            match SparStream::try_from(tokens) {
+                Ok(_) => panic!("expected error: {message}"),
+                Err(e) => assert_eq!(e.to_string(), message),
+            }
+        }
+    }
+
+    #[test]
+    fn lazy_stream() {
+        let spar_stream = SparStream::try_from(quote! {
+            OUTPUT(u8), LAZY, {
+                for i in 0..10 {
+                    STAGE(INPUT(i: u8), OUTPUT(i: u8), {});
+                }
+            }
+        })
+        .unwrap();
+        assert!(spar_stream.attrs.lazy);
+
+        let errors = [
+            (
+                quote! { LAZY, { let a = 1; STAGE(INPUT(a: u32), {}); } },
+                "a LAZY stream returns an iterator over its items, whose type it must declare with OUTPUT(Type)",
+            ),
+            (
+                quote! { OUTPUT(u32), LAZY, { let a = 1; STAGE(INPUT(a: u32), OUTPUT(a: u32), LAZY, {}); } },
+                "only a stream can be LAZY",
+            ),
+        ];
+
+        for (tokens, message) in errors {
+            match SparStream::try_from(tokens) {
+                Ok(_) => panic!("expected error: {message}"),
+                Err(e) => assert_eq!(e.to_string(), message),
+            }
+        }
+    }
+
+    #[test]
+    fn detached_stream() {
+        let spar_stream = SparStream::try_from(quote! {
+            INPUT(total: u32), DETACHED, {
+                for i in 0..10 {
+                    STAGE(INPUT(i: u32, total: u32), { total += i; });
+                }
+            }
+        })
+        .unwrap();
+        assert!(spar_stream.attrs.detached && spar_stream.is_expression());
+        assert!(!spar_stream.in_background());
+
+        let errors = [
+            (
+                quote! { OUTPUT(u32), LAZY, DETACHED, { let a = 1; STAGE(INPUT(a: u32), OUTPUT(a: u32), {}); } },
+                "a LAZY stream already runs in the background, and cannot be DETACHED",
+            ),
+            (
+                quote! {{ let a = 1; STAGE(INPUT(a: u32), DETACHED, {}); }},
+                "only a stream can be DETACHED",
+            ),
+        ];
+
+        for (tokens, message) in errors {
+            match SparStream::try_from(tokens) {
+                Ok(_) => panic!("expected error: {message}"),
+                Err(e) => assert_eq!(e.to_string(), message),
+            }
+        }
+    }
+
+    #[test]
+    fn cancellable_stream() {
+        let spar_stream = SparStream::try_from(quote! {
+            CANCEL = tokens[0], TIMEOUT = Duration::from_millis(10, 20), {
+                for i in 0..10 {
+                    STAGE(INPUT(i: u32), {});
+                }
+            }
+        })
+        .unwrap();
+        assert!(spar_stream.is_cancellable() && spar_stream.is_expression());
+        assert_eq!(spar_stream.attrs.cancel, Some(SparExpr(quote!(tokens[0]))));
+        assert_eq!(
+            spar_stream.attrs.timeout,
+            Some(SparExpr(quote!(Duration::from_millis(10, 20))))
+        );
+
+        let errors = [
+            (
+                quote! { CANCEL = a, CANCEL = b, { let a = 1; STAGE(INPUT(a: u32), {}); } },
+                "multiple CANCELs aren't allowed",
+            ),
+            (
+                quote! { TIMEOUT = , { let a = 1; STAGE(INPUT(a: u32), {}); } },
+                "expected an expression after 'TIMEOUT ='",
+            ),
+            (
+                quote! { TIMEOUT duration, { let a = 1; STAGE(INPUT(a: u32), {}); } },
+                "expected '=' after TIMEOUT, as in 'TIMEOUT = expression'",
+            ),
+            (
+                quote! {{ let a = 1; STAGE(INPUT(a: u32), CANCEL = token, {}); }},
+                "only a stream can have a CANCEL or TIMEOUT",
+            ),
+            (
+                quote! { OUTPUT(u32), LAZY, TIMEOUT = d, { let a = 1; STAGE(INPUT(a: u32), OUTPUT(a: u32), {}); } },
+                "a LAZY stream is stopped by dropping its iterator, and cannot have a CANCEL or TIMEOUT",
+            ),
+        ];
+
+        for (tokens, message) in errors {
+            match SparStream::try_from(tokens) {
+                Ok(_) => panic!("expected error: {message}"),
+                Err(e) => assert_eq!(e.to_string(), message),
+            }
+        }
+    }
+
+    #[test]
+    fn shared_inputs() {
+        let spar_stream = SparStream::try_from(quote! {
+            INPUT(total: Vec<u32>), SHARED(names: Vec<String>), {
+                for n in 0..3 {
+                    let item = n;
+                    STAGE(INPUT(item: usize, names: Vec<String>, total: Vec<u32>), {
+                        total.push(names[item].len() as u32);
+                    });
+                }
+            }
+        })
+        .unwrap();
+        let inputs: Vec<String> = spar_stream
+            .attrs
+            .input
+            .iter()
+            .map(|var| var.identifier.to_string())
+            .collect();
+        assert_eq!(inputs, ["total", "names"]);
+        let restored: Vec<String> = spar_stream
+            .attrs
+            .output
+            .iter()
+            .map(|var| var.identifier.to_string())
+            .collect();
+        assert_eq!(restored, ["total"]);
+
+        let errors = [
+            (
+                quote! { INPUT(a: u32), SHARED(a: u32), { STAGE(INPUT(a: u32), {}); } },
+                "`a` is both an INPUT and SHARED",
+            ),
+            (
+                quote! {{ for n in 0..3 { STAGE(INPUT(n: u32), SHARED(a: u32), {}); } }},
+                "only a stream can have SHARED inputs",
+            ),
+        ];
+        for (tokens, message) in errors {
+            match SparStream::try_from(tokens) {
+                Ok(_) => panic!("expected error: {message}"),
+                Err(e) => assert_eq!(e.to_string(), message),
+            }
+        }
+    }
+
+    #[test]
+    fn pipeline() {
+        let spar_stream = SparStream::pipeline(quote! {
+            INPUT(offset: u32), OUTPUT(u32), {
+                STAGE(INPUT(a: u32, offset: u32, b: u8), OUTPUT(a: u32), { a += *offset + b as u32; });
+            }
+        })
+        .unwrap();
+        assert!(spar_stream.is_pipeline && spar_stream.in_background());
+        let source = &spar_stream.stages[0];
+        assert!(source.is_source);
+        assert_eq!(source.id, 0);
+        assert_eq!(
+            source.attrs.output,
+            vec![
+                SparVar::new(Ident::new("a", Span::call_site()), VarType(quote!(u32))),
+                SparVar::new(Ident::new("b", Span::call_site()), VarType(quote!(u8))),
+            ]
+        );
+        assert_eq!(spar_stream.stages[1].state.len(), 1);
+
+        let errors = [
+            (
+                quote! {{ STAGE(INPUT(a: u32), {}); }},
+                "a `spar_pipeline!` returns the results of the items posted to it, whose type it must declare with OUTPUT(Type)",
+            ),
+            (
+                quote! { OUTPUT(u32), LAZY, { STAGE(INPUT(a: u32), OUTPUT(a: u32), {}); } },
+                "a `spar_pipeline!` always runs in the background, and cannot be LAZY or DETACHED",
+            ),
+            (
+                quote! { OUTPUT(u32), { let a = 1; STAGE(INPUT(a: u32), OUTPUT(a: u32), {}); } },
+                "a `spar_pipeline!` only has stages: its items are posted to the handle it returns",
+            ),
+            (
+                quote! { INPUT(a: u32), OUTPUT(u32), { STAGE(INPUT(a: u32), OUTPUT(a: u32), {}); } },
+                "the first stage of a `spar_pipeline!` receives the items posted to it, which it must declare as INPUT",
+            ),
+            (
+                quote! { OUTPUT(u32), CANCEL = token, { STAGE(INPUT(a: u32), OUTPUT(a: u32), {}); } },
+                "a `spar_pipeline!` runs until it is shut down, and cannot have a CANCEL or TIMEOUT",
+            ),
+            (
+                quote! { OUTPUT(u32), {
+                    STAGE(INPUT(a: u32), OUTPUT(a: u32), {});
+                    SPLIT(INPUT(a: u32), { STAGE(INPUT(a: u32), OUTPUT(a: u32), {}); });
+                    STAGE(INPUT(a: u32), OUTPUT(a: u32), {});
+                } },
+                "a `spar_pipeline!` returns one result for every item posted to it, and cannot have a SPLIT",
+            ),
+            (
+                quote! { OUTPUT(u32), {
+                    STAGE(INPUT(a: u32), OUTPUT(a: u32), NAME = twice, {});
+                    STAGE(INPUT(a: u32), OUTPUT(a: u32), LOOP_BACK(to = twice, while = a < 10), {});
+                } },
+                "a `spar_pipeline!` returns one result for every item posted to it, and cannot LOOP_BACK",
+            ),
+            (
+                quote! { OUTPUT(u32), {
+                    STAGE(INPUT(a: u32), OUTPUT(a: u32), WINDOW(size = 2), { let a = a.len() as u32; });
+                } },
+                "a `spar_pipeline!` returns one result for every item posted to it, and cannot have a WINDOW",
+            ),
+        ];
+
+        for (tokens, message) in errors {
+            match SparStream::pipeline(tokens) {
+                Ok(_) => panic!("expected error: {message}"),
+                Err(e) => assert_eq!(e.to_string(), message),
+            }
+        }
+    }
+
     #[test]
     #[should_panic]
     fn input_cannot_be_a_literal() {
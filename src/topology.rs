@@ -0,0 +1,457 @@
+//! This module exports the topology of a parsed SparStream, as Graphviz DOT and JSON.
+//!
+//! The topology describes the pipeline after the parser resolved it: which
+//! variables are sent between stages, which inputs became the 'state' of a
+//! stage, and which external variables are restored at the end of the stream.
+//!
+//! If the `SPAR_TOPOLOGY_DIR` environment variable is set when the macro is
+//! expanded, both files are written to that directory.
+
+use std::path::Path;
+
+use crate::spar_stream::{Replicate, SparStage, SparStream, SparVar};
+
+fn stage_name(stage: &SparStage) -> String {
+    match &stage.attrs.name {
+        _ if stage.id == 0 => "dispatcher".to_owned(),
+        Some(name) => name.to_string(),
+        None => format!("SparStage{}", stage.id),
+    }
+}
+
+/// The dispatcher shares the stream's attributes, but it always runs sequentially on the caller's thread,
+/// like source stages
+fn stage_replicate(stage: &SparStage) -> &Replicate {
+    if stage.id == 0 || stage.is_source {
+        &Replicate::SeqUnordered
+    } else {
+        &stage.attrs.replicate
+    }
+}
+
+/// Every pair of stages where the first sends its items to the second. A branched
+/// stream has several edges from the dispatcher or a SPLIT, or to a stage that
+/// merges branches
+fn edges(spar_stream: &SparStream) -> Vec<(&SparStage, &SparStage)> {
+    let mut edges = Vec::new();
+    for stage in &spar_stream.stages {
+        for id in &stage.prev {
+            if let Some(from) = spar_stream.stages.iter().find(|from| from.id == *id) {
+                edges.push((from, stage));
+            }
+        }
+    }
+    edges
+}
+
+/// Every stage that loops back, the stage it sends its items back to, and while
+/// which condition
+fn loops(spar_stream: &SparStream) -> Vec<(&SparStage, &SparStage, String)> {
+    spar_stream
+        .stages
+        .iter()
+        .filter_map(|stage| {
+            let looped = stage.attrs.loop_back.as_ref()?;
+            let target = spar_stream.loop_target(stage)?;
+            Some((stage, target, escape(&looped.condition.0.to_string())))
+        })
+        .collect()
+}
+
+fn escape(string: &str) -> String {
+    string.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Returns the Graphviz DOT representation of the stream
+pub fn to_dot(spar_stream: &SparStream) -> String {
+    let mut dot = String::from("digraph spar_stream {\n    rankdir=LR;\n    node [shape=box];\n");
+
+    for stage in &spar_stream.stages {
+        let mut label = stage_name(stage);
+        if stage.is_split {
+            label.push_str("\\nSPLIT");
+        }
+        match stage_replicate(stage) {
+            Replicate::Lit(n) => label.push_str(&format!("\\nREPLICATE = {n}")),
+            Replicate::Var(v) => label.push_str(&format!("\\nREPLICATE = {v}")),
+            Replicate::SeqOrdered => label.push_str("\\nORDERED"),
+            Replicate::SeqUnordered => (),
+        }
+        for var in &stage.state {
+            label.push_str(&format!("\\nstate: {}", escape(&var.to_string())));
+        }
+        if !stage.nested.is_empty() {
+            label.push_str(&format!("\\nnested stages: {}", stage.nested.len()));
+        }
+        if let Some(window) = &stage.attrs.window {
+            label.push_str(&format!(
+                "\\nWINDOW(size = {}",
+                escape(&window.size.0.to_string())
+            ));
+            if let Some(slide) = &window.slide {
+                label.push_str(&format!(", slide = {}", escape(&slide.0.to_string())));
+            }
+            label.push(')');
+        }
+        dot.push_str(&format!("    stage{} [label=\"{}\"];\n", stage.id, label));
+    }
+
+    for (from, to) in edges(spar_stream) {
+        let mut vars: Vec<String> = to
+            .attrs
+            .input
+            .iter()
+            .map(|var| escape(&var.to_string()))
+            .collect();
+        vars.extend(
+            to.forwarded
+                .iter()
+                .map(|var| format!("{} (forwarded)", escape(&var.to_string()))),
+        );
+        dot.push_str(&format!(
+            "    stage{} -> stage{} [label=\"{}\"];\n",
+            from.id,
+            to.id,
+            vars.join("\\n")
+        ));
+    }
+    for (from, to, condition) in loops(spar_stream) {
+        dot.push_str(&format!(
+            "    stage{} -> stage{} [label=\"while {condition}\", style=dashed];\n",
+            from.id, to.id
+        ));
+    }
+
+    if let Some(last) = spar_stream.stages.last() {
+        if spar_stream.collects() {
+            let mut label = String::from(if spar_stream.is_pipeline {
+                "handle"
+            } else if spar_stream.attrs.lazy {
+                "iterator"
+            } else {
+                "collector"
+            });
+            if matches!(spar_stream.attrs.replicate, Replicate::SeqOrdered) {
+                label.push_str("\\nORDERED");
+            }
+            let mut vars = Vec::new();
+            if let Some(output_type) = &spar_stream.attrs.output_type {
+                vars.push(format!(
+                    "returns: Vec<{}>",
+                    escape(&output_type.to_string())
+                ));
+                vars.extend(
+                    last.attrs
+                        .output
+                        .iter()
+                        .filter(|var| !spar_stream.attrs.output.contains(var))
+                        .map(|var| escape(&var.to_string())),
+                );
+            }
+            if !spar_stream.attrs.output.is_empty() {
+                vars.push("restores:".to_owned());
+                vars.extend(
+                    spar_stream
+                        .attrs
+                        .output
+                        .iter()
+                        .map(|var| escape(&var.to_string())),
+                );
+            }
+            dot.push_str(&format!(
+                "    collector [label=\"{label}\", shape=ellipse];\n"
+            ));
+            dot.push_str(&format!(
+                "    stage{} -> collector [label=\"{}\"];\n",
+                last.id,
+                vars.join("\\n")
+            ));
+        }
+    }
+
+    dot.push_str("}\n");
+    dot
+}
+
+fn vars_json<'a>(vars: impl IntoIterator<Item = &'a SparVar>) -> String {
+    let vars: Vec<String> = vars
+        .into_iter()
+        .map(|var| {
+            format!(
+                "{{\"name\":\"{}\",\"type\":\"{}\"}}",
+                var.identifier,
+                escape(&var.var_type.to_string())
+            )
+        })
+        .collect();
+    format!("[{}]", vars.join(","))
+}
+
+fn replicate_json(replicate: &Replicate) -> String {
+    match replicate {
+        Replicate::Lit(n) => n.to_string(),
+        Replicate::Var(v) => format!("\"{v}\""),
+        Replicate::SeqOrdered | Replicate::SeqUnordered => "1".to_owned(),
+    }
+}
+
+/// The size and the slide of the windows of a stage, as the expressions that give
+/// them. The slide is the size if not given
+fn window_json(stage: &SparStage) -> String {
+    match &stage.attrs.window {
+        Some(window) => {
+            let size = escape(&window.size.0.to_string());
+            let slide = window
+                .slide
+                .as_ref()
+                .map_or(size.clone(), |slide| escape(&slide.0.to_string()));
+            format!("{{\"size\":\"{size}\",\"slide\":\"{slide}\"}}")
+        }
+        None => "null".to_owned(),
+    }
+}
+
+/// Returns the JSON representation of the stream
+pub fn to_json(spar_stream: &SparStream) -> String {
+    let stages: Vec<String> = spar_stream
+        .stages
+        .iter()
+        .map(|stage| {
+            format!(
+                "{{\"id\":{},\"name\":\"{}\",\"replicate\":{},\"ordered\":{},\"input\":{},\"output\":{},\"state\":{},\"forwarded\":{},\"split\":{},\"nested\":{},\"window\":{}}}",
+                stage.id,
+                stage_name(stage),
+                replicate_json(stage_replicate(stage)),
+                matches!(stage_replicate(stage), Replicate::SeqOrdered),
+                vars_json(&stage.attrs.input),
+                vars_json(&stage.attrs.output),
+                vars_json(&stage.state),
+                vars_json(&stage.forwarded),
+                stage.is_split,
+                stage.nested.len(),
+                window_json(stage),
+            )
+        })
+        .collect();
+
+    let mut edges: Vec<String> = edges(spar_stream)
+        .into_iter()
+        .map(|(from, to)| {
+            format!(
+                "{{\"from\":{},\"to\":{},\"variables\":{}}}",
+                from.id,
+                to.id,
+                vars_json(to.received())
+            )
+        })
+        .collect();
+    // the edge of a LOOP_BACK has the condition while which items are sent back
+    edges.extend(loops(spar_stream).into_iter().map(|(from, to, condition)| {
+        format!(
+            "{{\"from\":{},\"to\":{},\"variables\":{},\"while\":\"{condition}\"}}",
+            from.id,
+            to.id,
+            vars_json(to.received())
+        )
+    }));
+
+    format!(
+        "{{\"ordered\":{},\"stages\":[{}],\"edges\":[{}],\"external\":{},\"restored\":{},\"output\":{},\"lazy\":{},\"detached\":{}}}",
+        matches!(spar_stream.attrs.replicate, Replicate::SeqOrdered),
+        stages.join(","),
+        edges.join(","),
+        vars_json(&spar_stream.external_vars),
+        vars_json(&spar_stream.attrs.output),
+        match &spar_stream.attrs.output_type {
+            Some(output_type) => format!("\"{}\"", escape(&output_type.to_string())),
+            None => "null".to_owned(),
+        },
+        spar_stream.attrs.lazy,
+        spar_stream.attrs.detached,
+    )
+}
+
+/// Writes `<name>.dot` and `<name>.json` to `dir`
+pub fn write(spar_stream: &SparStream, dir: &Path, name: &str) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(dir.join(format!("{name}.dot")), to_dot(spar_stream))?;
+    std::fs::write(dir.join(format!("{name}.json")), to_json(spar_stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use quote::quote;
+
+    use super::*;
+
+    fn stream() -> SparStream {
+        SparStream::try_from(quote! {
+            INPUT(size: usize, result: Vec<u32>), ORDERED, {
+                for i in 0..size {
+                    let item = i as u32;
+                    STAGE(INPUT(item: u32, size: usize), OUTPUT(item: u32), REPLICATE = 4, {
+                        item *= size as u32;
+                    });
+                    STAGE(INPUT(item: u32, result: Vec<u32>), {
+                        result.push(item);
+                    });
+                }
+            }
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn dot() {
+        let dot = to_dot(&stream());
+        assert!(dot.contains("stage0 [label=\"dispatcher\"];"));
+        assert!(dot.contains("stage1 [label=\"SparStage1\\nREPLICATE = 4\\nstate: size: usize\"];"));
+        assert!(dot.contains("stage0 -> stage1 [label=\"item: u32\"];"));
+        assert!(dot.contains("stage1 -> stage2 [label=\"item: u32\"];"));
+        assert!(dot.contains("stage2 -> collector [label=\"restores:\\nresult: Vec<u32>\"];"));
+    }
+
+    #[test]
+    fn json() {
+        let json = to_json(&stream());
+        assert!(json.starts_with("{\"ordered\":true,"));
+        assert!(json.contains("{\"id\":1,\"name\":\"SparStage1\",\"replicate\":4,\"ordered\":false,\"input\":[{\"name\":\"item\",\"type\":\"u32\"}],\"output\":[{\"name\":\"item\",\"type\":\"u32\"}],\"state\":[{\"name\":\"size\",\"type\":\"usize\"}],\"forwarded\":[],\"split\":false,\"nested\":0,\"window\":null}"));
+        assert!(json.contains(
+            "{\"from\":1,\"to\":2,\"variables\":[{\"name\":\"item\",\"type\":\"u32\"}]}"
+        ));
+        assert!(json.ends_with(
+            "\"restored\":[{\"name\":\"result\",\"type\":\"Vec<u32>\"}],\"output\":null,\"lazy\":false,\"detached\":false}"
+        ));
+    }
+
+    #[test]
+    fn returned_items() {
+        let spar_stream = SparStream::try_from(quote! {
+            OUTPUT((usize, String)), {
+                for i in 0..10 {
+                    STAGE(INPUT(i: usize), OUTPUT(i: usize, name: String), REPLICATE = 2, {
+                        let name = i.to_string();
+                    });
+                }
+            }
+        })
+        .unwrap();
+        let dot = to_dot(&spar_stream);
+        assert!(dot.contains(
+            "stage1 -> collector [label=\"returns: Vec<(usize, String)>\\ni: usize\\nname: String\"];"
+        ));
+        assert!(to_json(&spar_stream).ends_with(
+            "\"restored\":[],\"output\":\"(usize, String)\",\"lazy\":false,\"detached\":false}"
+        ));
+    }
+
+    #[test]
+    fn branches() {
+        let spar_stream = SparStream::try_from(quote! {
+            {
+                for i in 0..10 {
+                    if i % 2 == 0 {
+                        STAGE(INPUT(i: usize), OUTPUT(i: usize), {});
+                    } else {
+                        STAGE(INPUT(i: usize), OUTPUT(i: usize), REPLICATE = 2, {});
+                    }
+                    STAGE(INPUT(i: usize), {});
+                }
+            }
+        })
+        .unwrap();
+        let dot = to_dot(&spar_stream);
+        assert!(dot.contains("stage0 -> stage1 [label=\"i: usize\"];"));
+        assert!(dot.contains("stage0 -> stage2 [label=\"i: usize\"];"));
+        assert!(dot.contains("stage1 -> stage3 [label=\"i: usize\"];"));
+        assert!(dot.contains("stage2 -> stage3 [label=\"i: usize\"];"));
+        assert!(to_json(&spar_stream).contains(
+            "\"edges\":[{\"from\":0,\"to\":1,\"variables\":[{\"name\":\"i\",\"type\":\"usize\"}]},{\"from\":0,\"to\":2,"
+        ));
+    }
+
+    #[test]
+    fn splits() {
+        let spar_stream = SparStream::try_from(quote! {
+            {
+                for i in 0..10 {
+                    SPLIT(INPUT(i: usize), {
+                        { STAGE(INPUT(i: usize), OUTPUT(i: usize), {}); }
+                        { STAGE(INPUT(i: usize), OUTPUT(i: usize), REPLICATE = 2, {}); }
+                    });
+                    STAGE(INPUT(i: usize), {});
+                }
+            }
+        })
+        .unwrap();
+        let dot = to_dot(&spar_stream);
+        assert!(dot.contains("stage1 [label=\"SparStage1\\nSPLIT\"];"));
+        assert!(dot.contains("stage1 -> stage2 [label=\"i: usize\"];"));
+        assert!(dot.contains("stage1 -> stage3 [label=\"i: usize\"];"));
+        assert!(dot.contains("stage3 -> stage4 [label=\"i: usize\"];"));
+        assert!(to_json(&spar_stream)
+            .contains("\"forwarded\":[],\"split\":true,\"nested\":0,\"window\":null}"));
+    }
+
+    #[test]
+    fn nested_stages() {
+        let spar_stream = SparStream::try_from(quote! {
+            {
+                for i in 0..10 {
+                    STAGE(INPUT(i: usize), OUTPUT(i: usize), REPLICATE = 2, {
+                        STAGE(INPUT(i: usize), OUTPUT(j: usize), { let j = i; });
+                        STAGE(INPUT(j: usize), OUTPUT(i: usize), { let i = j; });
+                    });
+                    STAGE(INPUT(i: usize), {});
+                }
+            }
+        })
+        .unwrap();
+        assert!(to_dot(&spar_stream)
+            .contains("stage1 [label=\"SparStage1\\nREPLICATE = 2\\nnested stages: 2\"];"));
+        assert!(to_json(&spar_stream).contains("\"split\":false,\"nested\":2,\"window\":null}"));
+    }
+
+    #[test]
+    fn loop_back() {
+        let spar_stream = SparStream::try_from(quote! {
+            {
+                for i in 0..10 {
+                    STAGE(INPUT(i: usize), OUTPUT(i: usize), NAME = refine, {
+                        i += 1;
+                    });
+                    STAGE(INPUT(i: usize), OUTPUT(i: usize), LOOP_BACK(to = refine, while = i < 100), {
+                        i *= 2;
+                    });
+                    STAGE(INPUT(i: usize), {});
+                }
+            }
+        })
+        .unwrap();
+        let dot = to_dot(&spar_stream);
+        assert!(dot.contains("stage1 [label=\"refine\"];"));
+        assert!(dot.contains("stage2 -> stage1 [label=\"while i < 100\", style=dashed];"));
+        assert!(to_json(&spar_stream).contains(
+            "{\"from\":2,\"to\":1,\"variables\":[{\"name\":\"i\",\"type\":\"usize\"}],\"while\":\"i < 100\"}"
+        ));
+    }
+
+    #[test]
+    fn windows() {
+        let spar_stream = SparStream::try_from(quote! {
+            {
+                for i in 0..10 {
+                    STAGE(INPUT(i: usize), OUTPUT(i: usize), WINDOW(size = 3, slide = 1), { let i = i[0]; });
+                    STAGE(INPUT(i: usize), WINDOW(size = 2), {});
+                }
+            }
+        })
+        .unwrap();
+        let dot = to_dot(&spar_stream);
+        assert!(dot.contains("stage1 [label=\"SparStage1\\nWINDOW(size = 3, slide = 1)\"];"));
+        assert!(dot.contains("stage2 [label=\"SparStage2\\nWINDOW(size = 2)\"];"));
+        let json = to_json(&spar_stream);
+        assert!(json.contains("\"window\":{\"size\":\"3\",\"slide\":\"1\"}}"));
+        assert!(json.contains("\"window\":{\"size\":\"2\",\"slide\":\"2\"}}"));
+    }
+}
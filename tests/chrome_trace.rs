@@ -0,0 +1,82 @@
+#![cfg(feature = "chrome-trace")]
+
+use spar_rust::to_stream;
+
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[test]
+fn trace_file() {
+    let path = std::env::temp_dir().join(format!("spar-trace-{}.json", std::process::id()));
+    std::env::set_var("SPAR_TRACE_FILE", &path);
+
+    let squares = to_stream!(OUTPUT(u64), ORDERED, {
+        for n in 0..20u64 {
+            STAGE(INPUT(n: u64), OUTPUT(square: u64), REPLICATE = 4, {
+                let square = n * n;
+            });
+            STAGE(INPUT(square: u64), OUTPUT(square: u64), ORDERED, {});
+        }
+    });
+    assert_eq!(squares, (0..20u64).map(|n| n * n).collect::<Vec<u64>>());
+
+    let json = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let trace: Value = serde_json::from_str(&json).unwrap();
+    let events = trace["traceEvents"].as_array().unwrap();
+
+    // one complete event for every item in every stage, on the track of its replica
+    let mut items: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+    let mut tracks = BTreeSet::new();
+    for event in events.iter().filter(|event| event["ph"] == "X") {
+        let (stage, replica) = (
+            event["pid"].as_u64().unwrap(),
+            event["tid"].as_u64().unwrap(),
+        );
+        let item = event["args"]["item"].as_u64().unwrap();
+        assert_eq!(event["name"], format!("item {item}"));
+        assert!(event["dur"].as_f64().unwrap() >= 0.0);
+        match stage {
+            1 => assert!(replica < 4),
+            2 => assert_eq!(replica, 0),
+            _ => panic!("no stage {stage}"),
+        }
+        items.entry(stage).or_default().push(item);
+        tracks.insert((stage, replica));
+    }
+    for stage_items in items.values_mut() {
+        stage_items.sort_unstable();
+        assert_eq!(*stage_items, (0..20).collect::<Vec<u64>>());
+    }
+    assert_eq!(items.len(), 2);
+
+    // every stage is named as a process, and every replica that ran as a thread
+    let metadata: Vec<&Value> = events.iter().filter(|event| event["ph"] == "M").collect();
+    let processes: BTreeSet<u64> = metadata
+        .iter()
+        .filter(|event| event["name"] == "process_name")
+        .map(|event| {
+            let stage = event["pid"].as_u64().unwrap();
+            assert_eq!(event["args"]["name"], format!("SparStage{stage}"));
+            stage
+        })
+        .collect();
+    assert_eq!(processes, BTreeSet::from([1, 2]));
+    let threads: BTreeSet<(u64, u64)> = metadata
+        .iter()
+        .filter(|event| event["name"] == "thread_name")
+        .map(|event| {
+            let (stage, replica) = (
+                event["pid"].as_u64().unwrap(),
+                event["tid"].as_u64().unwrap(),
+            );
+            assert_eq!(
+                event["args"]["name"],
+                format!("SparStage{stage} replica {replica}")
+            );
+            (stage, replica)
+        })
+        .collect();
+    assert_eq!(threads, tracks);
+    assert_eq!(metadata.len(), processes.len() + threads.len());
+}
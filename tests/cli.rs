@@ -0,0 +1,95 @@
+#![cfg(feature = "cli")]
+
+//! Golden tests of the command line tools. `tests/cli/<name>.rs` is given to a
+//! tool, whose output must be `tests/cli/<name>.expanded.rs` for spar-expand, or
+//! `tests/cli/<name>.formatted.rs` for spar-fmt. Run them with
+//! `SPAR_GOLDEN=overwrite` to write the outputs instead.
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn run(tool: &str, args: &[&str]) -> Output {
+    Command::new(tool).args(args).output().unwrap()
+}
+
+fn check_golden(path: &str, actual: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(path);
+    if std::env::var_os("SPAR_GOLDEN").is_some_and(|var| var == "overwrite") {
+        std::fs::write(&path, actual).unwrap();
+    } else {
+        let expected = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            expected == actual,
+            "{} differs from:\n{actual}",
+            path.display()
+        );
+    }
+}
+
+#[test]
+#[cfg_attr(
+    any(feature = "tracing", feature = "chrome-trace"),
+    ignore = "the instrumentation changes the expanded code"
+)]
+fn expand() {
+    let output = run(env!("CARGO_BIN_EXE_spar-expand"), &["tests/cli/expand.rs"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    check_golden(
+        "tests/cli/expand.expanded.rs",
+        &String::from_utf8(output.stdout).unwrap(),
+    );
+}
+
+/// Copies `tests/cli/<name>` to a temporary file, which spar-fmt may rewrite
+fn scratch(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("spar-fmt-{}-{name}", std::process::id()));
+    std::fs::copy(format!("tests/cli/{name}"), &path).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+#[test]
+fn fmt() {
+    let path = scratch("fmt.rs");
+    let output = run(env!("CARGO_BIN_EXE_spar-fmt"), &[&path]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let formatted = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    check_golden("tests/cli/fmt.formatted.rs", &formatted);
+}
+
+#[test]
+fn fmt_check() {
+    // an unformatted file is reported, and left as it is
+    let path = scratch("fmt.rs");
+    let output = run(env!("CARGO_BIN_EXE_spar-fmt"), &["--check", &path]);
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{path}: to_stream! invocations are not formatted\n")
+    );
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        std::fs::read_to_string("tests/cli/fmt.rs").unwrap()
+    );
+    std::fs::remove_file(&path).unwrap();
+
+    // formatting is idempotent
+    let output = run(
+        env!("CARGO_BIN_EXE_spar-fmt"),
+        &["--check", "tests/cli/fmt.formatted.rs"],
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    assert!(output.stdout.is_empty());
+}
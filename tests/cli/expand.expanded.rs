@@ -0,0 +1,103 @@
+
+fn main() {
+    let squares = {
+        let spar_num_workers: Option<u32> = match std::env::var("SPAR_NUM_WORKERS") {
+            Ok(var) => {
+                match var.parse() {
+                    Ok(value) => {
+                        if value < 1 {
+                            eprintln!(
+                                "SPAR_NUM_WORKERS must be a number > 0. Found {}. Defaulting to 1...",
+                                value
+                            );
+                            Some(1)
+                        } else {
+                            Some(value)
+                        }
+                    }
+                    Err(_) => {
+                        eprintln!(
+                            "invalid value for SPAR_NUM_WORKERS variable: {}. Ignoring...",
+                            var
+                        );
+                        None
+                    }
+                }
+            }
+            Err(_) => None,
+        };
+        use rust_spp::*;
+        struct SparStage1 {}
+        impl SparStage1 {
+            fn new() -> Self {
+                Self {}
+            }
+        }
+        impl rust_spp::blocks::inout_block::InOut<u64, u64> for SparStage1 {
+            fn process(&mut self, input: u64) -> Option<u64> {
+                let mut n = input;
+                let square = n * n;
+                Some(square)
+            }
+        }
+        let mut spar_pipeline = {
+            rust_spp::pipeline![
+                rust_spp::parallel!(SparStage1::new(), if let Some(workers) =
+                spar_num_workers { workers as i32 } else { 4u32 as i32 }), collect_ordered!()
+            ]
+        };
+        struct SparEndOnDrop<P, F: FnOnce(P)>(Option<(P, F)>);
+        impl<P, F: FnOnce(P)> SparEndOnDrop<P, F> {
+            fn new(pipeline: P, end: F) -> Self {
+                Self(Some((pipeline, end)))
+            }
+            fn into_inner(mut self) -> P {
+                self.0.take().unwrap().0
+            }
+        }
+        impl<P, F: FnOnce(P)> std::ops::Deref for SparEndOnDrop<P, F> {
+            type Target = P;
+            fn deref(&self) -> &P {
+                &self.0.as_ref().unwrap().0
+            }
+        }
+        impl<P, F: FnOnce(P)> std::ops::DerefMut for SparEndOnDrop<P, F> {
+            fn deref_mut(&mut self) -> &mut P {
+                &mut self.0.as_mut().unwrap().0
+            }
+        }
+        impl<P, F: FnOnce(P)> Drop for SparEndOnDrop<P, F> {
+            fn drop(&mut self) {
+                if let Some((pipeline, end)) = self.0.take() {
+                    end(pipeline);
+                }
+            }
+        }
+        #[allow(unused_mut)]
+        let mut spar_pipeline = SparEndOnDrop::new(
+            spar_pipeline,
+            |spar_pipeline| {
+                spar_pipeline.end_and_wait();
+            },
+        );
+        {
+            let spar_pipeline = &*spar_pipeline;
+            for n in 1..=10u64 {
+                {
+                    spar_pipeline.post(n).unwrap();
+                }
+            }
+        }
+        let spar_pipeline = spar_pipeline.into_inner();
+        let collection = spar_pipeline.collect();
+        let spar_output: Vec<u64> = {
+            let mut spar_output: Vec<u64> = Vec::with_capacity(collection.len());
+            for spar_item in collection {
+                spar_output.push(spar_item);
+            }
+            spar_output
+        };
+        spar_output
+    };
+    assert_eq!(squares.iter().sum::<u64>(), 385);
+}
@@ -0,0 +1,12 @@
+use spar_rust::to_stream;
+
+fn main() {
+    let squares = to_stream!(OUTPUT(u64), ORDERED, {
+        for n in 1..=10u64 {
+            STAGE(INPUT(n: u64), OUTPUT(square: u64), REPLICATE = 4, {
+                let square = n * n;
+            });
+        }
+    });
+    assert_eq!(squares.iter().sum::<u64>(), 385);
+}
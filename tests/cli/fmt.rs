@@ -0,0 +1,13 @@
+use spar_rust::to_stream;
+
+fn main() {
+    let lengths = to_stream!(ORDERED,OUTPUT(usize), {
+        for n in 0..10u64 {
+        let text = n.to_string();
+            // the even items go to the first branch
+            let _ = if n % 2 == 0 { STAGE(OUTPUT(length: usize),INPUT(text: String), REPLICATE = 2, { let length = text.len(); }) } else { STAGE(INPUT(n: u64), OUTPUT(length: usize), { let length = n as usize; }) };
+            STAGE(INPUT(length: usize), OUTPUT(length: usize), ORDERED, {})
+        }
+    });
+    assert_eq!(lengths.len(), 10);
+}
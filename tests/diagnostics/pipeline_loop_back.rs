@@ -0,0 +1,13 @@
+extern crate spar_rust;
+use spar_rust::spar_pipeline;
+
+fn main() {
+    let _pipeline = spar_pipeline!(OUTPUT(u64), {
+        STAGE(INPUT(n: u64), OUTPUT(n: u64), NAME = grow, {
+            n += 3;
+        });
+        STAGE(INPUT(n: u64), OUTPUT(n: u64), LOOP_BACK(to = grow, while = n < 50), {
+            n *= 2;
+        });
+    });
+}
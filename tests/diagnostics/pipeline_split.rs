@@ -0,0 +1,16 @@
+extern crate spar_rust;
+use spar_rust::spar_pipeline;
+
+fn main() {
+    let _pipeline = spar_pipeline!(OUTPUT(u64), {
+        STAGE(INPUT(n: u64), OUTPUT(n: u64), {});
+        SPLIT(INPUT(n: u64), {
+            if n % 2 == 0 {
+                STAGE(INPUT(n: u64), OUTPUT(n: u64), { n /= 2; });
+            } else {
+                STAGE(INPUT(n: u64), OUTPUT(n: u64), { n = 3 * n + 1; });
+            }
+        });
+        STAGE(INPUT(n: u64), OUTPUT(n: u64), {});
+    });
+}
@@ -0,0 +1,10 @@
+extern crate spar_rust;
+use spar_rust::spar_pipeline;
+
+fn main() {
+    let _pipeline = spar_pipeline!(OUTPUT(f64), {
+        STAGE(INPUT(price: f64), OUTPUT(average: f64), ORDERED, WINDOW(size = 20, slide = 1), {
+            let average = price.iter().sum::<f64>() / price.len() as f64;
+        });
+    });
+}
@@ -0,0 +1,15 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+fn main() {
+    let mut result: Vec<u32> = Vec::new();
+    to_stream!(INPUT(result: Vec<u32>), {
+        for i in 0..10 {
+            let item = i;
+            STAGE(INPUT(item: u32, result: Vec<u32>), {
+                let doubled: String = item * 2;
+                result.push(item);
+            });
+        }
+    });
+}
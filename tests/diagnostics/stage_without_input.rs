@@ -0,0 +1,16 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+fn main() {
+    to_stream!({
+        for i in 0..10u32 {
+            let item = i;
+            STAGE(INPUT(item: u32), {
+                println!("{}", item);
+            });
+            STAGE({
+                println!("done");
+            });
+        }
+    });
+}
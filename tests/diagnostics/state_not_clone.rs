@@ -0,0 +1,18 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+struct Counter {
+    count: u32,
+}
+
+fn main() {
+    let counter = Counter { count: 0 };
+    to_stream!(INPUT(counter: Counter), {
+        for i in 0..10 {
+            let item = i;
+            STAGE(INPUT(item: u32, counter: Counter), {
+                counter.count += item;
+            });
+        }
+    });
+}
@@ -0,0 +1,13 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+fn main() {
+    let lengths: Vec<usize> = to_stream!(OUTPUT(usize), {
+        for word in ["a", "bb", "ccc"] {
+            STAGE(INPUT(word: &str), OUTPUT(length: u32), REPLICATE = 2, {
+                let length = word.len() as u32;
+            });
+        }
+    });
+    assert_eq!(lengths.len(), 3);
+}
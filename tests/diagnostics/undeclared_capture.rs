@@ -0,0 +1,14 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+fn main() {
+    to_stream!({
+        for i in 0..10u32 {
+            let item = i;
+            let offset = i * 2;
+            STAGE(INPUT(item: u32), {
+                println!("{}", item + offset);
+            });
+        }
+    });
+}
@@ -0,0 +1,73 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+enum Message {
+    Word(String),
+    Number(u64),
+    Skip,
+}
+
+fn messages() -> Vec<Message> {
+    (0..20u64)
+        .map(|i| match i % 3 {
+            0 => Message::Word(format!("w{i}")),
+            1 => Message::Number(i),
+            _ => Message::Skip,
+        })
+        .collect()
+}
+
+fn main() {
+    // each arm hands a differently-shaped item to its own branch, and both branches
+    // merge into the last stage
+    let lengths = to_stream!(OUTPUT(usize), ORDERED, {
+        for msg in messages() {
+            match msg {
+                Message::Word(word) => STAGE(INPUT(word: String), OUTPUT(length: usize), REPLICATE = 2, {
+                    let length = word.len();
+                }),
+                Message::Number(n) => {
+                    let text = n.to_string();
+                    STAGE(INPUT(n: u64, text: String), OUTPUT(length: usize), {
+                        assert_eq!(text, n.to_string());
+                        let length = text.len() * 100;
+                    });
+                }
+                Message::Skip => (),
+            }
+            STAGE(INPUT(length: usize), OUTPUT(length: usize), ORDERED, {});
+        }
+    });
+    let expected: Vec<usize> = messages()
+        .into_iter()
+        .filter_map(|msg| match msg {
+            Message::Word(word) => Some(word.len()),
+            Message::Number(n) => Some(n.to_string().len() * 100),
+            Message::Skip => None,
+        })
+        .collect();
+    assert_eq!(lengths, expected);
+
+    // branches that no stage merges consume their items, and if/else dispatches too
+    let (even_sum, odd_sum) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let (evens, odds) = (even_sum.clone(), odd_sum.clone());
+    to_stream!(INPUT(evens: Arc<AtomicUsize>, odds: Arc<AtomicUsize>), {
+        for n in 0..10usize {
+            if n % 2 == 0 {
+                STAGE(INPUT(n: usize), OUTPUT(n: usize), REPLICATE = 2, {});
+                STAGE(INPUT(n: usize, evens: Arc<AtomicUsize>), {
+                    evens.fetch_add(n, Ordering::SeqCst);
+                });
+            } else {
+                STAGE(INPUT(n: usize, odds: Arc<AtomicUsize>), {
+                    odds.fetch_add(n, Ordering::SeqCst);
+                });
+            }
+        }
+    });
+    assert_eq!(even_sum.load(Ordering::SeqCst), 20);
+    assert_eq!(odd_sum.load(Ordering::SeqCst), 25);
+}
@@ -0,0 +1,59 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+use spar_rust_runtime::{CancelToken, Outcome};
+use std::time::Duration;
+
+fn main() {
+    // the last stage cancels the stream, whose remaining items are skipped, but the
+    // items that went through it are restored
+    let token = CancelToken::new();
+    let mut total: Vec<u64> = Vec::new();
+    let outcome = to_stream!(INPUT(total: Vec<u64>, token: CancelToken), CANCEL = token, ORDERED, {
+        for n in 0u64.. {
+            STAGE(INPUT(n: u64), OUTPUT(n: u64), REPLICATE = 4, {
+                n *= 2;
+            });
+            STAGE(INPUT(n: u64, total: Vec<u64>, token: CancelToken), ORDERED, {
+                total.push(n);
+                if n == 20 {
+                    token.cancel();
+                }
+            });
+        }
+    });
+    assert_eq!(outcome, Outcome::Cancelled(()));
+    assert_eq!(total[..11], (0..=20).step_by(2).collect::<Vec<u64>>());
+    assert!(total.windows(2).all(|pair| pair[0] < pair[1]));
+
+    let outcome = to_stream!(OUTPUT(u64), TIMEOUT = Duration::from_millis(50), ORDERED, {
+        for n in 0..1000u64 {
+            STAGE(INPUT(n: u64), OUTPUT(n: u64), ORDERED, {
+                std::thread::sleep(Duration::from_millis(1));
+            });
+        }
+    });
+    assert!(outcome.is_cancelled());
+    let items = outcome.into_inner();
+    assert!(items.len() < 1000);
+    assert!(items.windows(2).all(|pair| pair[0] < pair[1]));
+
+    let token = CancelToken::new();
+    let outcome = to_stream!(OUTPUT(u64), CANCEL = &token, TIMEOUT = Duration::from_secs(60), ORDERED, {
+        for n in 0..100u64 {
+            STAGE(INPUT(n: u64), OUTPUT(n: u64), REPLICATE = 4, {});
+        }
+    });
+    assert_eq!(outcome, Outcome::Completed((0..100).collect()));
+
+    // cancelling the handle of a detached stream cancels its token
+    let token = CancelToken::new();
+    let stream = to_stream!(OUTPUT(u64), DETACHED, CANCEL = token, {
+        for n in 0u64.. {
+            STAGE(INPUT(n: u64), OUTPUT(n: u64), REPLICATE = 2, {});
+        }
+    });
+    stream.cancel();
+    assert!(token.is_cancelled());
+    assert!(stream.join().is_cancelled());
+}
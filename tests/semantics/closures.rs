@@ -0,0 +1,69 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+use spar_rust_runtime::{CancelToken, Outcome};
+
+use std::io::{BufRead, BufReader};
+
+fn lengths(text: &str) -> Result<Vec<usize>, String> {
+    let lengths = to_stream!(OUTPUT(usize), ORDERED, {
+        BufReader::new(text.as_bytes())
+            .lines()
+            .try_for_each(|line| {
+                let line = line.map_err(|e| e.to_string())?;
+                if line == "stop" {
+                    return Err(line);
+                }
+                STAGE(INPUT(line: String), OUTPUT(length: usize), REPLICATE = 2, {
+                    let length = line.len();
+                });
+                Ok(())
+            })?;
+    });
+    Ok(lengths)
+}
+
+fn main() {
+    let squares = to_stream!(OUTPUT(u64), ORDERED, {
+        (1..=10u64).for_each(|n| {
+            let square = n * n;
+            STAGE(INPUT(square: u64), OUTPUT(square: u64), REPLICATE = 4, {});
+        });
+    });
+    assert_eq!(squares, (1..=10u64).map(|n| n * n).collect::<Vec<u64>>());
+
+    // a closure whose body is the stage, and a `move` closure, which dispatch to two
+    // branches that the last stage merges
+    let offset = 100u64;
+    let items = to_stream!(OUTPUT(u64), ORDERED, {
+        (1..=5u64).for_each(|n| STAGE(INPUT(n: u64), OUTPUT(n: u64), REPLICATE = 2, {}));
+        (6..=10u64).for_each(move |n| {
+            let shifted = n + offset;
+            STAGE(INPUT(shifted: u64), OUTPUT(n: u64), {
+                let n = shifted;
+            });
+        });
+        STAGE(INPUT(n: u64), OUTPUT(n: u64), ORDERED, {});
+    });
+    let mut expected: Vec<u64> = (1..=5).collect();
+    expected.extend(106..=110);
+    assert_eq!(items, expected);
+
+    assert_eq!(lengths("a\nbb\nccc"), Ok(vec![1, 2, 3]));
+    assert_eq!(lengths("a\nstop\nccc"), Err("stop".to_owned()));
+
+    // once the stream is cancelled, the closure stops posting its items
+    let token = CancelToken::new();
+    let cancel = token.clone();
+    let outcome = to_stream!(OUTPUT(u64), ORDERED, CANCEL = token, {
+        (0..1000u64).for_each(|n| {
+            if n == 10 {
+                cancel.cancel();
+            }
+            STAGE(INPUT(n: u64), OUTPUT(n: u64), {});
+        });
+    });
+    match outcome {
+        Outcome::Cancelled(items) => assert!(items.len() <= 10),
+        Outcome::Completed(_) => panic!("the stream was cancelled"),
+    }
+}
@@ -0,0 +1,48 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+fn main() {
+    // the stream owns the variables it restores, and gives them back once joined
+    let total: Vec<u64> = vec![100];
+    let stream = to_stream!(INPUT(total: Vec<u64>), DETACHED, ORDERED, {
+        for n in 0..10u64 {
+            STAGE(INPUT(n: u64), OUTPUT(square: u64), REPLICATE = 4, {
+                let square = n * n;
+            });
+            STAGE(INPUT(square: u64, total: Vec<u64>), ORDERED, {
+                total.push(square);
+            });
+        }
+    });
+    while !stream.is_finished() {
+        std::thread::yield_now();
+    }
+    let total = stream.join();
+    assert_eq!(total, [100, 0, 1, 4, 9, 16, 25, 36, 49, 64, 81]);
+
+    let lengths = String::from("0");
+    let stream = to_stream!(INPUT(lengths: String), OUTPUT(usize), DETACHED, ORDERED, {
+        for word in ["a", "bb", "ccc"] {
+            let word = word.to_string();
+            STAGE(INPUT(word: String, lengths: String), OUTPUT(length: usize), {
+                let length = word.len();
+                lengths.push_str(&length.to_string());
+            });
+        }
+    });
+    let (items, lengths) = stream.join();
+    assert_eq!(items, [1, 2, 3]);
+    assert_eq!(lengths, "0123");
+
+    // a cancelled stream stops dispatching items, but the dispatched ones are returned
+    let stream = to_stream!(OUTPUT(u64), DETACHED, ORDERED, {
+        for n in 0u64.. {
+            STAGE(INPUT(n: u64), OUTPUT(n: u64), REPLICATE = 2, {
+                n += 1;
+            });
+        }
+    });
+    stream.cancel();
+    let items = stream.join();
+    assert_eq!(items, (1..=items.len() as u64).collect::<Vec<u64>>());
+}
@@ -0,0 +1,64 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// the stream is ended before the function returns, so that every item that was
+// dispatched already went through the stages
+fn first_invalid(words: Vec<&'static str>, processed: Arc<AtomicUsize>) -> Result<(), String> {
+    to_stream!(INPUT(processed: Arc<AtomicUsize>), {
+        for word in words {
+            let length: usize = word.parse().map_err(|_| word.to_string())?;
+            STAGE(INPUT(length: usize), OUTPUT(length: usize), REPLICATE = 4, {
+                std::thread::sleep(std::time::Duration::from_millis(length as u64));
+            });
+            STAGE(INPUT(length: usize, processed: Arc<AtomicUsize>), {
+                processed.fetch_add(length, Ordering::SeqCst);
+            });
+        }
+    });
+    Ok(())
+}
+
+fn sum_until(limit: u64, processed: Arc<AtomicUsize>) -> u64 {
+    to_stream!(INPUT(processed: Arc<AtomicUsize>), {
+        for n in 1..=100u64 {
+            if n > limit {
+                return n;
+            }
+            STAGE(INPUT(n: u64), OUTPUT(n: u64), REPLICATE = 2, {
+                std::thread::sleep(std::time::Duration::from_millis(n));
+            });
+            STAGE(INPUT(n: u64, processed: Arc<AtomicUsize>), {
+                processed.fetch_add(n as usize, Ordering::SeqCst);
+            });
+        }
+    });
+    0
+}
+
+fn main() {
+    let processed = Arc::new(AtomicUsize::new(0));
+    let result = first_invalid(vec!["1", "2", "3", "four", "5"], processed.clone());
+    assert_eq!(result, Err("four".to_string()));
+    assert_eq!(processed.load(Ordering::SeqCst), 6);
+
+    let processed = Arc::new(AtomicUsize::new(0));
+    assert_eq!(sum_until(10, processed.clone()), 11);
+    assert_eq!(processed.load(Ordering::SeqCst), 55);
+
+    // `break` ends the stream, which still returns the items it dispatched
+    let items = to_stream!(OUTPUT(u64), ORDERED, {
+        'outer: for i in 0..10u64 {
+            for j in 0..10u64 {
+                let n = i * 10 + j;
+                if n == 25 {
+                    break 'outer;
+                }
+                STAGE(INPUT(n: u64), OUTPUT(n: u64), REPLICATE = 4, {});
+            }
+        }
+    });
+    assert_eq!(items, (0..25).collect::<Vec<u64>>());
+}
@@ -0,0 +1,36 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+fn main() {
+    let mut result: Vec<(u32, u64, String)> = Vec::new();
+    to_stream!(INPUT(result: Vec<(u32, u64, String)>), ORDERED, {
+        for i in 0..100u32 {
+            let id = i;
+            let value = i as u64;
+            STAGE(INPUT(value: u64), OUTPUT(value: u64, label: String), REPLICATE = 4, {
+                let label = format!("item {}", value);
+            });
+
+            STAGE(INPUT(value: u64), OUTPUT(value: u64), REPLICATE = 4, {
+                let value = value * value;
+            });
+
+            STAGE(INPUT(label: String, value: u64), OUTPUT(value: u64, label: String), ORDERED, {
+                let label = label.to_uppercase();
+            });
+
+            // `id` is sent by the code before the stages, and `label` was sent by the
+            // first stage: both are forwarded by the stages in between
+            STAGE(INPUT(id: u32, value: u64, label: String, result: Vec<(u32, u64, String)>), ORDERED, {
+                result.push((id, value, label));
+            });
+        }
+    });
+
+    assert_eq!(result.len(), 100);
+    for (i, (id, value, label)) in result.into_iter().enumerate() {
+        assert_eq!(id, i as u32);
+        assert_eq!(value, (i * i) as u64);
+        assert_eq!(label, format!("ITEM {}", i));
+    }
+}
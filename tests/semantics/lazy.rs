@@ -0,0 +1,39 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+use std::sync::mpsc::channel;
+
+fn main() {
+    // the input is only sent once the first item came out, which would deadlock
+    // if the stream waited for its end before returning the items
+    let (feed, numbers) = channel::<u32>();
+    let mut squares = to_stream!(INPUT(numbers: std::sync::mpsc::Receiver<u32>), OUTPUT(u32), LAZY, ORDERED, {
+        for n in numbers {
+            STAGE(INPUT(n: u32), OUTPUT(square: u32), REPLICATE = 4, {
+                let square = n * n;
+            });
+        }
+    });
+
+    feed.send(3).unwrap();
+    assert_eq!(squares.next(), Some(9));
+    for n in 4..100 {
+        feed.send(n).unwrap();
+    }
+    drop(feed);
+    assert_eq!(squares.collect::<Vec<u32>>(), (4..100).map(|n| n * n).collect::<Vec<u32>>());
+
+    let words = vec!["a".to_string(), "bb".to_string(), "ccc".to_string()];
+    let mut lengths: Vec<(String, usize)> = to_stream!(OUTPUT((String, usize)), LAZY, {
+        STAGE(OUTPUT(word: String), { words.into_iter() });
+        STAGE(INPUT(word: String), OUTPUT(word: String, length: usize), REPLICATE = 2, {
+            let length = word.len();
+        });
+    })
+    .collect();
+    lengths.sort();
+    assert_eq!(
+        lengths,
+        [("a".to_string(), 1), ("bb".to_string(), 2), ("ccc".to_string(), 3)]
+    );
+}
@@ -0,0 +1,84 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+use std::time::Duration;
+
+fn collatz_steps(mut n: u64) -> u32 {
+    let mut steps = 0;
+    loop {
+        n = if n % 2 == 0 { n / 2 } else { 3 * n + 1 };
+        steps += 1;
+        if n == 1 {
+            return steps;
+        }
+    }
+}
+
+fn main() {
+    // a replicated stage sends every item back to itself until it converges
+    let mut steps = to_stream!(OUTPUT((u64, u32)), {
+        for start in 1..=30u64 {
+            let (n, steps) = (start, 0u32);
+            STAGE(
+                INPUT(start: u64, n: u64, steps: u32),
+                OUTPUT(start: u64, n: u64, steps: u32),
+                NAME = collatz,
+                REPLICATE = 2,
+                LOOP_BACK(to = collatz, while = n != 1),
+                {
+                    n = if n % 2 == 0 { n / 2 } else { 3 * n + 1 };
+                    steps += 1;
+                }
+            );
+            STAGE(INPUT(start: u64, steps: u32), OUTPUT(item: (u64, u32)), {
+                let item = (start, steps);
+            });
+        }
+    });
+    steps.sort();
+    let expected: Vec<(u64, u32)> = (1..=30u64).map(|start| (start, collatz_steps(start))).collect();
+    assert_eq!(steps, expected);
+
+    // items go through every stage between the target and the stage that loops
+    // back, which forward `id` to the last one
+    let mut texts = to_stream!(OUTPUT(String), {
+        for id in 0..10u64 {
+            let x = id;
+            STAGE(INPUT(x: u64), OUTPUT(x: u64), NAME = grow, {
+                x += 3;
+            });
+            STAGE(INPUT(x: u64), OUTPUT(x: u64), REPLICATE = 2, LOOP_BACK(to = grow, while = x < 50), {
+                x *= 2;
+            });
+            STAGE(INPUT(id: u64, x: u64), OUTPUT(text: String), {
+                let text = format!("{id}: {x}");
+            });
+        }
+    });
+    texts.sort();
+    let mut expected: Vec<String> = (0..10u64)
+        .map(|id| {
+            let mut x = id;
+            loop {
+                x = (x + 3) * 2;
+                if x >= 50 {
+                    return format!("{id}: {x}");
+                }
+            }
+        })
+        .collect();
+    expected.sort();
+    assert_eq!(texts, expected);
+
+    // items that never leave the loop are dropped once the stream times out
+    let outcome = to_stream!(OUTPUT(u64), TIMEOUT = Duration::from_millis(100), {
+        for n in 0..4u64 {
+            STAGE(INPUT(n: u64), OUTPUT(n: u64), NAME = spin, LOOP_BACK(to = spin, while = true), {
+                n += 1;
+            });
+            STAGE(INPUT(n: u64), OUTPUT(n: u64), {});
+        }
+    });
+    assert!(outcome.is_cancelled());
+    assert!(outcome.into_inner().is_empty());
+}
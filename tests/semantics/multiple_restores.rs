@@ -0,0 +1,81 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+use spar_rust_runtime::Restore;
+use std::collections::BTreeMap;
+
+#[derive(Default)]
+struct Counter(u64);
+
+impl Restore for Counter {
+    fn restore(&mut self, other: Self) {
+        self.0 += other.0;
+    }
+}
+
+fn add(values: &mut Vec<u64>, n: u64) {
+    values.push(n);
+}
+
+fn main() {
+    let names: Vec<String> = vec!["zero".to_string(), "one".to_string(), "two".to_string()];
+    let mut evens: Vec<u32> = Vec::new();
+    let mut by_rest: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+    let mut text = String::new();
+    to_stream!(
+        INPUT(names: Vec<String>, evens: Vec<u32>, by_rest: BTreeMap<u32, Vec<u32>>, text: String),
+        ORDERED,
+        {
+            for n in 0..30 {
+                let item = n;
+                STAGE(INPUT(item: u32), OUTPUT(item: u32, rest: u32), REPLICATE = 4, {
+                    let rest = item % 3;
+                });
+
+                STAGE(
+                    INPUT(
+                        item: u32,
+                        rest: u32,
+                        names: Vec<String>,
+                        evens: Vec<u32>,
+                        by_rest: BTreeMap<u32, Vec<u32>>,
+                        text: String
+                    ),
+                    ORDERED,
+                    {
+                        if item % 2 == 0 {
+                            evens.push(item);
+                        }
+                        by_rest.insert(rest, vec![item]);
+                        if item < 3 {
+                            text.push_str(&names[rest as usize]);
+                        }
+                    },
+                );
+            }
+        }
+    );
+
+    assert_eq!(evens, (0..30).step_by(2).collect::<Vec<u32>>());
+    assert_eq!(by_rest.len(), 3);
+    assert_eq!(by_rest[&0], vec![27]);
+    assert_eq!(by_rest[&1], vec![28]);
+    assert_eq!(by_rest[&2], vec![29]);
+    assert_eq!(text, "zeroonetwo");
+
+    // changes that go through a field or a function are restored too
+    let mut counter = Counter::default();
+    let mut result: Vec<u64> = Vec::new();
+    {
+        to_stream!(INPUT(counter: Counter, result: Vec<u64>), ORDERED, {
+            for n in 1..=10u64 {
+                STAGE(INPUT(n: u64, counter: Counter, result: Vec<u64>), ORDERED, {
+                    counter.0 += n;
+                    add(result, n);
+                });
+            }
+        });
+    }
+    assert_eq!(counter.0, 55);
+    assert_eq!(result, (1..=10).collect::<Vec<u64>>());
+}
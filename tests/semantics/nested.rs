@@ -0,0 +1,100 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Counts the replicas that run at the same time, and the most there ever were
+#[derive(Default)]
+struct Replicas {
+    live: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl Replicas {
+    fn run<T>(&self, f: impl FnOnce() -> T) -> T {
+        let live = self.live.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(live, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(2));
+        let result = f();
+        self.live.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+}
+
+/// A stream started from a function that a stage calls is only nested if it says so
+fn row_sum(frame: u64, replicas: Arc<Replicas>) -> u64 {
+    let rows = to_stream!(NESTED, INPUT(replicas: Arc<Replicas>), OUTPUT(u64), {
+        for row in 0..frame {
+            STAGE(INPUT(row: u64, replicas: Arc<Replicas>), OUTPUT(row: u64), REPLICATE = 64, {
+                let row = replicas.run(|| row * 2);
+            });
+        }
+    });
+    rows.iter().sum()
+}
+
+fn main() {
+    // every replica runs the whole chain of stages nested in it, one after the other
+    let texts = to_stream!(OUTPUT(String), ORDERED, {
+        for frame in 0..20u64 {
+            STAGE(INPUT(frame: u64), OUTPUT(frame: u64, text: String), REPLICATE = 4, {
+                STAGE(INPUT(frame: u64), OUTPUT(pixels: Vec<u64>), {
+                    let pixels: Vec<u64> = (0..frame).collect();
+                });
+                STAGE(INPUT(pixels: Vec<u64>), OUTPUT(sum: u64), {
+                    let sum = pixels.iter().sum();
+                });
+                STAGE(INPUT(sum: u64), OUTPUT(text: String), {
+                    let text = sum.to_string();
+                });
+            });
+            STAGE(INPUT(frame: u64, text: String), OUTPUT(text: String), ORDERED, {
+                let text = format!("{frame}: {text}");
+            });
+        }
+    });
+    let expected: Vec<String> = (0..20u64)
+        .map(|frame| format!("{frame}: {}", (0..frame).sum::<u64>()))
+        .collect();
+    assert_eq!(texts, expected);
+
+    // a stream nested in a stage runs for every item, and its replicas share the
+    // threads of the replica that runs it
+    let counted: Arc<Replicas> = Arc::default();
+    let replicas = counted.clone();
+    let totals = to_stream!(INPUT(replicas: Arc<Replicas>), OUTPUT(u64), ORDERED, {
+        for frame in 0..10u64 {
+            STAGE(INPUT(frame: u64, replicas: Arc<Replicas>), OUTPUT(total: u64), REPLICATE = 2, {
+                let replicas = replicas.clone();
+                let rows = to_stream!(INPUT(replicas: Arc<Replicas>), OUTPUT(u64), ORDERED, {
+                    for row in 0..frame {
+                        STAGE(INPUT(row: u64, replicas: Arc<Replicas>), OUTPUT(row: u64), REPLICATE = 64, {
+                            let row = replicas.run(|| row * 2);
+                        });
+                    }
+                });
+                let total = rows.iter().sum();
+            });
+        }
+    });
+    let expected: Vec<u64> = (0..10u64).map(|frame| (0..frame).map(|row| row * 2).sum()).collect();
+    assert_eq!(totals, expected);
+    // without the budget, the rows of both frames that run at once would all run
+    // at the same time, on up to 64 replicas each
+    let share = (std::thread::available_parallelism().map_or(1, |n| n.get()) / 2).max(1);
+    assert!(counted.peak.load(Ordering::SeqCst) <= 2 * share);
+
+    let counted: Arc<Replicas> = Arc::default();
+    let replicas = counted.clone();
+    let totals = to_stream!(INPUT(replicas: Arc<Replicas>), OUTPUT(u64), ORDERED, {
+        for frame in 0..10u64 {
+            STAGE(INPUT(frame: u64, replicas: Arc<Replicas>), OUTPUT(total: u64), REPLICATE = 2, {
+                let total = row_sum(frame, replicas.clone());
+            });
+        }
+    });
+    assert_eq!(totals, expected);
+    assert!(counted.peak.load(Ordering::SeqCst) <= 2 * share);
+}
@@ -0,0 +1,44 @@
+extern crate spar_rust;
+use spar_rust::spar_pipeline;
+
+fn main() {
+    let offset = 1u64;
+    let mut squares = spar_pipeline!(INPUT(offset: u64), OUTPUT((u64, u64)), ORDERED, {
+        STAGE(INPUT(n: u64), OUTPUT(n: u64, square: u64), REPLICATE = 4, {
+            let square = n * n;
+        });
+        STAGE(INPUT(n: u64, square: u64, offset: u64), OUTPUT(n: u64, square: u64), {
+            square += *offset;
+        });
+    });
+
+    // the same stages serve every batch
+    for batch in 0..3u64 {
+        for n in batch * 10..(batch + 1) * 10 {
+            squares.post(n);
+        }
+        let expected: Vec<(u64, u64)> = (batch * 10..(batch + 1) * 10).map(|n| (n, n * n + 1)).collect();
+        assert_eq!(squares.flush(), expected);
+    }
+
+    squares.post(100);
+    let mut results = Vec::new();
+    while squares.pending() > 0 {
+        results.extend(squares.drain());
+    }
+    assert_eq!(results, [(100, 10001)]);
+
+    squares.post(7);
+    assert_eq!(squares.shutdown(), [(7, 50)]);
+
+    let mut lengths = spar_pipeline!(OUTPUT(usize), {
+        STAGE(INPUT(word: String, repeat: usize), OUTPUT(length: usize), REPLICATE = 2, {
+            let length = word.len() * repeat;
+        });
+    });
+    lengths.post(("ab".to_string(), 3));
+    lengths.post(("c".to_string(), 2));
+    let mut total = lengths.flush();
+    total.sort();
+    assert_eq!(total, [2, 6]);
+}
@@ -0,0 +1,36 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+use spar_rust_runtime::Restore;
+
+// restored variables are never cloned, only merged
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; 4],
+}
+
+impl Restore for Histogram {
+    fn restore(&mut self, other: Self) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts) {
+            *count += other;
+        }
+    }
+}
+
+fn main() {
+    let mut histogram = Histogram { counts: [100, 0, 0, 0] };
+    to_stream!(INPUT(histogram: Histogram), {
+        for n in 0..1000u64 {
+            let item = n;
+            STAGE(INPUT(item: u64), OUTPUT(bucket: usize), REPLICATE = 4, {
+                let bucket = (item % 4) as usize;
+            });
+
+            STAGE(INPUT(bucket: usize, histogram: Histogram), {
+                histogram.counts[bucket] += 1;
+            });
+        }
+    });
+
+    assert_eq!(histogram.counts, [350, 250, 250, 250]);
+}
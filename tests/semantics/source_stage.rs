@@ -0,0 +1,29 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+use std::io::BufRead;
+
+fn main() {
+    let text = "1 2 3\n4 5\n6\n\n7 8 9 10\n";
+    let mut result: Vec<(usize, u32)> = Vec::new();
+    to_stream!(INPUT(result: Vec<(usize, u32)>), ORDERED, {
+        STAGE(OUTPUT(number: usize, line: String), {
+            text.as_bytes().lines().map(|line| line.unwrap()).enumerate()
+        });
+
+        STAGE(
+            INPUT(number: usize, line: String),
+            OUTPUT(number: usize, sum: u32),
+            REPLICATE = 4,
+            {
+                let sum: u32 = line.split_whitespace().map(|n| n.parse::<u32>().unwrap()).sum();
+            },
+        );
+
+        STAGE(INPUT(number: usize, sum: u32, result: Vec<(usize, u32)>), ORDERED, {
+            result.push((number, sum));
+        });
+    });
+
+    assert_eq!(result, vec![(0, 6), (1, 9), (2, 6), (3, 0), (4, 34)]);
+}
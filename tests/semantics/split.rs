@@ -0,0 +1,86 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+enum Shape {
+    Circle(u64),
+    Square(u64),
+}
+
+fn main() {
+    // items are routed by a predicate, and the branches merge in order. The merge
+    // receives `n` from the SPLIT, which both branches forward
+    let texts = to_stream!(OUTPUT(String), ORDERED, {
+        for n in 0..20u64 {
+            STAGE(INPUT(n: u64), OUTPUT(n: u64), REPLICATE = 2, {});
+            SPLIT(INPUT(n: u64), {
+                let half = n / 2;
+                if n % 2 == 0 {
+                    STAGE(INPUT(half: u64), OUTPUT(text: String), {
+                        let text = format!("even {half}");
+                    });
+                } else {
+                    STAGE(INPUT(half: u64), OUTPUT(text: String), REPLICATE = 2, {
+                        let text = format!("odd {half}");
+                    });
+                }
+            });
+            STAGE(INPUT(n: u64, text: String), OUTPUT(text: String), ORDERED, {
+                let text = format!("{n}: {text}");
+            });
+        }
+    });
+    let expected: Vec<String> = (0..20u64)
+        .map(|n| match n % 2 {
+            0 => format!("{n}: even {}", n / 2),
+            _ => format!("{n}: odd {}", n / 2),
+        })
+        .collect();
+    assert_eq!(texts, expected);
+
+    // every item is broadcast to both branches, which the ordered merge keeps together
+    let items = to_stream!(OUTPUT(u64), ORDERED, {
+        for n in 1..=10u64 {
+            SPLIT(INPUT(n: u64), {
+                {
+                    STAGE(INPUT(n: u64), OUTPUT(n: u64), REPLICATE = 2, {
+                        let n = n * n;
+                    });
+                }
+                {
+                    STAGE(INPUT(n: u64), OUTPUT(n: u64), REPLICATE = 2, {
+                        let n = n * 1000;
+                    });
+                }
+            });
+            STAGE(INPUT(n: u64), OUTPUT(n: u64), ORDERED, {});
+        }
+    });
+    let expected: Vec<u64> = (1..=10u64).flat_map(|n| [n * n, n * 1000]).collect();
+    assert_eq!(items, expected);
+
+    // items are routed by enum variant, to branches that no stage merges
+    let (circles, squares) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let (circle_count, square_count) = (circles.clone(), squares.clone());
+    to_stream!(INPUT(circle_count: Arc<AtomicUsize>, square_count: Arc<AtomicUsize>), {
+        for i in 0..30u64 {
+            let shape = if i % 3 == 0 { Shape::Circle(i) } else { Shape::Square(i) };
+            SPLIT(INPUT(shape: Shape), {
+                match shape {
+                    Shape::Circle(radius) => STAGE(INPUT(radius: u64, circle_count: Arc<AtomicUsize>), REPLICATE = 2, {
+                        assert_eq!(radius % 3, 0);
+                        circle_count.fetch_add(1, Ordering::SeqCst);
+                    }),
+                    Shape::Square(side) => STAGE(INPUT(side: u64, square_count: Arc<AtomicUsize>), {
+                        assert_ne!(side % 3, 0);
+                        square_count.fetch_add(1, Ordering::SeqCst);
+                    }),
+                }
+            });
+        }
+    });
+    assert_eq!(circles.load(Ordering::SeqCst), 10);
+    assert_eq!(squares.load(Ordering::SeqCst), 20);
+}
@@ -0,0 +1,51 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+fn squares(count: u64) -> Vec<u64> {
+    let mut squares = to_stream!(INPUT(count: u64), OUTPUT(u64), {
+        for n in 0..count {
+            STAGE(INPUT(n: u64), OUTPUT(square: u64), REPLICATE = 4, {
+                let square = n * n;
+            });
+        }
+    });
+    squares.sort();
+    squares
+}
+
+/// The last stage only reads `width`, a parameter that isn't `mut`, as the
+/// mandelbrot bench does, so it is not restored
+fn lines(width: usize, count: usize) -> Vec<String> {
+    to_stream!(INPUT(width: usize), OUTPUT(String), ORDERED, {
+        for i in 0..count {
+            STAGE(INPUT(width: usize, i: usize), OUTPUT(line: String), ORDERED, {
+                let line = format!("{i:>width$}", width = *width);
+            });
+        }
+    })
+}
+
+fn main() {
+    assert_eq!(squares(5), [0, 1, 4, 9, 16]);
+    assert_eq!(lines(3, 2), ["  0", "  1"]);
+
+    let mut total: Vec<u64> = Vec::new();
+    let lines: Vec<(usize, String)> = to_stream!(INPUT(total: Vec<u64>), OUTPUT((usize, String)), ORDERED, {
+        for i in 0..100usize {
+            STAGE(INPUT(i: usize), OUTPUT(i: usize, line: String), REPLICATE = 4, {
+                let line = format!("line {i}");
+            });
+
+            STAGE(INPUT(i: usize, line: String, total: Vec<u64>), OUTPUT(i: usize, line: String), ORDERED, {
+                total.push(line.len() as u64);
+            });
+        }
+    });
+
+    assert_eq!(lines.len(), 100);
+    for (n, (i, line)) in lines.iter().enumerate() {
+        assert_eq!(n, *i);
+        assert_eq!(*line, format!("line {i}"));
+    }
+    assert_eq!(total.iter().sum::<u64>(), lines.iter().map(|(_, line)| line.len() as u64).sum());
+}
@@ -0,0 +1,31 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+type Item = u64;
+
+fn main() {
+    let mut result: Vec<u64> = Vec::new();
+    to_stream!(INPUT(result: Vec<u64>), ORDERED, {
+        for i in 0..100u32 {
+            let item = i;
+            STAGE(
+                INPUT(item: u32),
+                OUTPUT(item: Item),
+                REPLICATE = 4,
+                {
+                    let item = item as u64 * 2;
+                },
+            );
+
+            STAGE(INPUT(item: std::primitive::u64), OUTPUT(item: Item), ORDERED, {
+                let item = item + 1;
+            });
+
+            STAGE(INPUT(item: u64, result: Vec<u64>), ORDERED, {
+                result.push(item);
+            });
+        }
+    });
+
+    assert_eq!(result, (0..100).map(|i| i * 2 + 1).collect::<Vec<u64>>());
+}
@@ -0,0 +1,59 @@
+extern crate spar_rust;
+use spar_rust::to_stream;
+
+fn main() {
+    // a moving average over the last 3 items, in the order of the stream
+    let averages = to_stream!(OUTPUT(f64), ORDERED, {
+        for n in 0..10u64 {
+            STAGE(INPUT(n: u64), OUTPUT(x: f64), REPLICATE = 4, {
+                let x = (n * n) as f64;
+            });
+            STAGE(INPUT(x: f64), OUTPUT(average: f64), ORDERED, WINDOW(size = 3, slide = 1), {
+                let average = x.iter().sum::<f64>() / x.len() as f64;
+            });
+        }
+    });
+    let squares: Vec<f64> = (0..10u64).map(|n| (n * n) as f64).collect();
+    let expected: Vec<f64> = squares.windows(3).map(|w| w.iter().sum::<f64>() / 3.0).collect();
+    assert_eq!(averages, expected);
+
+    // windows that don't overlap, where the last items of the stream make a
+    // partial window, even though a replicated stage reorders them
+    let mut sizes = to_stream!(OUTPUT((usize, u64)), {
+        for n in 0..10u64 {
+            STAGE(INPUT(n: u64), OUTPUT(n: u64), REPLICATE = 4, {});
+            STAGE(INPUT(n: u64), OUTPUT(batch: (usize, u64)), WINDOW(size = 4), {
+                let batch = (n.len(), n.iter().sum());
+            });
+        }
+    });
+    sizes.sort();
+    assert_eq!(sizes.iter().map(|(size, _)| *size).collect::<Vec<_>>(), [2, 4, 4]);
+    assert_eq!(sizes.iter().map(|(_, sum)| sum).sum::<u64>(), 45);
+
+    // windows that start further apart than they have items skip the items between them
+    let windows = to_stream!(OUTPUT(Vec<u64>), ORDERED, {
+        for n in 0..8u64 {
+            STAGE(INPUT(n: u64), OUTPUT(window: Vec<u64>), ORDERED, WINDOW(size = 2, slide = 3), {
+                let window = n;
+            });
+        }
+    });
+    assert_eq!(windows, [vec![0, 1], vec![3, 4], vec![6, 7]]);
+
+    // a stage with a WINDOW over the windows of another one, which tells it how many
+    // it sent, and n-grams of several inputs
+    let size = 3;
+    let grams = to_stream!(INPUT(size: usize), OUTPUT(String), ORDERED, {
+        for (i, word) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+            let word = word.to_owned();
+            STAGE(INPUT(i: usize, word: String), OUTPUT(pair: String), ORDERED, WINDOW(size = 2, slide = 1), {
+                let pair = format!("{}{}:{}", word[0], word[1], i[0]);
+            });
+            STAGE(INPUT(pair: String), OUTPUT(gram: String), ORDERED, WINDOW(size = size), {
+                let gram = pair.join(" ");
+            });
+        }
+    });
+    assert_eq!(grams, ["ab:0 bc:1 cd:2", "de:3"]);
+}
@@ -8,3 +8,9 @@ fn semantics() {
         t.pass(p.unwrap().path());
     }
 }
+
+#[test]
+fn diagnostics() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/diagnostics/*.rs");
+}
@@ -0,0 +1,107 @@
+#![cfg(feature = "tracing")]
+
+use spar_rust::to_stream;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+/// The numeric fields of a span or event, and its message
+#[derive(Default, Debug, Clone)]
+struct Fields(HashMap<&'static str, u64>, String);
+
+impl Visit for Fields {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name(), value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name(), value as u64);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.1 = format!("{value:?}");
+        }
+    }
+}
+
+/// Records the fields of every `spar_stage` span, and the events that count items
+#[derive(Default, Clone)]
+struct Recorder {
+    stages: Arc<Mutex<Vec<Fields>>>,
+    counts: Arc<Mutex<Vec<(String, u64)>>>,
+}
+
+impl<S: Subscriber> Layer<S> for Recorder {
+    fn on_new_span(&self, attrs: &Attributes<'_>, _: &Id, _: Context<'_, S>) {
+        if attrs.metadata().name() == "spar_stage" {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            self.stages.lock().unwrap().push(fields);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        if let Some(&items) = fields.0.get("items") {
+            self.counts.lock().unwrap().push((fields.1, items));
+        }
+    }
+}
+
+#[test]
+fn stage_spans() {
+    let recorder = Recorder::default();
+    // the replicas run on their own threads, so the subscriber must be the global one
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(recorder.clone()))
+        .unwrap();
+
+    let offset = 100u64;
+    let items = to_stream!(OUTPUT(u64), ORDERED, {
+        (0..10u64).for_each(|n| STAGE(INPUT(n: u64), OUTPUT(n: u64), REPLICATE = 4, {}));
+        (10..20u64).for_each(move |n| {
+            let shifted = n + offset;
+            STAGE(INPUT(shifted: u64), OUTPUT(n: u64), REPLICATE = 2, {
+                let n = shifted - 100;
+            });
+        });
+        STAGE(INPUT(n: u64), OUTPUT(n: u64), ORDERED, {});
+    });
+    assert_eq!(items, (0..20).collect::<Vec<u64>>());
+
+    // the dispatcher counts the items posted by the `move` closure too
+    assert_eq!(
+        *recorder.counts.lock().unwrap(),
+        vec![
+            ("spar dispatcher finished".to_owned(), 20),
+            ("spar collector finished".to_owned(), 20),
+        ]
+    );
+
+    let stages = recorder.stages.lock().unwrap();
+    let mut items: HashMap<u64, Vec<u64>> = HashMap::new();
+    for fields in stages.iter() {
+        let stage = fields.0["stage"];
+        let replica = fields.0["replica"];
+        let item = fields.0["item"];
+        match stage {
+            1 => assert!(replica < 4 && item < 10),
+            2 => assert!(replica < 2 && (10..20).contains(&item)),
+            3 => assert_eq!(replica, 0),
+            _ => panic!("no stage {stage}"),
+        }
+        items.entry(stage).or_default().push(item);
+    }
+    // every item went through its branch, and then through the last stage, once
+    for (stage, expected) in [(1, 0..10), (2, 10..20), (3, 0..20)] {
+        let stage_items = items.get_mut(&stage).unwrap();
+        stage_items.sort_unstable();
+        assert_eq!(*stage_items, expected.collect::<Vec<u64>>());
+    }
+}